@@ -1,6 +1,49 @@
 use crate::common::*;
+use crate::extensions::callback::PANEL_EXPIRED;
+use crate::services::outbox::{Outbox, Response};
 use std::borrow::Cow;
 
+/// The bot API, except that requests go through an `Outbox`: those
+/// fired and forgotten by `spawn` are queued and retried, those whose
+/// response is needed are sent by `send` within the same rate limits.
+#[derive(Clone)]
+pub struct Bot {
+  api: tg::Api,
+  outbox: Outbox,
+}
+
+impl Bot {
+  pub fn new(api: tg::Api, handle: reactor::Handle, logger: Logger) -> Self {
+    let outbox = Outbox::new(api.clone(), handle, logger);
+    Bot { api, outbox }
+  }
+
+  /// Shadows `tg::Api::spawn`, so the request is rate limited and retried
+  pub fn spawn<Req: tg::Request>(&self, request: Req) {
+    self.outbox.push(request)
+  }
+
+  /// Shadows `tg::Api::send`, so the request is rate limited and counted
+  pub fn send<Req: tg::Request>(
+    &self,
+    request: Req,
+  ) -> Box<Future<Item = Response<Req>, Error = tg::Error>> {
+    self.outbox.send(request)
+  }
+
+  pub fn outbox(&self) -> &Outbox {
+    &self.outbox
+  }
+}
+
+impl Deref for Bot {
+  type Target = tg::Api;
+
+  fn deref(&self) -> &tg::Api {
+    &self.api
+  }
+}
+
 pub trait TgApiExt {
   // This method blocks the main thread
   fn consume_updates<'a>(
@@ -15,9 +58,18 @@ pub trait TgApiExt {
   where
     R: tg::ToMessageId + tg::ToSourceChat,
     T: Into<Cow<'s, str>>;
+
+  fn answer_inline(
+    &self,
+    query: &tg::InlineQuery,
+    results: Vec<tg::InlineQueryResult>,
+  );
+
+  /// Tell the user the button clicked belongs to a stale panel
+  fn answer_expired(&self, query: &tg::CallbackQuery);
 }
 
-impl TgApiExt for tg::Api {
+impl TgApiExt for Bot {
   fn consume_updates<'a>(
     &'a self,
   ) -> Box<Future<Item = Vec<tg::Update>, Error = tg::Error> + 'a> {
@@ -53,6 +105,18 @@ impl TgApiExt for tg::Api {
   {
     self.spawn(reply(to, md_text).parse_mode(Markdown));
   }
+
+  fn answer_inline(
+    &self,
+    query: &tg::InlineQuery,
+    results: Vec<tg::InlineQueryResult>,
+  ) {
+    self.spawn(query.answer(results));
+  }
+
+  fn answer_expired(&self, query: &tg::CallbackQuery) {
+    self.spawn(query.answer(PANEL_EXPIRED));
+  }
 }
 
 pub trait TgMessageExt {
@@ -163,27 +227,133 @@ impl TgMessageExt for tg::Message {
   }
 }
 
+/// Id of the messages the control API makes up to run commands on. No
+/// real message has it, so replies to them do not quote them.
+pub const SYNTHETIC_MESSAGE_ID: tg::Integer = 0;
+
 // Requests
 pub fn reply<'s, R, T>(to: R, text: T) -> tg::SendMessage<'s>
 where
   R: tg::ToMessageId + tg::ToSourceChat,
   T: Into<Cow<'s, str>>,
 {
-  tg::SendMessage::new(to.to_source_chat(), text)
-    .reply_to(to)
-    .clone()
+  let mut req = tg::SendMessage::new(to.to_source_chat(), text);
+  if to.to_message_id() != tg::MessageId::from(SYNTHETIC_MESSAGE_ID) {
+    req.reply_to(to);
+  }
+  req
+}
+
+/// Inline result that sends `md_text` when chosen
+pub fn inline_article<I, T, M>(
+  id: I,
+  title: T,
+  md_text: M,
+) -> tg::InlineQueryResult
+where
+  I: Into<String>,
+  T: Into<String>,
+  M: Into<String>,
+{
+  let content = tg::InputTextMessageContent {
+    message_text: md_text.into(),
+    parse_mode: Some(Markdown),
+    disable_web_page_preview: false,
+  };
+  tg::InlineQueryResultArticle::new(id.into(), title.into(), content).into()
 }
 
 pub trait TgCallbackQueryExt {
-  fn ext(&self) -> Option<&str>;
-  fn key(&self) -> Option<&str>;
+  fn callback_data(&self) -> Option<CallbackData>;
+  fn ext(&self) -> Option<&str> {
+    self.callback_data().map(|data| data.ext)
+  }
+  fn token(&self) -> Option<Token> {
+    self.callback_data().map(|data| data.token)
+  }
+  fn payload<T: DeserializeOwned>(&self) -> Option<T> {
+    self.callback_data().and_then(|data| data.payload())
+  }
 }
 
 impl TgCallbackQueryExt for tg::CallbackQuery {
-  fn ext(&self) -> Option<&str> {
-    self.data.splitn(2, '.').nth(0)
+  fn callback_data(&self) -> Option<CallbackData> {
+    CallbackData::parse(&self.data)
   }
-  fn key(&self) -> Option<&str> {
-    self.data.splitn(2, '.').nth(1)
+}
+
+// Requests not yet covered by telegram-bot
+#[derive(Serialize, Debug, Clone)]
+pub struct BotCommand {
+  command: String,
+  description: String,
+}
+
+impl<'a> From<&'a Command> for BotCommand {
+  fn from(cmd: &'a Command) -> BotCommand {
+    BotCommand {
+      command: cmd.name.into(),
+      description: cmd.description.into(),
+    }
+  }
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BotCommandScope {
+  Default,
+  AllPrivateChats,
+  AllGroupChats,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMyCommands {
+  commands: Vec<BotCommand>,
+  scope: BotCommandScope,
+}
+
+impl SetMyCommands {
+  pub fn new(commands: Vec<BotCommand>, scope: BotCommandScope) -> Self {
+    SetMyCommands { commands, scope }
+  }
+}
+
+impl tg::Request for SetMyCommands {
+  type Type = tg::JsonRequestType<Self>;
+  type Response = tg::JsonTrueToUnitResponse;
+
+  fn serialize(
+    &self,
+  ) -> std::result::Result<tg::HttpRequest, tg::types::Error> {
+    use crate::tg::RequestType;
+    Self::Type::serialize(tg::RequestUrl::method("setMyCommands"), self)
+  }
+}
+
+/// `tg::SetWebhook` predates the secret token
+#[derive(Serialize, Debug, Clone)]
+pub struct SetWebhook {
+  url: String,
+  secret_token: String,
+}
+
+impl SetWebhook {
+  pub fn new(url: &str, secret_token: &str) -> Self {
+    SetWebhook {
+      url: url.into(),
+      secret_token: secret_token.into(),
+    }
+  }
+}
+
+impl tg::Request for SetWebhook {
+  type Type = tg::JsonRequestType<Self>;
+  type Response = tg::JsonTrueToUnitResponse;
+
+  fn serialize(
+    &self,
+  ) -> std::result::Result<tg::HttpRequest, tg::types::Error> {
+    use crate::tg::RequestType;
+    Self::Type::serialize(tg::RequestUrl::method("setWebhook"), self)
   }
 }
@@ -26,19 +26,37 @@ pub use slog::Logger;
 
 pub use regex::{Regex, RegexSet};
 
-pub use crate::bot::{reply, TgApiExt, TgCallbackQueryExt, TgMessageExt};
+pub use crate::config::Config;
+
+pub use crate::bot::{
+  inline_article, reply, Bot, TgApiExt, TgCallbackQueryExt, TgMessageExt,
+};
 
 pub use crate::context::Context;
 pub use crate::context_extensions::ContextExtension;
-pub use crate::db::Db;
-pub use crate::extensions::{BotExtension, ExtensionError, InteractiveBuilder};
+pub use crate::db::{
+  DbError, DbResult, MemoryStorage, SqliteStorage, Storage, StorageExt,
+  Versioned,
+};
+pub use crate::extensions::{
+  new_token, BotExtension, CallbackData, Command, Dispatch, ExtensionError,
+  InteractiveBuilder, Named, Token, Visibility,
+};
+pub use crate::services::audit::{
+  AuditEntry, AuditKind, AuditLog, IncidentThrottle,
+};
+pub use crate::services::error_report::ErrorReporter;
 pub use crate::services::request::request;
 pub use crate::services::request::RequestError;
+pub use crate::services::scheduler::{Job, JobId, Scheduler, Trigger};
+pub use crate::services::session::{SessionKey, SessionStore, ToSessionKey};
+pub use crate::services::supervisor::Supervisor;
 
 pub use url::Url;
 
+pub use crate::context_extensions::ext_switch::ExtSwitch;
 pub use crate::context_extensions::name_map::NameMap;
-pub use crate::context_extensions::safety_guard::SafetyGuard;
+pub use crate::context_extensions::safety_guard::{Role, SafetyGuard};
 
 pub use crate::util::{
   ellipsis, escape_markdown, format_duration, format_human_time, format_time,
@@ -0,0 +1,412 @@
+//! Settings read from a TOML file at startup, with the environment
+//! variables used before it overriding the file.
+//!
+//! ```toml
+//! plugins = ["history_saver", "afk", "weather"]
+//!
+//! [telegram]
+//! token = "123:abc"
+//! webhook_callback = "https://example.com/fondbot"
+//!
+//! [context.safety-guard]
+//! safe_chats = [12345, -67890]
+//!
+//! [extensions.weather]
+//! caiyun_api_key = "..."
+//! ```
+
+use crate::common::*;
+use crate::db::DB_FILE;
+
+use toml;
+use toml::value::{Table, Value};
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+
+pub const DEFAULT_PATH: &str = "fondbot.toml";
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Fail, Debug)]
+pub enum ConfigError {
+  #[fail(display = "Cannot read {}: {}", path, error)]
+  Read {
+    path: String,
+    #[cause]
+    error: io::Error,
+  },
+
+  #[fail(display = "Invalid {}: {}", path, error)]
+  Parse {
+    path: String,
+    #[cause]
+    error: toml::de::Error,
+  },
+
+  #[fail(display = "Missing {} (or env {})", key, env)]
+  Missing {
+    key: &'static str,
+    env: &'static str,
+  },
+
+  #[fail(display = "Invalid {}: {}", key, reason)]
+  Invalid { key: String, reason: String },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+  pub token: Option<String>,
+  /// Long polling is used unless this is set
+  pub webhook_callback: Option<String>,
+  pub webhook_bind: SocketAddr,
+  /// Made up on each start if not set
+  pub webhook_secret: Option<String>,
+  /// Skip the updates that piled up while the bot was down
+  pub consume_updates: bool,
+}
+
+impl Default for TelegramConfig {
+  fn default() -> Self {
+    TelegramConfig {
+      token: None,
+      webhook_callback: None,
+      webhook_bind: ([127, 0, 0, 1], 6407).into(),
+      webhook_secret: None,
+      consume_updates: false,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+  pub backend: Backend,
+  /// Of the SQLite database
+  pub path: String,
+}
+
+impl Default for DbConfig {
+  fn default() -> Self {
+    DbConfig {
+      backend: Backend::Sqlite,
+      path: DB_FILE.into(),
+    }
+  }
+}
+
+/// Where the bot keeps its state
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+  Sqlite,
+  /// Lost on exit, for trying things out
+  Memory,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+  pub bind: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+  fn default() -> Self {
+    MetricsConfig {
+      bind: ([127, 0, 0, 1], 6408).into(),
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+  /// The control API is off unless this is set
+  pub token: Option<String>,
+  pub bind: SocketAddr,
+  /// Users the control API may run commands as
+  pub users: Vec<tg::Integer>,
+}
+
+impl Default for ControlConfig {
+  fn default() -> Self {
+    ControlConfig {
+      token: None,
+      bind: ([127, 0, 0, 1], 6409).into(),
+      users: vec![],
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub telegram: TelegramConfig,
+  pub db: DbConfig,
+  pub metrics: MetricsConfig,
+  pub control: ControlConfig,
+  /// Where errors are summarized
+  pub operator_chat: Option<tg::Integer>,
+  /// Plugins to load in this order, all available ones if not set
+  pub plugins: Option<Vec<String>>,
+  /// Sections of the `ContextExtension`s, by their names
+  context: Table,
+  /// Sections of the `BotExtension`s, by their names
+  extensions: Table,
+  /// Sections that failed to parse, see `take_errors`
+  #[serde(skip)]
+  errors: RefCell<Vec<ConfigError>>,
+}
+
+impl Config {
+  /// Read `path`, a missing file is fine unless `required`. Call
+  /// `validate` before serving with it.
+  pub fn load(path: &str, required: bool) -> Result<Self> {
+    let mut config = match fs::read_to_string(path) {
+      Ok(text) => Self::parse(path, &text)?,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => {
+        Config::default()
+      }
+      Err(error) => {
+        let path = path.into();
+        return Err(ConfigError::Read { path, error });
+      }
+    };
+    config.apply_env()?;
+    Ok(config)
+  }
+
+  pub fn parse(path: &str, text: &str) -> Result<Self> {
+    toml::from_str(text).map_err(|error| ConfigError::Parse {
+      path: path.into(),
+      error,
+    })
+  }
+
+  /// The section of a context extension, defaults if missing or invalid
+  pub fn context<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+    self.section("context", &self.context, name)
+  }
+
+  /// The section of a bot extension, defaults if missing or invalid
+  pub fn extension<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+    self.section("extensions", &self.extensions, name)
+  }
+
+  fn section<T>(&self, kind: &str, sections: &Table, name: &str) -> T
+  where
+    T: DeserializeOwned + Default,
+  {
+    let section = match sections.get(name) {
+      Some(section) => section.clone(),
+      None => return T::default(),
+    };
+    section.try_into().unwrap_or_else(|e: toml::de::Error| {
+      self.errors.borrow_mut().push(ConfigError::Invalid {
+        key: format!("[{}.{}]", kind, name),
+        reason: e.to_string(),
+      });
+      T::default()
+    })
+  }
+
+  /// Problems found reading the sections so far
+  pub fn take_errors(&self) -> Vec<ConfigError> {
+    self.errors.borrow_mut().drain(..).collect()
+  }
+
+  /// The variables configuring fondbot before there was a file
+  fn apply_env(&mut self) -> Result<()> {
+    let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+
+    if let Some(token) = var("TELEGRAM_BOT_TOKEN") {
+      self.telegram.token = Some(token);
+    }
+    if let Some(callback) = var("TELEGRAM_WEBHOOK_CALLBACK") {
+      self.telegram.webhook_callback = Some(callback);
+    }
+    if let Some(bind) = var("TELEGRAM_WEBHOOK_BIND") {
+      self.telegram.webhook_bind = parse_env("TELEGRAM_WEBHOOK_BIND", &bind)?;
+    }
+    if let Some(secret) = var("TELEGRAM_WEBHOOK_SECRET") {
+      self.telegram.webhook_secret = Some(secret);
+    }
+    if let Some(consume) = var("CONSUME_UPDATES") {
+      self.telegram.consume_updates = consume == "1";
+    }
+    if let Some(path) = var("DB_FILE") {
+      self.db.path = path;
+    }
+    if let Some(bind) = var("METRICS_BIND") {
+      self.metrics.bind = parse_env("METRICS_BIND", &bind)?;
+    }
+    if let Some(token) = var("CONTROL_TOKEN") {
+      self.control.token = Some(token);
+    }
+    if let Some(bind) = var("CONTROL_BIND") {
+      self.control.bind = parse_env("CONTROL_BIND", &bind)?;
+    }
+    if let Some(ids) = var("CONTROL_USERS") {
+      self.control.users = ids
+        .split(',')
+        .map(|id| parse_env("CONTROL_USERS", id.trim()))
+        .collect::<Result<_>>()?;
+    }
+    if let Some(chat) = var("OPERATOR_CHAT") {
+      self.operator_chat = Some(parse_env("OPERATOR_CHAT", &chat)?);
+    }
+
+    let guard = "safety-guard";
+    for &(name, key) in &[
+      ("SAFE_CHATS", "safe_chats"),
+      ("ADMIN_USERS", "admins"),
+      ("OWNER_USERS", "owners"),
+    ] {
+      if let Some(ids) = var(name) {
+        let ids = ids
+          .split(',')
+          .map(|id| parse_env(name, id.trim()).map(Value::Integer))
+          .collect::<Result<_>>()?;
+        set(&mut self.context, guard, key, Value::Array(ids));
+      }
+    }
+    if let Some(chat) = var("AUDIT_CHAT") {
+      let chat = Value::Integer(parse_env("AUDIT_CHAT", &chat)?);
+      set(&mut self.context, guard, "audit_chat", chat);
+    }
+    // user_id->name,...
+    if let Some(names) = var("NAME_MAP") {
+      for pair in names.split(',') {
+        let mut pair = pair.splitn(2, "->");
+        let (id, name) = match (pair.next(), pair.next()) {
+          (Some(id), Some(name)) => (id, name),
+          _ => return Err(invalid_env("NAME_MAP", "expected id->name")),
+        };
+        let id = Value::Integer(parse_env("NAME_MAP", id)?);
+        set(&mut self.context, "name-map", name, id);
+      }
+    }
+
+    if let Some(key) = var("CAIYUN_API_KEY") {
+      let key = Value::String(key);
+      set(&mut self.extensions, "weather", "caiyun_api_key", key);
+    }
+    if let Some(addr) = var("YEELIGHT_ADDR") {
+      parse_env::<SocketAddr>("YEELIGHT_ADDR", &addr)?;
+      let addr = Value::String(addr);
+      set(&mut self.extensions, "yeelight", "addr", addr);
+    }
+    Ok(())
+  }
+
+  /// Settings required to serve
+  pub fn validate(&self) -> Result<()> {
+    if self.telegram.token.is_none() {
+      return Err(ConfigError::Missing {
+        key: "telegram.token",
+        env: "TELEGRAM_BOT_TOKEN",
+      });
+    }
+    if let Some(ref secret) = self.telegram.webhook_secret {
+      // what Telegram accepts as a secret token
+      let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+      if secret.is_empty() || secret.len() > 256 || !secret.chars().all(valid) {
+        return Err(ConfigError::Invalid {
+          key: "telegram.webhook_secret".into(),
+          reason: "expected 1-256 of A-Z, a-z, 0-9, _ and -".into(),
+        });
+      }
+    }
+    if self.control.token.as_ref().map_or(false, String::is_empty) {
+      return Err(ConfigError::Invalid {
+        key: "control.token".into(),
+        reason: "must not be empty".into(),
+      });
+    }
+    Ok(())
+  }
+}
+
+fn parse_env<T>(name: &str, value: &str) -> Result<T>
+where
+  T: std::str::FromStr,
+  T::Err: Display,
+{
+  value
+    .parse()
+    .map_err(|e: T::Err| invalid_env(name, &e.to_string()))
+}
+
+fn invalid_env(name: &str, reason: &str) -> ConfigError {
+  ConfigError::Invalid {
+    key: format!("env {}", name),
+    reason: reason.into(),
+  }
+}
+
+/// Set `key` of the section `name`, creating the section if needed
+fn set(sections: &mut Table, name: &str, key: &str, value: Value) {
+  let section = sections
+    .entry(name.to_string())
+    .or_insert_with(|| Value::Table(Table::new()));
+  if let Value::Table(ref mut section) = *section {
+    section.insert(key.into(), value);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Deserialize, Default, Debug)]
+  struct Section {
+    key: Option<String>,
+  }
+
+  #[test]
+  fn test_parse_config() {
+    let config = Config::parse(
+      "test.toml",
+      r#"
+        operator_chat = 123
+        [telegram]
+        token = "t"
+        webhook_bind = "0.0.0.0:8443"
+        [db]
+        backend = "memory"
+        [extensions.weather]
+        key = "abc"
+        [extensions.music]
+        key = 1
+      "#,
+    )
+    .unwrap();
+    assert_eq!(config.telegram.webhook_bind.port(), 8443);
+    assert_eq!(config.metrics.bind.port(), 6408);
+    assert_eq!(config.db.backend, Backend::Memory);
+    assert!(config.validate().is_ok());
+
+    let weather: Section = config.extension("weather");
+    assert_eq!(weather.key, Some("abc".into()));
+    let music: Section = config.extension("music");
+    assert_eq!(music.key, None);
+    let errors = config.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0]
+      .to_string()
+      .starts_with("Invalid [extensions.music]"));
+
+    let error = Config::parse("test.toml", "[telegram]\ntokn = \"t\"");
+    assert!(error
+      .unwrap_err()
+      .to_string()
+      .contains("unknown field `tokn`"));
+    let error = Config::default().validate().unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Missing telegram.token (or env TELEGRAM_BOT_TOKEN)"
+    );
+  }
+}
@@ -1,99 +1,379 @@
+use crate::bot::{BotCommand, BotCommandScope, SetMyCommands, SetWebhook};
 use crate::common::*;
+use crate::extensions::callback::FEATURE_DISABLED;
+use crate::extensions::ChatMemberChange;
+use crate::services::audit::AuditFilter;
+use crate::services::control::{self, Action, Call, Reply};
+use crate::services::control::{RunCommand, SendText};
+use crate::services::metrics;
+use crate::services::webhook;
+
+use futures::sync::mpsc;
+use serde_json::Value as JsonValue;
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{self, Instant};
+
+/// How often due jobs are looked up
+const TICK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// How long jobs of extensions that are not loaded are put off
+const DEFER_MINUTES: i64 = 10;
+
+enum Event {
+  Update(tg::Update),
+  Tick,
+  Control(Call),
+}
 
 pub struct Context {
-  pub bot: tg::Api,
+  pub bot: Bot,
   pub handle: reactor::Handle,
-  pub bypass: Cell<bool>,
-  pub exts: RefCell<Vec<Box<BotExtension>>>,
+  pub exts: Vec<RefCell<Box<BotExtension>>>,
   pub logger: Logger,
   pub guard: SafetyGuard,
   pub names: NameMap,
-  pub db: Db,
+  pub switches: RefCell<ExtSwitch>,
+  pub errors: ErrorReporter,
+  pub supervisor: Supervisor,
+  pub db: Box<Storage>,
+  pub config: Config,
+  incidents: IncidentThrottle,
+  /// Config items changed behind their owner's back, see `reload_conf`
+  reloads: RefCell<Vec<String>>,
+  /// Set by `serve_control`
+  control_calls: Option<mpsc::UnboundedReceiver<Call>>,
 }
 
 impl Context {
-  pub fn new(bot: tg::Api, handle: reactor::Handle, logger: Logger) -> Context {
-    use crate::ContextExtension;
-    let db = Db::init();
-
-    let guard = SafetyGuard::new(&db);
-    let names = NameMap::new(&db);
+  /// Keeping the state in `db`, whichever backend the deployment chose
+  pub fn new(
+    bot: Bot,
+    handle: reactor::Handle,
+    logger: Logger,
+    db: Box<Storage>,
+    config: Config,
+  ) -> Context {
+    let operator_chat = config.operator_chat.map(tg::ChatId::from);
+    let errors = ErrorReporter::new(bot.clone(), logger.clone(), operator_chat);
+    bot.outbox().set_errors(errors.clone());
+    let guard = load_ext::<SafetyGuard>(&db, &config, &errors);
+    let names = load_ext::<NameMap>(&db, &config, &errors);
+    let switches = RefCell::new(load_ext::<ExtSwitch>(&db, &config, &errors));
+    let supervisor = Supervisor::new(errors.clone());
 
     Context {
-      bypass: Cell::new(false),
-      exts: RefCell::new(vec![]),
+      exts: vec![],
       bot,
       handle,
       logger,
       db,
+      config,
       guard,
       names,
+      switches,
+      errors,
+      supervisor,
+      incidents: IncidentThrottle::default(),
+      reloads: RefCell::new(vec![]),
+      control_calls: None,
     }
   }
 
+  /// Spawn a future on behalf of `ext`, a panic in it is isolated the
+  /// same way as one in the extension's handlers
+  pub fn spawn<F>(&self, ext: &str, future: F)
+  where
+    F: Future<Item = (), Error = ()> + 'static,
+  {
+    self.handle.spawn(self.supervisor.wrap(ext, future))
+  }
+
   pub fn plug_ext<T: BotExtension + 'static>(&mut self) {
     let plugin = T::init(&self);
     info!(self.logger, "Loading plugin {}", plugin.name());
-    self.exts.borrow_mut().push(Box::new(plugin));
+
+    // keep the chain sorted by priority, in plugging order otherwise
+    let pos = self
+      .exts
+      .iter()
+      .position(|ext| ext.borrow().priority() < plugin.priority())
+      .unwrap_or_else(|| self.exts.len());
+    self.exts.insert(pos, RefCell::new(Box::new(plugin)));
   }
 
   pub fn serve_poll<'a>(
     &'a mut self,
   ) -> Box<Future<Item = (), Error = ()> + 'a> {
     let req = self.bot.send(tg::DeleteWebhook);
-    Box::new(req.map_err(|_| ()).then(|_| {
-      self
-        .bot
-        .stream()
-        .for_each(move |update| {
-          self.process_update(update);
-          ok(())
-        })
-        .map_err(|_| ())
+    Box::new(req.map_err(|_| ()).then(move |_| {
+      let updates = self.bot.stream().map_err(|_| ());
+      self.serve(updates)
     }))
   }
 
   pub fn serve_webhook<'a>(
     &'a mut self,
     callback_url: &str,
-    bind: &str,
+    addr: &SocketAddr,
   ) -> Box<Future<Item = (), Error = ()> + 'a> {
-    let mut webhook = self.bot.webhook();
-    webhook.register(callback_url);
-    webhook.serve_at(
-      bind
-        .parse()
-        .unwrap_or_else(|_| panic!("invalid bind format {}", bind)),
-    );
-    Box::new(webhook.for_each(move |update| {
-      self.process_update(update);
+    let secret = match self.config.telegram.webhook_secret.clone() {
+      Some(secret) => secret,
+      None => webhook::new_secret()
+        .unwrap_or_else(|e| panic!("Failed to make a webhook secret: {}", e)),
+    };
+    let updates =
+      webhook::listen(addr, secret.clone(), &self.handle, self.logger.clone())
+        .unwrap_or_else(|e| panic!("Failed to bind on {}: {}", addr, e));
+
+    let logger = self.logger.clone();
+    let req = self.bot.send(SetWebhook::new(callback_url, &secret));
+    Box::new(
+      req
+        .map_err(move |e| error!(logger, "Failed to register webhook: {}", e))
+        .then(move |_| self.serve(updates)),
+    )
+  }
+
+  /// Serve the control API on `addr` for requests bearing `token`, the
+  /// calls are processed along with the updates
+  pub fn serve_control(
+    &mut self,
+    addr: &SocketAddr,
+    token: String,
+  ) -> io::Result<()> {
+    let (calls, received) = mpsc::unbounded();
+    let logger = self.logger.clone();
+    control::serve(addr, token, calls, &self.handle, logger)?;
+    self.control_calls = Some(received);
+    Ok(())
+  }
+
+  /// Process updates as they come, running due jobs in between
+  fn serve<'a, S>(
+    &'a mut self,
+    updates: S,
+  ) -> Box<Future<Item = (), Error = ()> + 'a>
+  where
+    S: Stream<Item = tg::Update, Error = ()> + 'a,
+  {
+    let ticks = reactor::Interval::new(TICK_INTERVAL, &self.handle)
+      .expect("Failed to create timer")
+      .map(|_| Event::Tick)
+      .map_err(|_| ());
+    let control: Box<Stream<Item = Event, Error = ()>> =
+      match self.control_calls.take() {
+        Some(calls) => Box::new(calls.map(Event::Control)),
+        None => Box::new(futures::stream::empty()),
+      };
+    let events = updates.map(Event::Update).select(ticks).select(control);
+
+    Box::new(events.for_each(move |event| {
+      match event {
+        Event::Update(update) => self.process_update(update),
+        Event::Tick => self.run_due_jobs(Local::now()),
+        Event::Control(call) => self.process_control(call),
+      }
       ok(())
     }))
   }
 
+  /// A config item of an extension, the default if missing or unreadable
+  pub fn load_conf<T: Versioned + Default>(&self, key: &str) -> T {
+    match self.db.load_conf(key) {
+      Ok(value) => value.unwrap_or_default(),
+      Err(e) => {
+        self.errors.report(key, &e);
+        T::default()
+      }
+    }
+  }
+
+  pub fn save_conf<T: Versioned>(&self, key: &str, value: &T) {
+    if let Err(e) = self.db.save_conf(key, value) {
+      self.errors.report(key, &e);
+    }
+  }
+
+  /// The outcome of a query made by `source`, the default if it failed
+  pub fn or_report<T: Default>(&self, source: &str, result: DbResult<T>) -> T {
+    result.unwrap_or_else(|e| {
+      self.errors.report(source, &e);
+      T::default()
+    })
+  }
+
+  /// Have the owner of the config item `key` read it again once the
+  /// current update is processed
+  pub fn reload_conf(&self, key: &str) {
+    self.reloads.borrow_mut().push(key.into());
+  }
+
+  /// Hand the config items changed while processing to their owners
+  fn apply_reloads(&mut self) {
+    let keys: Vec<_> = self.reloads.borrow_mut().drain(..).collect();
+    for key in keys {
+      info!(self.logger, "Reloading {}", key);
+      if key == SafetyGuard::key() {
+        self.guard = load_ext(&self.db, &self.config, &self.errors);
+      } else if key == NameMap::key() {
+        self.names = load_ext(&self.db, &self.config, &self.errors);
+      } else if key == ExtSwitch::key() {
+        *self.switches.get_mut() =
+          load_ext(&self.db, &self.config, &self.errors);
+      }
+      for ext in self.exts.iter() {
+        let mut ext = ext.borrow_mut();
+        let name = ext.name().to_string();
+        self.supervisor.run(&name, || ext.reload(&key, self));
+      }
+    }
+  }
+
+  /// Persist a context extension changed by a command in `msg`
+  fn save_ext<T: ContextExtension>(&self, ext: &T, msg: &tg::Message) {
+    if let Err(e) = ext.save(&self.db) {
+      self.errors.report_to(T::name(), &e, msg);
+    }
+  }
+
+  pub fn scheduler(&self) -> Scheduler {
+    Scheduler::new(&self.db)
+  }
+
+  pub fn audit_log(&self) -> AuditLog {
+    AuditLog::new(&self.db)
+  }
+
+  /// Persist an audit entry, forwarding incidents to the audit chat
+  pub fn audit(&self, entry: AuditEntry) {
+    self.record_audit(&entry);
+    let forward_to = self
+      .guard
+      .audit_chat
+      .filter(|_| entry.kind.is_incident() && self.incidents.admit(&entry));
+    if let Some(chat) = forward_to {
+      let text = format!("Incident: {}", entry.format());
+      self.bot.spawn(tg::SendMessage::new(chat, text));
+    }
+  }
+
+  /// Persist an audit entry without forwarding it
+  fn record_audit(&self, entry: &AuditEntry) {
+    let text = entry.format();
+    if entry.kind.is_incident() {
+      warn!(self.logger, "Audit: {}", text);
+    } else {
+      info!(self.logger, "Audit: {}", text);
+    }
+
+    if let Err(e) = self.audit_log().record(entry) {
+      error!(self.logger, "Failed to record audit entry: {}", e);
+    }
+  }
+
+  /// Run the jobs due by `now`. A job stays due until its extension
+  /// handles it without panicking. Jobs of extensions not loaded or
+  /// suspended are put off instead of being looked up on every tick.
+  pub fn run_due_jobs(&self, now: DateTime<Local>) {
+    let scheduler = self.scheduler();
+    let jobs = match scheduler.due(now) {
+      Ok(jobs) => jobs,
+      Err(e) => {
+        self.errors.report("scheduler", &e);
+        return;
+      }
+    };
+    for job in jobs {
+      let ext = self.exts.iter().find(|&ext| ext.borrow().name() == job.ext);
+      let deferred = match ext {
+        None => Some(now + Duration::minutes(DEFER_MINUTES)),
+        Some(_) => self.supervisor.suspended_until(&job.ext),
+      };
+      if let Some(until) = deferred {
+        trace!(self.logger, "Deferred job {} of {}", job.id, job.ext);
+        if let Err(e) = scheduler.defer(&job, until) {
+          self.errors.report("scheduler", &e);
+        }
+        continue;
+      }
+      let ext = ext.unwrap();
+
+      trace!(self.logger, "Running job {} of {}", job.id, job.ext);
+      let mut ext = ext.borrow_mut();
+      let ran = self
+        .supervisor
+        .run(&job.ext, || ext.process_job(&job, self));
+      if ran.is_some() {
+        if let Err(e) = scheduler.done(&job, now) {
+          self.errors.report("scheduler", &e);
+        }
+      }
+    }
+  }
+
+  /// Every query is answered, or the client shows a spinner until it
+  /// gives up
   pub fn process_callback(&mut self, query: &tg::CallbackQuery) {
     if !self.guard.is_safe(&query.message) {
+      self.audit(AuditEntry::callback(AuditKind::UnsafeCallback, query));
+      self
+        .bot
+        .spawn(query.answer("You are not allowed to do this"));
       return;
     }
 
     info!(self.logger, "Got callback {:?}", query);
-    if query.ext().is_none() {
-      warn!(self.logger, "Unknown callback from nowhere");
-      return;
-    }
+    let ext_name = match query.ext() {
+      Some(name) => name,
+      None => {
+        // most likely a button rendered before payloads were versioned
+        warn!(self.logger, "Malformed callback data: {}", query.data);
+        self.bot.answer_expired(query);
+        return;
+      }
+    };
 
-    let mut exts = self.exts.borrow_mut();
-    let ext = exts
-      .iter_mut()
-      .find(|ext| query.ext().unwrap() == ext.name());
+    let ext = self
+      .exts
+      .iter()
+      .find(|&ext| ext_name == ext.borrow().name());
 
     if ext.is_none() {
-      warn!(self.logger, "Cannot find ext: {}", query.ext().unwrap());
+      warn!(self.logger, "Cannot find ext: {}", ext_name);
+      self.bot.spawn(query.answer(FEATURE_DISABLED));
       return;
     }
 
-    let ext = ext.unwrap();
-    ext.process_callback(query, self);
+    let mut ext = ext.unwrap().borrow_mut();
+    let role = self.guard.role_of(&query.from, query.message.chat.id());
+    if role < ext.callback_role() {
+      self.audit(AuditEntry::callback(AuditKind::Denied, query));
+      self
+        .bot
+        .spawn(query.answer("You are not allowed to do this"));
+      return;
+    }
+    if !self.is_ext_enabled(query.message.chat.id(), ext.name()) {
+      trace!(
+        self.logger,
+        "Ignored callback for disabled ext {}",
+        ext.name()
+      );
+      self.bot.spawn(query.answer(FEATURE_DISABLED));
+      return;
+    }
+    if self.supervisor.is_suspended(ext.name()) {
+      self
+        .bot
+        .spawn(query.answer("Temporarily unavailable, try again later"));
+      return;
+    }
+    let name = ext.name().to_string();
+    self
+      .supervisor
+      .run(&name, || ext.process_callback(query, self));
   }
 
   pub fn process_message(&mut self, message: &tg::Message) {
@@ -103,36 +383,174 @@ impl Context {
     }
 
     info!(self.logger, "Got message {:?}", message);
-    self.exts_process_message(message);
+
+    if let Some(change) = ChatMemberChange::from_message(message) {
+      self.exts_dispatch(message.chat.id(), |ext| {
+        ext.process_chat_member(message, &change, self);
+        Dispatch::Continue
+      });
+      return;
+    }
+
+    if !self.check_role(message) {
+      return;
+    }
+    if self.process_builtin_cmd(message) {
+      metrics::COMMANDS.inc(&[("ext", "builtin")]);
+      return;
+    }
+
+    let is_cmd = message.cmd_name().is_some();
+    self.exts_dispatch(message.chat.id(), |ext| {
+      // counted whatever the extension makes of it, some answer and
+      // still let the chain go on
+      if is_cmd && ext.commands().iter().any(|cmd| cmd.matches(message)) {
+        metrics::COMMANDS.inc(&[("ext", ext.name())]);
+      }
+      ext.process(message, self)
+    });
+  }
+
+  pub fn process_edited_message(&mut self, message: &tg::Message) {
+    if !self.guard.is_safe(message) {
+      self.audit(AuditEntry::message(AuditKind::Unsafe, message));
+      return;
+    }
+
+    info!(self.logger, "Got edited message {:?}", message);
+
+    if !self.check_role(message) || self.process_builtin_cmd(message) {
+      return;
+    }
+
+    self.exts_dispatch(message.chat.id(), |ext| {
+      ext.process_edited(message, self)
+    });
+  }
+
+  pub fn process_channel_post(&mut self, post: &tg::ChannelPost) {
+    let chat_id = tg::ChatId::from(post.chat.id);
+    if !self.guard.is_safe_chat(chat_id) {
+      warn!(self.logger, "Ignored post from unsafe channel: {:?}", post);
+      return;
+    }
+
+    info!(self.logger, "Got channel post {:?}", post);
+    self.exts_dispatch(chat_id, |ext| ext.process_channel_post(post, self));
+  }
+
+  pub fn process_inline_query(&mut self, query: &tg::InlineQuery) {
+    if !self.guard.is_safe_user(&query.from) {
+      // sent on every keystroke, so only kept for /audit
+      let entry = AuditEntry::inline_query(AuditKind::Unsafe, query);
+      self.record_audit(&entry);
+      self.bot.answer_inline(query, vec![]);
+      return;
+    }
+
+    info!(self.logger, "Got inline query {:?}", query);
+
+    // there is no chat, so the user's private chat decides which
+    // extensions are enabled
+    let chat = tg::ChatId::from(query.from.id);
+    let dispatch =
+      self.exts_dispatch(chat, |ext| ext.process_inline_query(query, self));
+    if dispatch == Dispatch::Continue {
+      self.bot.answer_inline(query, vec![]);
+    }
   }
 
   pub fn process_update(&mut self, update: tg::Update) {
+    // webhook deliveries are retried if we crash before answering
+    match self.db.mark_update(update.id, Local::now().timestamp()) {
+      Ok(true) => {}
+      Ok(false) => {
+        info!(self.logger, "Ignored duplicate update {}", update.id);
+        return;
+      }
+      // better twice than never
+      Err(e) => {
+        self.errors.report("updates", &e);
+      }
+    }
+
+    let kind = match update.kind {
+      tg::UpdateKind::Message(_) => "message",
+      tg::UpdateKind::EditedMessage(_) => "edited_message",
+      tg::UpdateKind::ChannelPost(_) => "channel_post",
+      tg::UpdateKind::EditedChannelPost(_) => "edited_channel_post",
+      tg::UpdateKind::CallbackQuery(_) => "callback_query",
+      tg::UpdateKind::InlineQuery(_) => "inline_query",
+      _ => "other",
+    };
+    metrics::UPDATES.inc(&[("kind", kind)]);
+    let start = Instant::now();
+
     match update.kind {
       tg::UpdateKind::Message(message) => {
         self.process_message(&message);
       }
+      tg::UpdateKind::EditedMessage(message) => {
+        self.process_edited_message(&message);
+      }
+      tg::UpdateKind::ChannelPost(post)
+      | tg::UpdateKind::EditedChannelPost(post) => {
+        self.process_channel_post(&post);
+      }
       tg::UpdateKind::CallbackQuery(query) => {
         self.process_callback(&query);
       }
+      tg::UpdateKind::InlineQuery(query) => {
+        self.process_inline_query(&query);
+      }
       _ => {}
     }
+    self.apply_reloads();
+    metrics::UPDATE_SECONDS.observe_since(&[("kind", kind)], start);
   }
 
-  fn exts_process_message(&self, msg: &tg::Message) {
-    let mut exts = self.exts.borrow_mut();
-    for ext in exts.iter_mut() {
-      if self.bypass.get() {
+  /// Run `f` on every extension enabled in `chat` until one of them
+  /// ends the chain, returns how the chain ended
+  fn exts_dispatch<F>(&self, chat: tg::ChatId, mut f: F) -> Dispatch
+  where
+    F: FnMut(&mut Box<BotExtension>) -> Dispatch,
+  {
+    for ext in self.exts.iter() {
+      let mut ext = ext.borrow_mut();
+      if !self.is_ext_enabled(chat, ext.name()) {
         trace!(
           self.logger,
-          "Not processing with plugin: {} (bypassed)",
+          "Not processing with plugin: {} (disabled in chat)",
           ext.name()
         );
-      } else {
-        trace!(self.logger, "Processing with plugin: {}", ext.name());
-        ext.process(msg, self);
+        continue;
+      }
+      if self.supervisor.is_suspended(ext.name()) {
+        trace!(
+          self.logger,
+          "Not processing with plugin: {} (suspended)",
+          ext.name()
+        );
+        continue;
+      }
+
+      trace!(self.logger, "Processing with plugin: {}", ext.name());
+      let name = ext.name().to_string();
+      let dispatch = self.supervisor.run(&name, || f(&mut ext));
+      match dispatch.unwrap_or(Dispatch::Continue) {
+        Dispatch::Continue => {}
+        Dispatch::Consumed => {
+          trace!(self.logger, "Message consumed by plugin: {}", ext.name());
+          return Dispatch::Consumed;
+        }
+        Dispatch::Stop => {
+          trace!(self.logger, "Chain stopped by plugin: {}", ext.name());
+          return Dispatch::Stop;
+        }
       }
     }
-    self.bypass.set(false);
+
+    Dispatch::Continue
   }
 
   pub fn prohibit_access(&self, msg: &tg::Message) {
@@ -143,10 +561,961 @@ impl Context {
       .clone();
     self.bot.spawn(req);
 
-    warn!(self.logger, "Prohibited access: {:?}", msg)
+    self.audit(AuditEntry::message(AuditKind::Unsafe, msg));
+  }
+
+  /// Refuse a command the sender's role is not enough for, returns true
+  /// if the message can be processed further
+  fn check_role(&self, msg: &tg::Message) -> bool {
+    let cmd = match self.commands().into_iter().find(|cmd| cmd.matches(msg)) {
+      Some(cmd) => cmd,
+      None => return true,
+    };
+    let required = self.guard.required_role(&cmd);
+    if self.guard.role_of(&msg.from, msg.chat.id()) >= required {
+      if required >= Role::Admin {
+        self.audit(AuditEntry::message(AuditKind::Privileged, msg));
+      }
+      return true;
+    }
+
+    self.audit(AuditEntry::message(AuditKind::Denied, msg));
+    let text = format!("You need to be {} to use /{}", required, cmd.name);
+    self.bot.reply_to(msg, text);
+    false
+  }
+
+  /// Handle commands provided by the context itself, returns true if the
+  /// message is consumed
+  fn process_builtin_cmd(&mut self, msg: &tg::Message) -> bool {
+    if help_command().matches(msg) {
+      self.bot.reply_to(msg, self.help_text(msg));
+    } else if ext_list_command().matches(msg) {
+      self.bot.reply_to(msg, self.ext_list_text(msg.chat.id()));
+    } else if ext_enable_command().matches(msg) {
+      self.switch_ext(msg, true);
+    } else if ext_disable_command().matches(msg) {
+      self.switch_ext(msg, false);
+    } else if grant_command().matches(msg) {
+      self.change_role(msg, true);
+    } else if revoke_command().matches(msg) {
+      self.change_role(msg, false);
+    } else if roles_command().matches(msg) {
+      self.bot.reply_to(msg, self.roles_text(msg.chat.id()));
+    } else if require_command().matches(msg) {
+      self.change_requirement(msg);
+    } else if safe_chat_add_command().matches(msg) {
+      self.switch_safe_chat(msg, true);
+    } else if safe_chat_remove_command().matches(msg) {
+      self.switch_safe_chat(msg, false);
+    } else if audit_command().matches(msg) {
+      self.bot.reply_to(msg, self.audit_text(msg));
+    } else if audit_here_command().matches(msg) {
+      self.switch_audit_chat(msg);
+    } else {
+      return false;
+    }
+    true
+  }
+
+  fn builtin_commands() -> Vec<Command> {
+    vec![
+      help_command(),
+      ext_list_command(),
+      ext_enable_command(),
+      ext_disable_command(),
+      grant_command(),
+      revoke_command(),
+      roles_command(),
+      require_command(),
+      safe_chat_add_command(),
+      safe_chat_remove_command(),
+      audit_command(),
+      audit_here_command(),
+    ]
   }
 
-  pub fn set_bypass(&self) {
-    self.bypass.replace(true);
+  pub fn is_ext_enabled(&self, chat: tg::ChatId, ext: &str) -> bool {
+    self.switches.borrow().is_enabled(chat, ext)
+  }
+
+  fn switch_ext(&self, msg: &tg::Message, enable: bool) {
+    let name = match msg.cmd_arg() {
+      Some(ref name) if !name.trim().is_empty() => name.trim().to_string(),
+      _ => {
+        let usage = if enable {
+          ext_enable_command().usage_text()
+        } else {
+          ext_disable_command().usage_text()
+        };
+        self.bot.reply_to(msg, usage);
+        return;
+      }
+    };
+
+    if !self.exts.iter().any(|ext| ext.borrow().name() == name) {
+      self
+        .bot
+        .reply_to(msg, format!("Unknown extension: {}", name));
+      return;
+    }
+
+    let chat = msg.chat.id();
+    let changed = {
+      let mut switches = self.switches.borrow_mut();
+      if enable {
+        switches.enable(chat, &name)
+      } else {
+        switches.disable(chat, &name)
+      }
+    };
+
+    let state = if enable { "enabled" } else { "disabled" };
+    if changed {
+      self.save_ext(&*self.switches.borrow(), msg);
+      let text = format!("Extension {} {} in this chat", name, state);
+      self.bot.reply_to(msg, text);
+    } else {
+      let text = format!("Extension {} is already {} here", name, state);
+      self.bot.reply_to(msg, text);
+    }
+  }
+
+  fn change_role(&mut self, msg: &tg::Message, grant: bool) {
+    let mut args = msg.cmd_args();
+    args.retain(|arg| !arg.is_empty());
+
+    let replied = match msg.reply_to_message.clone().map(|x| *x) {
+      Some(tg::MessageOrChannelPost::Message(refer)) => Some(refer.from.id),
+      _ => None,
+    };
+    let target = match replied {
+      Some(id) => Some(id),
+      None if !args.is_empty() => args
+        .remove(0)
+        .parse::<tg::Integer>()
+        .ok()
+        .map(tg::UserId::from),
+      None => None,
+    };
+    let role = match args.first() {
+      Some(role) if grant => role.parse::<Role>().map(Some),
+      None if grant => Err("Missing role".into()),
+      _ => Ok(None),
+    };
+    let (target, role) = match (target, role) {
+      (Some(target), Ok(role)) => (target, role),
+      (_, Err(e)) => {
+        self.bot.reply_to(msg, e.to_string());
+        return;
+      }
+      (None, _) => {
+        let usage = if grant {
+          grant_command().usage_text()
+        } else {
+          revoke_command().usage_text()
+        };
+        self.bot.reply_to(msg, usage);
+        return;
+      }
+    };
+
+    // nobody hands out or takes away more than their own role
+    let chat = msg.chat.id();
+    let own = self.guard.role_of(&msg.from, chat);
+    let current = self.guard.granted(chat, target);
+    if role.map_or(false, |r| r > own) || current.map_or(false, |r| r > own) {
+      let text = format!("You cannot change roles above {}", own);
+      self.bot.reply_to(msg, text);
+      return;
+    }
+
+    let user = self.user_label(target);
+    let text = match role {
+      Some(role) => {
+        self.guard.grant(chat, target, role);
+        format!("{} is now {}", user, role)
+      }
+      None => match self.guard.revoke(chat, target) {
+        Some(role) => format!("{} is no longer {}", user, role),
+        None => format!("{} has no role to revoke", user),
+      },
+    };
+    self.save_ext(&self.guard, msg);
+    self.bot.reply_to(msg, text);
+  }
+
+  fn change_requirement(&mut self, msg: &tg::Message) {
+    let mut args = msg.cmd_args();
+    args.retain(|arg| !arg.is_empty());
+    let name = match args.first() {
+      Some(name) => name.trim_start_matches('/').to_string(),
+      None => {
+        self.bot.reply_to(msg, require_command().usage_text());
+        return;
+      }
+    };
+    if !self.commands().iter().any(|cmd| cmd.name == name) {
+      self
+        .bot
+        .reply_to(msg, format!("Unknown command: /{}", name));
+      return;
+    }
+
+    let role = match args.get(1).map(|role| role.parse::<Role>()) {
+      Some(Ok(role)) => Some(role),
+      Some(Err(e)) => {
+        self.bot.reply_to(msg, e.to_string());
+        return;
+      }
+      None => None,
+    };
+
+    self.guard.require(&name, role);
+    self.save_ext(&self.guard, msg);
+    let text = match role {
+      Some(role) => format!("/{} now requires {}", name, role),
+      None => format!("/{} requires its default role again", name),
+    };
+    self.bot.reply_to(msg, text);
+  }
+
+  /// Chats are given by id, as the bot ignores unsafe ones, except for
+  /// removing the current one
+  fn switch_safe_chat(&mut self, msg: &tg::Message, safe: bool) {
+    let mut args = msg.cmd_args();
+    args.retain(|arg| !arg.is_empty());
+    let chat = match args.first() {
+      Some(arg) => arg.parse::<tg::Integer>().ok().map(tg::ChatId::from),
+      None if !safe => Some(msg.chat.id()),
+      None => None,
+    };
+    let chat = match chat {
+      Some(chat) => chat,
+      None => {
+        let usage = if safe {
+          safe_chat_add_command().usage_text()
+        } else {
+          safe_chat_remove_command().usage_text()
+        };
+        self.bot.reply_to(msg, usage);
+        return;
+      }
+    };
+
+    let changed = if safe {
+      self.guard.add_safe_chat(chat)
+    } else {
+      self.guard.remove_safe_chat(chat)
+    };
+    let text = match (safe, changed) {
+      (true, true) => format!("Chat {} is now safe", chat),
+      (true, false) => format!("Chat {} is already safe", chat),
+      (false, true) => format!("Chat {} is no longer safe", chat),
+      (false, false) => format!("Chat {} is not safe", chat),
+    };
+    if changed {
+      self.save_ext(&self.guard, msg);
+    }
+    self.bot.reply_to(msg, text);
+  }
+
+  fn user_label(&self, id: tg::UserId) -> String {
+    match self.names.get(&id) {
+      ref name if name.is_empty() => id.to_string(),
+      name => format!("{} ({})", name, id),
+    }
+  }
+
+  fn roles_text(&self, chat: tg::ChatId) -> String {
+    let mut text = String::new();
+    writeln!(text, "Roles:").ok();
+    let mut roles = self.guard.roles_in(chat);
+    roles.sort_by_key(|&(id, role)| (role, id));
+    for (id, role) in roles.into_iter().rev() {
+      writeln!(text, "{}: {}", self.user_label(id), role).ok();
+    }
+
+    if !self.guard.requirements.is_empty() {
+      writeln!(text, "\nRequirements:").ok();
+      let mut reqs: Vec<_> = self.guard.requirements.iter().collect();
+      reqs.sort();
+      for (cmd, role) in reqs {
+        writeln!(text, "/{}: {}", cmd, role).ok();
+      }
+    }
+    text
+  }
+
+  fn audit_text(&self, msg: &tg::Message) -> String {
+    let filter = match AuditFilter::parse(&msg.cmd_args()) {
+      Ok(filter) => filter,
+      Err(e) => return e.to_string(),
+    };
+    let entries = match self.audit_log().query(&filter) {
+      Ok(entries) => entries,
+      Err(e) => return e.to_string(),
+    };
+    if entries.is_empty() {
+      return "No audit entries".into();
+    }
+
+    let mut text = String::new();
+    for entry in entries.iter().rev() {
+      writeln!(text, "{}", entry.format()).ok();
+    }
+    ellipsis(&text, 4000)
+  }
+
+  fn switch_audit_chat(&mut self, msg: &tg::Message) {
+    let off = msg.cmd_arg().map_or(false, |arg| arg.trim() == "off");
+    let text = if off {
+      self.guard.audit_chat = None;
+      "Incidents are no longer forwarded"
+    } else {
+      self.guard.audit_chat = Some(msg.chat.id());
+      "Incidents will be forwarded to this chat"
+    };
+    self.save_ext(&self.guard, msg);
+    self.bot.reply_to(msg, text);
+  }
+
+  fn ext_list_text(&self, chat: tg::ChatId) -> String {
+    let mut text = String::new();
+    writeln!(text, "Extensions in this chat:").ok();
+    for ext in self.exts.iter() {
+      let ext = ext.borrow();
+      let mark = if self.is_ext_enabled(chat, ext.name()) {
+        "\u{2714}"
+      } else {
+        "\u{2718}"
+      };
+      writeln!(text, "{} {}", mark, ext.name()).ok();
+    }
+    text
+  }
+
+  /// Whether an extension named `name` is plugged in
+  pub fn has_ext(&self, name: &str) -> bool {
+    self.exts.iter().any(|ext| match ext.try_borrow() {
+      Ok(ext) => ext.name() == name,
+      // only the one asking is borrowed
+      Err(_) => false,
+    })
+  }
+
+  /// `report()` of each extension, skipping the one asking, the errors
+  /// reported, suspended extensions and the requests the outbox gave up on
+  pub fn reports(&self) -> Vec<(String, String)> {
+    let mut reports: Vec<_> = self
+      .exts
+      .iter()
+      .filter_map(|ext| ext.try_borrow().ok())
+      .filter_map(|ext| {
+        let name = ext.name().to_string();
+        let report = self.supervisor.run(&name, || ext.report())?;
+        Some((name, report))
+      })
+      .collect();
+
+    let failures = self.bot.outbox().failures();
+    let mut report = format!("{} failed requests", failures.len());
+    for failure in failures {
+      write!(report, "\n{}", failure.format()).ok();
+    }
+    reports.push(("outbox".into(), report));
+
+    let errors = self.errors.recent();
+    let mut report = format!("{} distinct errors", errors.len());
+    for error in errors {
+      write!(report, "\n{}", error.format()).ok();
+    }
+    reports.push(("errors".into(), report));
+
+    let suspended = self.supervisor.suspended();
+    let mut report = format!("{} suspended extensions", suspended.len());
+    for (ext, until) in suspended {
+      write!(report, "\n{} until {}", ext, format_time(&until)).ok();
+    }
+    reports.push(("supervisor".into(), report));
+    reports
+  }
+
+  fn process_control(&mut self, call: Call) {
+    let Call { action, reply } = call;
+    info!(self.logger, "Got control call {:?}", action);
+    let result = match action {
+      Action::Send(send) => self.control_send(send),
+      Action::Command(cmd) => self.control_command(cmd),
+      Action::Reports => {
+        let reports: serde_json::Map<_, _> = self
+          .reports()
+          .into_iter()
+          .map(|(name, report)| (name, JsonValue::from(report)))
+          .collect();
+        Ok(reports.into())
+      }
+    };
+    // the client may have hung up already
+    reply.send(result).ok();
+  }
+
+  fn control_send(&self, send: SendText) -> Reply {
+    let chat = tg::ChatId::from(send.chat_id);
+    let mut req = tg::SendMessage::new(chat, send.text);
+    if send.markdown {
+      req.parse_mode(Markdown);
+    }
+    self.bot.spawn(req);
+    Ok(json!("queued"))
+  }
+
+  /// Run a command on a message made up for it, as one of the users
+  /// the control API may act as. It is audited as coming from the API,
+  /// and answered in the chat without a message to quote.
+  fn control_command(&mut self, cmd: RunCommand) -> Reply {
+    if !cmd.text.starts_with('/') {
+      return Err("Not a command".into());
+    }
+    if !self.config.control.users.contains(&cmd.user_id) {
+      return Err(format!("Not allowed to run as user {}", cmd.user_id));
+    }
+    let chat = tg::ChatId::from(cmd.chat_id);
+    if !self.guard.is_safe_chat(chat) {
+      return Err(format!("Not a safe chat: {}", chat));
+    }
+
+    let name = match self.names.get(&tg::UserId::from(cmd.user_id)) {
+      ref name if name.is_empty() => cmd.user_id.to_string(),
+      name => name,
+    };
+    let msg = cmd.message(&name).map_err(|e| e.to_string())?;
+    self.audit(AuditEntry::message(AuditKind::Control, &msg));
+    self.process_message(&msg);
+    self.apply_reloads();
+    Ok(json!("dispatched"))
+  }
+
+  pub fn commands(&self) -> Vec<Command> {
+    let mut commands = Self::builtin_commands();
+    for ext in self.exts.iter() {
+      commands.extend(ext.borrow().commands());
+    }
+    commands
+  }
+
+  pub fn help_text(&self, msg: &tg::Message) -> String {
+    let role = self.guard.role_of(&msg.from, msg.chat.id());
+    let mut commands = self.commands().into_iter().filter(|cmd| {
+      let allowed = role >= self.guard.required_role(cmd);
+      cmd.is_visible_in(&msg.chat, allowed)
+    });
+
+    let query = msg.cmd_arg().unwrap_or_default();
+    let query = query.trim().trim_start_matches('/');
+    if !query.is_empty() {
+      return match commands.find(|cmd| cmd.name == query) {
+        Some(cmd) => cmd.help_detail(),
+        None => format!("Unknown command: /{}", query),
+      };
+    }
+
+    let mut text = String::new();
+    writeln!(text, "Available commands:").ok();
+    for cmd in commands {
+      writeln!(text, "{}", cmd.help_line()).ok();
+    }
+    text
+  }
+
+  /// Push the command list to Telegram so clients can auto-complete them
+  pub fn publish_commands(&self) {
+    let commands = self.commands();
+    let scopes = [
+      (BotCommandScope::Default, Visibility::Public),
+      (BotCommandScope::AllPrivateChats, Visibility::Private),
+      (BotCommandScope::AllGroupChats, Visibility::Group),
+    ];
+
+    for (scope, visibility) in scopes.iter() {
+      let list = commands
+        .iter()
+        .filter(|cmd| cmd.is_listable())
+        .filter(|cmd| {
+          cmd.visibility == Visibility::Public || cmd.visibility == *visibility
+        })
+        .map(BotCommand::from)
+        .collect();
+
+      let logger = self.logger.clone();
+      let req = self
+        .bot
+        .send(SetMyCommands::new(list, *scope))
+        .map_err(move |e| warn!(logger, "Failed to publish commands: {}", e));
+      self.handle.spawn(req);
+    }
+  }
+}
+
+fn help_command() -> Command {
+  Command::new("help", "show available commands")
+    .usage("[command]")
+    .arg("command", "show detailed usage of the given command")
+    .requires(Role::Guest)
+}
+
+fn ext_list_command() -> Command {
+  Command::new("ext_list", "list extensions enabled in this chat")
+}
+
+fn ext_enable_command() -> Command {
+  Command::new("ext_enable", "enable an extension in this chat")
+    .usage("<name>")
+    .arg("name", "extension name as shown in /ext_list")
+    .requires(Role::Admin)
+}
+
+fn ext_disable_command() -> Command {
+  Command::new("ext_disable", "disable an extension in this chat")
+    .usage("<name>")
+    .arg("name", "extension name as shown in /ext_list")
+    .requires(Role::Admin)
+}
+
+fn grant_command() -> Command {
+  Command::new("grant", "give a user a role here, owners everywhere")
+    .usage("[user_id] <role>")
+    .arg("user_id", "omitted when replying to the user's message")
+    .arg("role", "guest, member, admin or owner, up to your own")
+    .requires(Role::Admin)
+}
+
+fn revoke_command() -> Command {
+  Command::new("revoke", "reset a user to the chat's default role")
+    .usage("[user_id]")
+    .arg("user_id", "omitted when replying to the user's message")
+    .requires(Role::Admin)
+}
+
+fn roles_command() -> Command {
+  Command::new("roles", "list roles granted here and command requirements")
+    .requires(Role::Admin)
+}
+
+fn require_command() -> Command {
+  Command::new("require", "change the role a command requires")
+    .usage("<command> [role]")
+    .arg("role", "omit to restore the command's default")
+    .requires(Role::Owner)
+}
+
+fn safe_chat_add_command() -> Command {
+  Command::new("safe_chat_add", "let the bot serve a chat")
+    .usage("<chat_id>")
+    .requires(Role::Owner)
+}
+
+fn safe_chat_remove_command() -> Command {
+  Command::new("safe_chat_remove", "stop serving a chat")
+    .usage("[chat_id]")
+    .arg("chat_id", "this chat if omitted")
+    .requires(Role::Owner)
+}
+
+fn audit_command() -> Command {
+  Command::new("audit", "show recent audit entries")
+    .usage("[kind] [user:<id>] [chat:<id>] [count]")
+    .arg(
+      "kind",
+      "unsafe, unsafe_callback, denied, privileged or control",
+    )
+    .arg("count", "number of entries, 20 by default")
+    .requires(Role::Admin)
+}
+
+fn audit_here_command() -> Command {
+  Command::new("audit_here", "forward incidents to this chat")
+    .usage("[off]")
+    .arg("off", "stop forwarding incidents")
+    .requires(Role::Owner)
+}
+
+/// Load a context extension, starting over from its config section if
+/// the stored state is unreadable
+fn load_ext<T: ContextExtension>(
+  db: &Storage,
+  config: &Config,
+  errors: &ErrorReporter,
+) -> T {
+  T::new(db, config).unwrap_or_else(|e| {
+    errors.report(T::name(), &e);
+    T::init(None, config)
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::common::*;
+  use crate::extensions::link_cleanser::LinkCleanser;
+  use crate::extensions::weather::Weather;
+  use crate::testing::*;
+
+  #[test]
+  fn test_help_lists_extension_commands() {
+    let mut h = Harness::new();
+    h.plug::<Weather>();
+    h.send_text(PRIVATE_CHAT, USER_ID, "/help");
+
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].method, "sendMessage");
+    assert_eq!(reqs[0].chat_id(), Some(PRIVATE_CHAT));
+    let text = reqs[0].text().unwrap();
+    assert!(text.contains("/help [command] - show available commands"));
+    assert!(text.contains("/add_loc <city> <long,lat> - add location"));
+  }
+
+  #[test]
+  fn test_ext_disable_per_chat() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<LinkCleanser>();
+    let link = "https://item.m.jd.com/product/4385461.html?utm_source=x";
+    h.send_text(GROUP_CHAT, USER_ID, "/ext_disable link_cleanser");
+    h.send_text(GROUP_CHAT, USER_ID, link);
+    h.send_text(PRIVATE_CHAT, USER_ID, link);
+
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 2);
+    assert_eq!(
+      reqs[0].text(),
+      Some("Extension link_cleanser disabled in this chat")
+    );
+    assert_eq!(reqs[1].chat_id(), Some(PRIVATE_CHAT));
+    assert_eq!(reqs[1].text(), Some("https://item.jd.com/4385461.html"));
+  }
+
+  #[test]
+  fn test_callbacks_of_disabled_exts_are_answered() {
+    use crate::extensions::reminder::ReminderPool;
+
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<ReminderPool>();
+    h.send_text(GROUP_CHAT, USER_ID, "/remind_me take pill");
+    let panel = h.take_requests().remove(0);
+    let shift = panel.button("+5 min").unwrap();
+    h.send_text(GROUP_CHAT, USER_ID, "/ext_disable reminder");
+    h.take_requests();
+
+    h.click(&panel, USER_ID, &shift);
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].method, "answerCallbackQuery");
+    assert_eq!(reqs[0].body["text"], FEATURE_DISABLED);
+
+    // nor is a button of an extension that is not loaded left hanging
+    let data = shift.replacen("reminder", "weather", 1);
+    h.click(&panel, USER_ID, &data);
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["text"], FEATURE_DISABLED);
+  }
+
+  #[test]
+  fn test_roles_gate_commands() {
+    let mut h = Harness::new();
+    h.ctx.guard.add_owner(tg::UserId::from(USER_ID));
+    h.plug::<LinkCleanser>();
+
+    // a friend in a safe group is only a member
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/ext_disable link_cleanser");
+    h.send_text(GROUP_CHAT, USER_ID, "/grant 101 admin");
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/ext_disable link_cleanser");
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/grant 102 owner");
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/require ext_list admin");
+    // nor does the grant reach other chats
+    h.send_text(PRIVATE_CHAT, OTHER_USER_ID, "/ext_disable link_cleanser");
+
+    let texts: Vec<_> = h
+      .take_requests()
+      .iter()
+      .map(|req| req.text().unwrap_or_default().to_string())
+      .collect();
+    assert_eq!(
+      texts,
+      vec![
+        "You need to be admin to use /ext_disable",
+        "101 is now admin",
+        "Extension link_cleanser disabled in this chat",
+        "You cannot change roles above admin",
+        "You need to be owner to use /require",
+        "You need to be admin to use /ext_disable",
+      ]
+    );
+
+    // the grant is persisted
+    let guard = SafetyGuard::new(&h.ctx.db, &h.ctx.config).unwrap();
+    let other = tg::UserId::from(OTHER_USER_ID);
+    let group = tg::ChatId::from(GROUP_CHAT);
+    assert_eq!(guard.granted(group, other), Some(Role::Admin));
+    assert_eq!(guard.granted(tg::ChatId::from(PRIVATE_CHAT), other), None);
+  }
+
+  #[test]
+  fn test_safe_chats_are_managed_by_owners() {
+    let mut h = Harness::new();
+    h.add_admin(OTHER_USER_ID);
+    h.ctx.guard.add_owner(tg::UserId::from(USER_ID));
+
+    h.send_text(PRIVATE_CHAT, OTHER_USER_ID, "/safe_chat_add -300");
+    h.send_text(PRIVATE_CHAT, USER_ID, "/safe_chat_add -300");
+    h.send_text(-300, OTHER_USER_ID, "/ext_list");
+    h.send_text(-300, USER_ID, "/safe_chat_remove");
+    h.send_text(PRIVATE_CHAT, USER_ID, "/safe_chat_remove -300");
+    let texts: Vec<_> = h
+      .take_requests()
+      .iter()
+      .map(|req| req.text().unwrap_or_default().to_string())
+      .collect();
+    assert_eq!(texts[0], "You need to be owner to use /safe_chat_add");
+    assert_eq!(texts[1], "Chat -300 is now safe");
+    assert!(texts[2].starts_with("Extensions"));
+    assert_eq!(texts[3], "Chat -300 is no longer safe");
+    assert_eq!(texts[4], "Chat -300 is not safe");
+
+    let guard = SafetyGuard::new(&h.ctx.db, &h.ctx.config).unwrap();
+    assert!(!guard.is_safe_chat(tg::ChatId::from(-300)));
+    assert!(guard.is_safe_chat(tg::ChatId::from(GROUP_CHAT)));
+  }
+
+  #[test]
+  fn test_unreadable_guard_starts_from_config() {
+    let h = Harness::new();
+    let config = "[context.safety-guard]\nowners = [100]";
+    let config = Config::parse("test.toml", config).unwrap();
+    let saved = json!({"safe_chats": 1});
+    h.ctx.db.save_json("exts.safety-guard", 0, &saved).unwrap();
+
+    let guard = load_ext::<SafetyGuard>(&h.ctx.db, &config, &h.ctx.errors);
+    assert!(guard.owners.contains(&tg::UserId::from(USER_ID)));
+    let errors = h.ctx.errors.recent();
+    assert_eq!(errors[0].source, "safety-guard");
+    assert!(errors[0]
+      .message
+      .starts_with("Unreadable exts.safety-guard"));
+  }
+
+  #[test]
+  fn test_global_roles_are_kept_per_chat() {
+    let h = Harness::new();
+    let saved = json!({
+      "safe_chats": [GROUP_CHAT, PRIVATE_CHAT],
+      "roles": {"100": "owner", "101": "admin"},
+    });
+    h.ctx.db.save_json("exts.safety-guard", 0, &saved).unwrap();
+
+    let guard = SafetyGuard::new(&h.ctx.db, &h.ctx.config).unwrap();
+    assert!(guard.owners.contains(&tg::UserId::from(USER_ID)));
+    let other = tg::UserId::from(OTHER_USER_ID);
+    for &chat in &[GROUP_CHAT, PRIVATE_CHAT] {
+      let chat = tg::ChatId::from(chat);
+      assert_eq!(guard.granted(chat, other), Some(Role::Admin));
+    }
+  }
+
+  #[test]
+  fn test_audit_unsafe_access() {
+    let mut h = Harness::new();
+    h.ctx.guard.add_owner(tg::UserId::from(USER_ID));
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit_here");
+    h.send_text(-999, OTHER_USER_ID, "/weather");
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit unsafe");
+
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 4);
+    assert_eq!(
+      reqs[0].text(),
+      Some("Incidents will be forwarded to this chat")
+    );
+    assert_eq!(reqs[2].chat_id(), Some(PRIVATE_CHAT));
+    assert!(reqs[2].text().unwrap().starts_with("Incident: "));
+    let audit = reqs[3].text().unwrap();
+    assert_eq!(audit.lines().count(), 1);
+    assert!(audit.contains("[unsafe] User101 (101) in -999: /weather"));
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit privileged user:100");
+    let reqs = h.take_requests();
+    // including the /audit commands themselves
+    assert_eq!(reqs[0].text().unwrap().lines().count(), 3);
+  }
+
+  #[test]
+  fn test_incidents_are_throttled() {
+    let mut h = Harness::new();
+    h.ctx.guard.add_owner(tg::UserId::from(USER_ID));
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit_here");
+    h.send_text(-999, OTHER_USER_ID, "/weather");
+    h.send_text(-999, OTHER_USER_ID, "/weather");
+    h.inline_query(OTHER_USER_ID, "search");
+
+    let forwarded = h
+      .take_requests()
+      .iter()
+      .filter(|req| req.text().unwrap_or("").starts_with("Incident: "))
+      .count();
+    assert_eq!(forwarded, 1);
+
+    // all of them are recorded
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit unsafe");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text().unwrap().lines().count(), 3);
+  }
+
+  #[test]
+  fn test_control_calls() {
+    let mut h = Harness::new();
+    h.ctx.guard.add_owner(tg::UserId::from(USER_ID));
+    h.ctx.config.control.users = vec![USER_ID];
+    h.plug::<Weather>();
+    let call = |h: &mut Harness, action| {
+      let (call, replied) = Call::new(action);
+      h.ctx.process_control(call);
+      h.settle();
+      replied.wait().unwrap()
+    };
+
+    let send = SendText {
+      chat_id: GROUP_CHAT,
+      text: "*backup done*".into(),
+      markdown: true,
+    };
+    assert_eq!(call(&mut h, Action::Send(send)), Ok(json!("queued")));
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text(), Some("*backup done*"));
+    assert_eq!(reqs[0].body["parse_mode"], "Markdown");
+
+    let run = |user_id, text: &str| {
+      Action::Command(RunCommand {
+        chat_id: GROUP_CHAT,
+        user_id,
+        text: text.into(),
+      })
+    };
+    assert!(call(&mut h, run(USER_ID, "help")).is_err());
+    // nobody else can be posed as
+    let posing = call(&mut h, run(OTHER_USER_ID, "/ext_disable weather"));
+    assert_eq!(posing, Err("Not allowed to run as user 101".into()));
+    assert!(h.take_requests().is_empty());
+
+    let ran = call(&mut h, run(USER_ID, "/ext_disable weather"));
+    assert_eq!(ran, Ok(json!("dispatched")));
+    // answered in the chat, with nothing echoed or quoted
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].chat_id(), Some(GROUP_CHAT));
+    assert!(reqs[0].body["reply_to_message_id"].is_null());
+    assert!(!h
+      .ctx
+      .is_ext_enabled(tg::ChatId::from(GROUP_CHAT), "weather"));
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/audit control");
+    let reqs = h.take_requests();
+    let audit = reqs[0].text().unwrap();
+    assert!(audit.contains("[control] 100 (100) in -200: /ext_disable"));
+  }
+
+  #[test]
+  fn test_duplicate_updates_are_ignored() {
+    let mut h = Harness::new();
+    let update = json!({
+      "update_id": 42,
+      "message": fixtures::message(1, PRIVATE_CHAT, USER_ID, "/help"),
+    });
+    h.feed(serde_json::from_value(update.clone()).unwrap());
+    h.feed(serde_json::from_value(update).unwrap());
+    assert_eq!(h.take_requests().len(), 1);
+  }
+
+  struct Boom;
+
+  impl BotExtension for Boom {
+    fn init(_: &Context) -> Self {
+      Boom
+    }
+
+    fn process(&mut self, msg: &tg::Message, _: &Context) -> Dispatch {
+      if msg.is_cmd("boom") {
+        panic!("boom");
+      }
+      Dispatch::Continue
+    }
+
+    fn process_job(&mut self, _: &Job, _: &Context) {
+      panic!("boom");
+    }
+
+    fn name(&self) -> &str {
+      "boom"
+    }
+  }
+
+  #[test]
+  fn test_jobs_wait_for_their_extension() {
+    let mut h = Harness::new();
+    let at = Local::now() + Duration::minutes(1);
+    let once = Trigger::Once(at);
+    h.ctx.scheduler().schedule("boom", once, &()).unwrap();
+    h.run_jobs_at(at);
+    // put off rather than looked up again on the next tick
+    let jobs = h.ctx.scheduler().jobs("boom").unwrap();
+    assert_eq!(jobs.len(), 1);
+    let later = at + Duration::minutes(DEFER_MINUTES);
+    assert_eq!(jobs[0].next_run.timestamp(), later.timestamp());
+    assert!(h.ctx.scheduler().due(at).unwrap().is_empty());
+
+    // nor is it lost when the extension panics on it
+    h.plug::<Boom>();
+    h.run_jobs_at(later);
+    assert!(h.ctx.supervisor.is_suspended("boom"));
+    assert_eq!(h.ctx.scheduler().jobs("boom").unwrap().len(), 1);
+
+    // and waits for the suspension to end
+    h.run_jobs_at(later);
+    let until = h.ctx.supervisor.suspended_until("boom").unwrap();
+    let jobs = h.ctx.scheduler().jobs("boom").unwrap();
+    assert_eq!(jobs[0].next_run.timestamp(), until.timestamp());
+  }
+
+  #[test]
+  fn test_panicking_ext_is_suspended() {
+    let mut h = Harness::new();
+    h.plug::<Boom>().plug::<LinkCleanser>();
+    let link = "https://item.m.jd.com/product/4385461.html";
+    h.send_text(PRIVATE_CHAT, USER_ID, "/boom");
+    assert!(h.ctx.supervisor.is_suspended("boom"));
+
+    // the rest of the chain still runs
+    h.send_text(PRIVATE_CHAT, USER_ID, link);
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].text(), Some("https://item.jd.com/4385461.html"));
+
+    let errors = h.ctx.errors.recent();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].source, "boom");
+    assert!(errors[0].message.starts_with("panicked: boom"));
+  }
+
+  #[test]
+  fn test_prohibit_unsafe_chat() {
+    let mut h = Harness::new();
+    h.plug::<Weather>();
+    h.send_text(-999, USER_ID, "/weather");
+
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].chat_id(), Some(-999));
+    assert!(reqs[0].text().unwrap().starts_with("You're not permitted"));
   }
 }
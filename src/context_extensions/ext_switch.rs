@@ -0,0 +1,45 @@
+use crate::common::*;
+
+/// Extensions disabled on a per-chat basis
+#[derive(Serialize, Deserialize, Default)]
+pub struct ExtSwitch {
+  pub disabled: HashMap<tg::ChatId, HashSet<String>>,
+}
+
+impl Versioned for ExtSwitch {}
+
+impl ContextExtension for ExtSwitch {
+  fn name() -> &'static str {
+    "ext-switch"
+  }
+}
+
+impl ExtSwitch {
+  pub fn is_enabled(&self, chat: tg::ChatId, ext: &str) -> bool {
+    self
+      .disabled
+      .get(&chat)
+      .map(|exts| !exts.contains(ext))
+      .unwrap_or(true)
+  }
+
+  /// Returns false if the extension was already enabled
+  pub fn enable(&mut self, chat: tg::ChatId, ext: &str) -> bool {
+    let removed = self
+      .disabled
+      .get_mut(&chat)
+      .map(|exts| exts.remove(ext))
+      .unwrap_or(false);
+
+    if self.disabled.get(&chat).map(HashSet::is_empty) == Some(true) {
+      self.disabled.remove(&chat);
+    }
+
+    removed
+  }
+
+  /// Returns false if the extension was already disabled
+  pub fn disable(&mut self, chat: tg::ChatId, ext: &str) -> bool {
+    self.disabled.entry(chat).or_default().insert(ext.into())
+  }
+}
@@ -1,30 +1,40 @@
 pub mod safety_guard;
 pub mod name_map;
+pub mod ext_switch;
 
 use crate::common::*;
 
 pub trait ContextExtension
 where
-  Self: Default + Serialize + DeserializeOwned,
+  Self: Default + Versioned,
 {
   fn name() -> &'static str;
-  fn new_from_env() -> Option<Self> {
+  /// The config item the state is saved as
+  fn key() -> String {
+    format!("exts.{}", Self::name())
+  }
+  /// The initial state before anything is saved, from the extension's
+  /// `[context.<name>]` section
+  fn new_from_config(_config: &Config) -> Option<Self> {
     None
   }
 
-  fn new_from_db(db: &Db) -> Option<Self> {
-    let key = format!("exts.{}", Self::name());
-    db.load_conf(&key)
+  fn new_from_db(db: &Storage) -> DbResult<Option<Self>> {
+    db.load_conf(&Self::key())
   }
 
-  fn save(&self, db: &Db) {
-    let key = format!("exts.{}", Self::name());
-    db.save_conf(&key, self)
+  fn save(&self, db: &Storage) -> DbResult<()> {
+    db.save_conf(&Self::key(), self)
   }
 
-  fn new(db: &Db) -> Self {
-    Self::new_from_db(db)
-      .or_else(Self::new_from_env)
+  /// The state to run with, given the saved one if any
+  fn init(saved: Option<Self>, config: &Config) -> Self {
+    saved
+      .or_else(|| Self::new_from_config(config))
       .unwrap_or_default()
   }
+
+  fn new(db: &Storage, config: &Config) -> DbResult<Self> {
+    Ok(Self::init(Self::new_from_db(db)?, config))
+  }
 }
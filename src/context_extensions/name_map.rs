@@ -5,20 +5,21 @@ pub struct NameMap {
   pub names: HashMap<tg::UserId, String>,
 }
 
+impl Versioned for NameMap {}
+
 impl ContextExtension for NameMap {
   fn name() -> &'static str {
     "name-map"
   }
-  fn new_from_env() -> Option<Self> {
+  /// Given as `name = user_id` pairs
+  fn new_from_config(config: &Config) -> Option<Self> {
+    let names: HashMap<String, tg::Integer> = config.context(Self::name());
+    if names.is_empty() {
+      return None;
+    }
     let mut ret: Self = Default::default();
-    let env_value = env::var("NAME_MAP").unwrap_or_else(|_| "".into());
-    let assocs = env_value.split(',');
-    for pair in assocs {
-      let mut pair = pair.split("->");
-      let user_id: tg::UserId =
-        pair.nth(0)?.parse::<tg::Integer>().ok()?.into();
-      let user_name = pair.nth(1).expect("Invalid name map");
-      ret.add_name_map(user_id, user_name);
+    for (name, user_id) in names {
+      ret.add_name_map(user_id.into(), &name);
     }
     Some(ret)
   }
@@ -1,33 +1,238 @@
 use crate::common::*;
 
+use std::fmt;
+use std::str::FromStr;
+
+/// Access levels, each including the ones below
+#[derive(
+  Serialize,
+  Deserialize,
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+  Guest,
+  Member,
+  Admin,
+  Owner,
+}
+
+impl fmt::Display for Role {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match *self {
+      Role::Guest => "guest",
+      Role::Member => "member",
+      Role::Admin => "admin",
+      Role::Owner => "owner",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for Role {
+  type Err = FondbotError;
+
+  fn from_str(s: &str) -> Result<Role> {
+    match s.to_lowercase().as_str() {
+      "guest" => Ok(Role::Guest),
+      "member" => Ok(Role::Member),
+      "admin" => Ok(Role::Admin),
+      "owner" => Ok(Role::Owner),
+      _ => Err(format!("Unknown role: {}", s).into()),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct SafetyGuard {
   pub safe_chats: HashSet<tg::ChatId>,
+  /// Owners are owners in every chat
+  #[serde(default)]
+  pub owners: HashSet<tg::UserId>,
+  /// Users with a role other than the default one, by chat
+  #[serde(default)]
+  pub grants: HashMap<tg::ChatId, HashMap<tg::UserId, Role>>,
+  /// Roles required by commands, overriding their defaults
+  #[serde(default)]
+  pub requirements: HashMap<String, Role>,
+  /// Where incidents are forwarded as they happen
+  #[serde(default)]
+  pub audit_chat: Option<tg::ChatId>,
+  /// Superseded by `grants`, only read to migrate older configs
+  #[serde(default, skip_serializing)]
+  admins: HashSet<tg::UserId>,
+  /// Roles that applied in every chat, superseded by `owners` and
+  /// `grants`, only read to migrate older configs
+  #[serde(default, skip_serializing)]
+  roles: HashMap<tg::UserId, Role>,
+}
+
+/// The `[context.safety-guard]` section
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SafetyConfig {
+  safe_chats: Vec<tg::Integer>,
+  admins: Vec<tg::Integer>,
+  /// Applied on every start, so nobody can lock them out
+  owners: Vec<tg::Integer>,
+  audit_chat: Option<tg::Integer>,
 }
 
+impl Versioned for SafetyGuard {}
+
 impl ContextExtension for SafetyGuard {
   fn name() -> &'static str {
     "safety-guard"
   }
-  fn new_from_env() -> Option<Self> {
-    let mut ret: Self = Default::default();
+  fn new_from_config(config: &Config) -> Option<Self> {
+    Self::from_section(&config.context(Self::name()))
+  }
 
-    env::var("SAFE_CHATS").ok().map(|x| {
-      let xs = x.split(',');
-      xs.filter_map(|v| v.parse::<tg::Integer>().ok())
-        .map(tg::ChatId::from)
-        .for_each(|chat_id| ret.add_safe_chat(chat_id));
-      ret
-    })
+  fn init(saved: Option<Self>, config: &Config) -> Self {
+    let section: SafetyConfig = config.context(Self::name());
+    let mut guard = saved
+      .or_else(|| Self::from_section(&section))
+      .unwrap_or_default();
+
+    // older roles applied everywhere, so they are kept in every safe chat
+    let admins = guard.admins.drain().map(|id| (id, Role::Admin));
+    let legacy: Vec<_> = admins.chain(guard.roles.drain()).collect();
+    let chats: Vec<_> = guard.safe_chats.iter().cloned().collect();
+    for (user_id, role) in legacy {
+      match role {
+        Role::Owner => guard.add_owner(user_id),
+        _ => {
+          for &chat in &chats {
+            guard.grant(chat, user_id, role);
+          }
+        }
+      }
+    }
+    for &user_id in &section.owners {
+      guard.add_owner(user_id.into());
+    }
+    guard
+  }
+}
+
+impl SafetyGuard {
+  fn from_section(section: &SafetyConfig) -> Option<Self> {
+    if section.safe_chats.is_empty() {
+      return None;
+    }
+
+    let mut ret: Self = Default::default();
+    for &chat_id in &section.safe_chats {
+      ret.add_safe_chat(chat_id.into());
+      for &user_id in &section.admins {
+        ret.add_admin(chat_id.into(), user_id.into());
+      }
+    }
+    ret.audit_chat = section.audit_chat.map(tg::ChatId::from);
+    Some(ret)
   }
 }
 
 impl SafetyGuard {
   pub fn is_safe(&self, msg: &tg::Message) -> bool {
-    self.safe_chats.contains(&msg.chat.id())
+    self.is_safe_chat(msg.chat.id())
+  }
+
+  pub fn is_safe_chat(&self, chat: tg::ChatId) -> bool {
+    self.safe_chats.contains(&chat)
+  }
+
+  /// Users without a chat context, e.g. in inline queries, are trusted if
+  /// their private chat with the bot is
+  pub fn is_safe_user(&self, user: &tg::User) -> bool {
+    self.role_of(user, tg::ChatId::from(user.id)) >= Role::Member
+  }
+
+  /// Owners everywhere, otherwise the role granted in a safe chat or a
+  /// member there, and a guest in any other chat
+  pub fn role_of(&self, user: &tg::User, chat: tg::ChatId) -> Role {
+    match self.granted(chat, user.id) {
+      Some(Role::Owner) => Role::Owner,
+      _ if !self.is_safe_chat(chat) => Role::Guest,
+      Some(role) => role,
+      None => Role::Member,
+    }
+  }
+
+  /// The role explicitly given to a user in `chat`, if any
+  pub fn granted(&self, chat: tg::ChatId, id: tg::UserId) -> Option<Role> {
+    if self.owners.contains(&id) {
+      return Some(Role::Owner);
+    }
+    self.grants.get(&chat)?.get(&id).cloned()
+  }
+
+  /// Owners and the roles granted in `chat`
+  pub fn roles_in(&self, chat: tg::ChatId) -> Vec<(tg::UserId, Role)> {
+    let owners = self.owners.iter().map(|&id| (id, Role::Owner));
+    let grants = self.grants.get(&chat).into_iter().flatten();
+    owners
+      .chain(grants.map(|(&id, &role)| (id, role)))
+      .collect()
+  }
+
+  pub fn required_role(&self, cmd: &Command) -> Role {
+    self.requirements.get(cmd.name).cloned().unwrap_or(cmd.role)
+  }
+
+  /// False if it was safe already
+  pub fn add_safe_chat(&mut self, id: tg::ChatId) -> bool {
+    self.safe_chats.insert(id)
+  }
+
+  /// False if it was not safe. Roles granted there are kept, but only
+  /// apply again if it is made safe again.
+  pub fn remove_safe_chat(&mut self, id: tg::ChatId) -> bool {
+    self.safe_chats.remove(&id)
+  }
+
+  pub fn add_owner(&mut self, id: tg::UserId) {
+    self.owners.insert(id);
+  }
+
+  pub fn add_admin(&mut self, chat: tg::ChatId, id: tg::UserId) {
+    self.grant(chat, id, Role::Admin);
+  }
+
+  /// Owners are made owners everywhere, other roles apply in `chat`
+  pub fn grant(&mut self, chat: tg::ChatId, id: tg::UserId, role: Role) {
+    if role == Role::Owner {
+      return self.add_owner(id);
+    }
+    self.owners.remove(&id);
+    self.grants.entry(chat).or_default().insert(id, role);
+  }
+
+  /// Back to the default role, returns the one revoked. Owners lose
+  /// their role everywhere.
+  pub fn revoke(&mut self, chat: tg::ChatId, id: tg::UserId) -> Option<Role> {
+    if self.owners.remove(&id) {
+      return Some(Role::Owner);
+    }
+    let grants = self.grants.get_mut(&chat)?;
+    let role = grants.remove(&id);
+    if grants.is_empty() {
+      self.grants.remove(&chat);
+    }
+    role
   }
 
-  pub fn add_safe_chat(&mut self, id: tg::ChatId) {
-    self.safe_chats.insert(id);
+  pub fn require(&mut self, cmd: &str, role: Option<Role>) {
+    match role {
+      Some(role) => self.requirements.insert(cmd.into(), role),
+      None => self.requirements.remove(cmd),
+    };
   }
 }
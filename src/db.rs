@@ -1,21 +1,86 @@
 use diesel;
-use diesel::expression::sql_literal::sql;
-use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
-use diesel::types::{Bool, Text};
 
-use serde::de::DeserializeOwned;
-use serde::Serialize;
+use failure::Fail;
 use serde_json;
 use std;
 
-const DB_FILE: &str = "data.db";
+mod memory;
+mod migrations;
+mod sqlite;
+mod state;
 
-pub struct Db {
-  conn: SqliteConnection,
+pub use self::memory::MemoryStorage;
+pub use self::migrations::{Migrated, MIGRATIONS};
+pub use self::sqlite::SqliteStorage;
+pub use self::state::{StorageExt, Upgrade, Versioned};
+
+pub const DB_FILE: &str = "data.db";
+
+/// Telegram keeps undelivered updates for a day, so ids seen longer ago
+/// than this will not come again
+const UPDATE_RETENTION_SECS: i64 = 2 * 24 * 3600;
+
+#[derive(Fail, Debug)]
+pub enum DbError {
+  #[fail(display = "Cannot open database: {}", _0)]
+  Connection(#[cause] diesel::result::ConnectionError),
+
+  #[fail(display = "Database error: {}", _0)]
+  Query(#[cause] diesel::result::Error),
+
+  #[fail(display = "Invalid stored value: {}", _0)]
+  Value(#[cause] serde_json::Error),
+
+  #[fail(
+    display = "Database schema version {} is newer than supported ({})",
+    version, latest
+  )]
+  SchemaTooNew { version: i32, latest: i32 },
+
+  #[fail(display = "{} already exists", _0)]
+  Exists(String),
+
+  #[fail(
+    display = "Unreadable {}, moved to backup {}: {}",
+    key, backup, reason
+  )]
+  State {
+    key: String,
+    backup: i32,
+    reason: String,
+  },
+}
+
+pub type DbResult<T> = std::result::Result<T, DbError>;
+
+impl From<diesel::result::ConnectionError> for DbError {
+  fn from(e: diesel::ConnectionError) -> DbError {
+    DbError::Connection(e)
+  }
+}
+
+impl From<diesel::result::Error> for DbError {
+  fn from(e: diesel::result::Error) -> DbError {
+    DbError::Query(e)
+  }
+}
+
+impl From<serde_json::Error> for DbError {
+  fn from(e: serde_json::Error) -> DbError {
+    DbError::Value(e)
+  }
 }
 
 pub mod schema {
+  table! {
+      config (id) {
+          id -> Nullable<Integer>,
+          key -> Text,
+          value -> Text,
+          version -> Integer,
+      }
+  }
+
   table! {
       messages (id) {
           id -> Nullable<Integer>,
@@ -30,10 +95,109 @@ pub mod schema {
           created_at -> Nullable<BigInt>,
       }
   }
+
+  table! {
+      jobs (id) {
+          id -> Nullable<Integer>,
+          ext -> Text,
+          key -> Nullable<Text>,
+          schedule -> Text,
+          payload -> Text,
+          next_run -> BigInt,
+      }
+  }
+
+  table! {
+      audit (id) {
+          id -> Nullable<Integer>,
+          kind -> Text,
+          user_id -> Nullable<BigInt>,
+          user_name -> Nullable<Text>,
+          chat_id -> Nullable<BigInt>,
+          detail -> Text,
+          created_at -> BigInt,
+      }
+  }
+
+  table! {
+      updates (update_id) {
+          update_id -> BigInt,
+          received_at -> BigInt,
+      }
+  }
+
+  table! {
+      reminders (id) {
+          id -> Nullable<Integer>,
+          remind_at -> BigInt,
+          set_at -> BigInt,
+          content -> Text,
+          chat_id -> BigInt,
+          message_id -> BigInt,
+          job_id -> Nullable<Integer>,
+      }
+  }
+
+  table! {
+      weather_locations (city) {
+          city -> Text,
+          long_lat -> Text,
+      }
+  }
+
+  table! {
+      yeelight_devices (id) {
+          id -> Nullable<Integer>,
+          addr -> Text,
+      }
+  }
+
+  table! {
+      yeelight_modes (id) {
+          id -> Nullable<Integer>,
+          name -> Text,
+          request -> Text,
+          version -> Integer,
+      }
+  }
+
+  table! {
+      search_chats (chat_id) {
+          chat_id -> BigInt,
+      }
+  }
+
+  table! {
+      search_users (user_id) {
+          user_id -> BigInt,
+      }
+  }
+
+  table! {
+      state_backups (id) {
+          id -> Nullable<Integer>,
+          key -> Text,
+          version -> Integer,
+          value -> Text,
+          error -> Text,
+          created_at -> BigInt,
+      }
+  }
+
+  table! {
+      schema_migrations (version) {
+          version -> Integer,
+          name -> Text,
+          applied_at -> BigInt,
+      }
+  }
 }
 
 use self::schema::*;
 
+/// Escapes `LIKE` patterns in `search_msg`
+const LIKE_ESCAPE: char = '\\';
+
 #[derive(Insertable, Queryable, Serialize, Deserialize, Clone, Debug)]
 #[table_name = "messages"]
 pub struct DbMessage {
@@ -49,128 +213,322 @@ pub struct DbMessage {
   pub created_at: Option<i64>,
 }
 
-pub fn quote_str(s: &str) -> String {
-  s.replace("'", "''")
+#[derive(Insertable, Queryable, Clone, Debug)]
+#[table_name = "jobs"]
+pub struct DbJob {
+  pub id: Option<i32>,
+  pub ext: String,
+  pub key: Option<String>,
+  pub schedule: String,
+  pub payload: String,
+  pub next_run: i64,
+}
+
+#[derive(Insertable, Queryable, Clone, Debug)]
+#[table_name = "audit"]
+pub struct DbAudit {
+  pub id: Option<i32>,
+  pub kind: String,
+  pub user_id: Option<i64>,
+  pub user_name: Option<String>,
+  pub chat_id: Option<i64>,
+  pub detail: String,
+  pub created_at: i64,
+}
+
+#[derive(Insertable, Queryable, Clone, Debug)]
+#[table_name = "reminders"]
+pub struct DbReminder {
+  pub id: Option<i32>,
+  pub remind_at: i64,
+  pub set_at: i64,
+  pub content: String,
+  pub chat_id: i64,
+  pub message_id: i64,
+  pub job_id: Option<i32>,
+}
+
+/// Matches `s` literally in a pattern given to `search_msg`
+pub fn escape_like(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    if c == LIKE_ESCAPE || c == '%' || c == '_' {
+      escaped.push(LIKE_ESCAPE);
+    }
+    escaped.push(c);
+  }
+  escaped
 }
 
 pub const SEARCH_PER: usize = 10;
 
-impl Db {
-  pub fn init() -> Self {
-    // check file
-    let conn = SqliteConnection::establish(DB_FILE).unwrap();
-    let db = Db { conn };
-    db.init_table_config();
-    db.init_table_messages();
-    db
-  }
-
-  pub fn init_table_config(&self) {
-    self.execute_sql(
-      "CREATE TABLE IF NOT EXISTS config (
-                id INTEGER PRIMARY KEY ASC,
-                key TEXT UNIQUE,
-                value TEXT
-             );",
-    );
-  }
-
-  pub fn init_table_messages(&self) {
-    self.execute_sql(
-      "CREATE TABLE IF NOT EXISTS messages (
-                id INTEGER PRIMARY KEY ASC,
-                msg_id BIGINT NOT NULL,
-                user_id BIGINT NOT NULL,
-                user_name TEXT NOT NULL,
-                chat_id BIGINT NOT NULL,
-                chat_name TEXT NOT NULL,
-                is_group INT NOT NULL,
-                reply_to_msg_id BIGINT,
-                text TEXT,
-                created_at BIGINT,
-                UNIQUE(msg_id, chat_id) ON CONFLICT IGNORE
-             );",
-    );
-  }
-
-  pub fn save_conf<T>(&self, key: &str, value: T)
-  where
-    T: Serialize,
-  {
-    let value_str = serde_json::to_string_pretty(&value).unwrap();
-    self.execute_sql(&format!(
-      "INSERT OR REPLACE INTO config (id, key, value)
-      VALUES (
-          (SELECT id FROM config WHERE key = '{key}'),
-          '{key}',
-          '{val}'
-      )",
-      key=quote_str(&key),
-      val=quote_str(&value_str)
-    ));
-  }
-
-  pub fn load_conf<T>(&self, key: &str) -> Option<T>
-  where
-    T: DeserializeOwned,
-  {
-    sql::<Text>(&format!(
-      "SELECT value FROM config WHERE key = '{}'",
-      quote_str(key)
-    )).get_result::<String>(&self.conn)
-      .ok()
-      .and_then(|val_str| serde_json::from_str(&val_str).ok())
-  }
-
-  #[allow(dead_code)]
-  pub fn list_conf(&self) -> Vec<(String, String)> {
-    sql::<(Text, Text)>("SELECT key, value FROM config")
-      .get_results(&self.conn)
-      .unwrap_or_default()
-  }
-
-  pub fn save_msg(&self, msg: &DbMessage) {
-    diesel::insert(msg)
-      .into(messages::table)
-      .execute(&self.conn)
-      .ok();
-  }
-
-  pub fn search_msg(
+/// Where the bot keeps its state. Object safe, see `StorageExt` for the
+/// generic helpers on top of it.
+pub trait Storage {
+  /// The saved JSON of a config item and its version
+  fn conf(&self, key: &str) -> DbResult<Option<(String, i32)>>;
+  /// The saved JSON as is
+  fn raw_conf(&self, key: &str) -> DbResult<Option<String>>;
+  fn store_conf(&self, key: &str, version: i32, value: &str) -> DbResult<()>;
+  fn list_conf(&self) -> DbResult<Vec<(String, String)>>;
+  /// False if there was no such key
+  fn delete_conf(&self, key: &str) -> DbResult<bool>;
+  /// Move an unreadable config item out of the way, returns the id of
+  /// the backup
+  fn back_up_conf(
+    &self,
+    key: &str,
+    version: i32,
+    value: &str,
+    error: &str,
+  ) -> DbResult<i32>;
+
+  fn save_msg(&self, msg: &DbMessage) -> DbResult<()>;
+  fn update_msg_text(
+    &self,
+    chat_id: i64,
+    msg_id: i64,
+    text: &str,
+  ) -> DbResult<()>;
+  /// Messages of `users` containing `pattern`, a `LIKE` pattern whose
+  /// literal parts are escaped with `escape_like`, latest first
+  fn search_msg(
     &self,
     page: usize,
     pattern: &str,
     users: &[i64],
-  ) -> (usize, Vec<DbMessage>) {
-    if pattern.is_empty() {
-      return Default::default();
+  ) -> DbResult<(usize, Vec<DbMessage>)>;
+
+  /// Returns the id of the inserted job, replacing the one with the same
+  /// `ext` and `key`
+  fn insert_job(&self, job: &DbJob) -> DbResult<i32>;
+  fn due_jobs(&self, now: i64) -> DbResult<Vec<DbJob>>;
+  fn ext_jobs(&self, ext: &str) -> DbResult<Vec<DbJob>>;
+  fn reschedule_job(&self, id: i32, next_run: i64) -> DbResult<()>;
+  /// False if there was no such job
+  fn delete_job(&self, id: i32) -> DbResult<bool>;
+
+  fn insert_audit(&self, entry: &DbAudit) -> DbResult<()>;
+  /// Latest audit entries first
+  fn audit_entries(
+    &self,
+    kind: Option<&str>,
+    user_id: Option<i64>,
+    chat_id: Option<i64>,
+    limit: usize,
+  ) -> DbResult<Vec<DbAudit>>;
+
+  /// Remember an update as processed, false if it was already
+  fn mark_update(&self, update_id: i64, now: i64) -> DbResult<bool>;
+
+  /// Returns the id of the inserted reminder
+  fn insert_reminder(&self, reminder: &DbReminder) -> DbResult<i32>;
+  /// In the order they were set
+  fn reminders(&self) -> DbResult<Vec<DbReminder>>;
+  fn set_reminder_job(&self, id: i32, job_id: i32) -> DbResult<()>;
+  /// False if there was no such reminder
+  fn delete_reminder(&self, id: i32) -> DbResult<bool>;
+
+  /// Replaces the location of the same name
+  fn save_location(&self, city: &str, long_lat: &str) -> DbResult<()>;
+  /// Pairs of city and `long,lat`, by city
+  fn locations(&self) -> DbResult<Vec<(String, String)>>;
+  /// False if there was no such location
+  fn delete_location(&self, city: &str) -> DbResult<bool>;
+
+  /// Addresses of the bulbs, oldest first
+  fn yeelight_devices(&self) -> DbResult<Vec<String>>;
+  /// Does nothing for an address already added
+  fn add_yeelight_device(&self, addr: &str) -> DbResult<()>;
+  /// False if there was no such device
+  fn delete_yeelight_device(&self, addr: &str) -> DbResult<bool>;
+  /// Name, version and JSON encoded request of each mode, in the order
+  /// they were added
+  fn yeelight_modes(&self) -> DbResult<Vec<(String, i32, String)>>;
+  /// `DbError::Exists` for a name already taken
+  fn insert_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+  ) -> DbResult<()>;
+  /// False if there was no such mode
+  fn delete_yeelight_mode(&self, name: &str) -> DbResult<bool>;
+  /// Move an unreadable mode out of the way, returns the id of the backup
+  fn back_up_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+    error: &str,
+  ) -> DbResult<i32>;
+
+  /// Chats whose messages are saved for searching, by id
+  fn search_chats(&self) -> DbResult<Vec<i64>>;
+  /// Does nothing for a chat already added
+  fn add_search_chat(&self, chat_id: i64) -> DbResult<()>;
+  /// Users whose messages show up in searches, by id
+  fn search_users(&self) -> DbResult<Vec<i64>>;
+  /// Does nothing for a user already added
+  fn add_search_user(&self, user_id: i64) -> DbResult<()>;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_escape_like() {
+    assert_eq!(escape_like("100%_a\\b"), "100\\%\\_a\\\\b");
+    assert_eq!(escape_like("'); --"), "'); --");
+  }
+
+  fn message(msg_id: i64, user_id: i64, text: &str) -> DbMessage {
+    DbMessage {
+      id: None,
+      msg_id,
+      user_id,
+      user_name: None,
+      chat_id: -200,
+      chat_name: None,
+      is_group: true,
+      reply_to_msg_id: None,
+      text: Some(text.into()),
+      created_at: Some(msg_id),
+    }
+  }
+
+  fn job(ext: &str, key: Option<&str>, next_run: i64) -> DbJob {
+    DbJob {
+      id: None,
+      ext: ext.into(),
+      key: key.map(Into::into),
+      schedule: "{}".into(),
+      payload: "null".into(),
+      next_run,
     }
-    let msg_filter_sql = format!("lower(text) LIKE lower('%{}%')", pattern);
-    let user_filter_sql = users
-      .iter()
-      .chain(std::iter::once(&-1))
-      .map(|i| format!("user_id = {}", i))
-      .collect::<Vec<String>>()
-      .join(" OR ");
-    let query = messages::table
-      .filter(sql(&msg_filter_sql))
-      .filter(sql(&format!("({})", user_filter_sql)))
-      .order(messages::created_at.desc());
-    let count: i64 = query
-      .clone()
-      .count()
-      .get_result(&self.conn)
-      .unwrap_or_default();
-    print_sql! {query.clone()};
-    let result = query
-      .offset(((page - 1) * SEARCH_PER) as i64)
-      .limit(SEARCH_PER as i64)
-      .load(&self.conn)
-      .unwrap_or_default();
-    (count as usize, result)
-  }
-
-  pub fn execute_sql(&self, s: &str) -> bool {
-    sql::<Bool>(s).execute(&self.conn).is_ok()
+  }
+
+  fn audit(kind: &str, user_id: i64) -> DbAudit {
+    DbAudit {
+      id: None,
+      kind: kind.into(),
+      user_id: Some(user_id),
+      user_name: None,
+      chat_id: None,
+      detail: String::new(),
+      created_at: 0,
+    }
+  }
+
+  /// Calls every backend must agree on, constraints included
+  fn check_storage(db: &Storage) {
+    db.store_conf("b", 0, "1").unwrap();
+    db.store_conf("a", 1, "2").unwrap();
+    db.store_conf("b", 2, "3").unwrap();
+    assert_eq!(db.conf("b").unwrap(), Some(("3".into(), 2)));
+    let keys: Vec<_> =
+      db.list_conf().unwrap().into_iter().map(|c| c.0).collect();
+    assert_eq!(keys, vec!["a", "b"]);
+    db.back_up_conf("a", 1, "2", "broken").unwrap();
+    assert_eq!(db.raw_conf("a").unwrap(), None);
+    assert!(db.delete_conf("b").unwrap());
+    assert!(!db.delete_conf("b").unwrap());
+
+    db.save_msg(&message(1, 7, "Snake_case")).unwrap();
+    db.save_msg(&message(1, 7, "ignored, same message"))
+      .unwrap();
+    db.save_msg(&message(2, 7, "snakecase")).unwrap();
+    db.save_msg(&message(3, 8, "snake_case")).unwrap();
+    db.update_msg_text(-200, 2, "snake case").unwrap();
+    let (count, found) = db.search_msg(1, &escape_like("e_c"), &[7]).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(found[0].text, Some("Snake_case".into()));
+    let (count, found) = db.search_msg(1, "snake", &[7, 8]).unwrap();
+    assert_eq!(count, 3);
+    let ids: Vec<_> = found.iter().map(|m| m.msg_id).collect();
+    assert_eq!(ids, vec![3, 2, 1]);
+
+    let first = db
+      .insert_job(&job("yeelight", Some("refresh"), 20))
+      .unwrap();
+    let second = db
+      .insert_job(&job("yeelight", Some("refresh"), 30))
+      .unwrap();
+    let tea = db.insert_job(&job("reminder", None, 10)).unwrap();
+    db.insert_job(&job("reminder", None, 40)).unwrap();
+    let due: Vec<_> = db.due_jobs(30).unwrap().iter().map(|j| j.id).collect();
+    assert_eq!(due, vec![Some(tea), Some(second)]);
+    db.reschedule_job(tea, 50).unwrap();
+    assert_eq!(db.ext_jobs("reminder").unwrap()[1].id, Some(tea));
+    assert!(db.delete_job(second).unwrap());
+    assert!(!db.delete_job(first).unwrap());
+
+    db.insert_audit(&audit("denied", 1)).unwrap();
+    db.insert_audit(&audit("unsafe", 2)).unwrap();
+    db.insert_audit(&audit("denied", 2)).unwrap();
+    let users = |kind: Option<&str>, user_id: Option<i64>, limit: usize| {
+      let entries = db.audit_entries(kind, user_id, None, limit).unwrap();
+      entries.iter().map(|e| e.user_id).collect::<Vec<_>>()
+    };
+    assert_eq!(users(Some("denied"), None, 10), vec![Some(2), Some(1)]);
+    assert_eq!(users(None, Some(2), 1), vec![Some(2)]);
+
+    assert!(db.mark_update(1, 0).unwrap());
+    assert!(!db.mark_update(1, 10).unwrap());
+    assert!(db.mark_update(1, UPDATE_RETENTION_SECS + 1).unwrap());
+
+    let reminder = DbReminder {
+      id: None,
+      remind_at: 1,
+      set_at: 0,
+      content: "tea".into(),
+      chat_id: 1,
+      message_id: 1,
+      job_id: None,
+    };
+    let id = db.insert_reminder(&reminder).unwrap();
+    db.set_reminder_job(id, tea).unwrap();
+    assert_eq!(db.reminders().unwrap()[0].job_id, Some(tea));
+    assert!(db.delete_reminder(id).unwrap());
+    assert!(db.reminders().unwrap().is_empty());
+
+    db.save_location("Tokyo", "1,1").unwrap();
+    db.save_location("Berlin", "2,2").unwrap();
+    db.save_location("Tokyo", "3,3").unwrap();
+    let loc = |city: &str, lat: &str| (city.to_string(), lat.to_string());
+    let locations = vec![loc("Berlin", "2,2"), loc("Tokyo", "3,3")];
+    assert_eq!(db.locations().unwrap(), locations);
+    assert!(db.delete_location("Tokyo").unwrap());
+    assert!(!db.delete_location("Tokyo").unwrap());
+    assert_eq!(db.locations().unwrap(), vec![loc("Berlin", "2,2")]);
+
+    db.add_yeelight_device("b").unwrap();
+    db.add_yeelight_device("a").unwrap();
+    db.add_yeelight_device("b").unwrap();
+    assert_eq!(db.yeelight_devices().unwrap(), vec!["b", "a"]);
+    assert!(db.delete_yeelight_device("b").unwrap());
+    assert!(!db.delete_yeelight_device("b").unwrap());
+    assert_eq!(db.yeelight_devices().unwrap(), vec!["a"]);
+    db.insert_yeelight_mode("Night", 0, "[]").unwrap();
+    let error = db.insert_yeelight_mode("Night", 1, "{}").unwrap_err();
+    assert_eq!(error.to_string(), "Yeelight mode Night already exists");
+    db.back_up_yeelight_mode("Night", 0, "[]", "broken")
+      .unwrap();
+    assert!(db.yeelight_modes().unwrap().is_empty());
+    assert!(!db.delete_yeelight_mode("Night").unwrap());
+
+    for &id in &[3, 1, 3] {
+      db.add_search_chat(id).unwrap();
+      db.add_search_user(id).unwrap();
+    }
+    assert_eq!(db.search_chats().unwrap(), vec![1, 3]);
+    assert_eq!(db.search_users().unwrap(), vec![1, 3]);
+  }
+
+  #[test]
+  fn test_backends_agree() {
+    check_storage(&SqliteStorage::in_memory());
+    check_storage(&MemoryStorage::default());
   }
 }
@@ -0,0 +1,438 @@
+use super::*;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Storage kept in memory, for tests and runs that need not persist.
+/// Mirrors the constraints of the SQLite schema.
+#[derive(Default)]
+pub struct MemoryStorage {
+  tables: RefCell<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+  /// The value and version by key
+  config: BTreeMap<String, (String, i32)>,
+  /// Key, version, value and error of each backup
+  backups: Vec<(String, i32, String, String)>,
+  messages: Vec<DbMessage>,
+  jobs: Vec<DbJob>,
+  audit: Vec<DbAudit>,
+  /// When each update was received
+  updates: HashMap<i64, i64>,
+  reminders: Vec<DbReminder>,
+  locations: BTreeMap<String, String>,
+  yeelight_devices: Vec<String>,
+  /// Name, version and request of each mode
+  yeelight_modes: Vec<(String, i32, String)>,
+  search_chats: BTreeSet<i64>,
+  search_users: BTreeSet<i64>,
+  /// The last id given to a row of any table
+  last_id: i32,
+}
+
+impl Tables {
+  fn next_id(&mut self) -> i32 {
+    self.last_id += 1;
+    self.last_id
+  }
+}
+
+/// Remove the rows matching `f`, false if there was none
+fn remove_where<T, F: Fn(&T) -> bool>(rows: &mut Vec<T>, f: F) -> bool {
+  let len = rows.len();
+  rows.retain(|row| !f(row));
+  rows.len() < len
+}
+
+/// A character of a `LIKE` pattern
+enum PatternChar {
+  /// `%`, any number of characters
+  Any,
+  /// `_`, a single character
+  One,
+  Literal(char),
+}
+
+/// `text LIKE pattern ESCAPE '\'`, ignoring ASCII case as SQLite does.
+/// Backtracks only to the last `%`, so it takes at most the product of
+/// the lengths.
+fn like(pattern: &str, text: &str) -> bool {
+  use self::PatternChar::*;
+
+  let mut chars = pattern.chars();
+  let mut pattern = vec![];
+  while let Some(c) = chars.next() {
+    pattern.push(match c {
+      '%' => Any,
+      '_' => One,
+      LIKE_ESCAPE => Literal(chars.next().unwrap_or(LIKE_ESCAPE)),
+      c => Literal(c),
+    });
+  }
+  let text: Vec<char> = text.chars().collect();
+
+  let (mut p, mut t) = (0, 0);
+  // the last `%` and where in the text its match ends so far
+  let mut any: Option<(usize, usize)> = None;
+  while t < text.len() {
+    match pattern.get(p) {
+      Some(&Any) => {
+        any = Some((p, t));
+        p += 1;
+        continue;
+      }
+      Some(&One) => {
+        p += 1;
+        t += 1;
+        continue;
+      }
+      Some(&Literal(c)) if c.eq_ignore_ascii_case(&text[t]) => {
+        p += 1;
+        t += 1;
+        continue;
+      }
+      _ => {}
+    }
+    match any {
+      // let the `%` take one more character
+      Some((any_p, any_t)) => {
+        any = Some((any_p, any_t + 1));
+        p = any_p + 1;
+        t = any_t + 1;
+      }
+      None => return false,
+    }
+  }
+  pattern[p..].iter().all(|c| match *c {
+    Any => true,
+    _ => false,
+  })
+}
+
+impl Storage for MemoryStorage {
+  fn conf(&self, key: &str) -> DbResult<Option<(String, i32)>> {
+    Ok(self.tables.borrow().config.get(key).cloned())
+  }
+
+  fn raw_conf(&self, key: &str) -> DbResult<Option<String>> {
+    Ok(self.conf(key)?.map(|(value, _)| value))
+  }
+
+  fn store_conf(&self, key: &str, version: i32, value: &str) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    tables.config.insert(key.into(), (value.into(), version));
+    Ok(())
+  }
+
+  fn list_conf(&self) -> DbResult<Vec<(String, String)>> {
+    let tables = self.tables.borrow();
+    let confs = tables
+      .config
+      .iter()
+      .map(|(key, (value, _))| (key.clone(), value.clone()));
+    Ok(confs.collect())
+  }
+
+  fn delete_conf(&self, key: &str) -> DbResult<bool> {
+    Ok(self.tables.borrow_mut().config.remove(key).is_some())
+  }
+
+  fn back_up_conf(
+    &self,
+    key: &str,
+    version: i32,
+    value: &str,
+    error: &str,
+  ) -> DbResult<i32> {
+    let mut tables = self.tables.borrow_mut();
+    let backup = (key.into(), version, value.into(), error.into());
+    tables.backups.push(backup);
+    tables.config.remove(key);
+    Ok(tables.next_id())
+  }
+
+  fn save_msg(&self, msg: &DbMessage) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    let exists = tables
+      .messages
+      .iter()
+      .any(|m| m.msg_id == msg.msg_id && m.chat_id == msg.chat_id);
+    if !exists {
+      let id = Some(tables.next_id());
+      tables.messages.push(DbMessage { id, ..msg.clone() });
+    }
+    Ok(())
+  }
+
+  fn update_msg_text(
+    &self,
+    chat_id: i64,
+    msg_id: i64,
+    text: &str,
+  ) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    let msgs = tables.messages.iter_mut();
+    for msg in msgs.filter(|m| m.chat_id == chat_id && m.msg_id == msg_id) {
+      msg.text = Some(text.into());
+    }
+    Ok(())
+  }
+
+  fn search_msg(
+    &self,
+    page: usize,
+    pattern: &str,
+    users: &[i64],
+  ) -> DbResult<(usize, Vec<DbMessage>)> {
+    if pattern.is_empty() || users.is_empty() {
+      return Ok(Default::default());
+    }
+    let pattern = format!("%{}%", pattern);
+    let tables = self.tables.borrow();
+    let mut found: Vec<_> = tables
+      .messages
+      .iter()
+      .filter(|m| users.contains(&m.user_id))
+      .filter(|m| m.text.as_ref().map_or(false, |t| like(&pattern, t)))
+      .cloned()
+      .collect();
+    found.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    let count = found.len();
+    let result = found
+      .into_iter()
+      .skip((page - 1) * SEARCH_PER)
+      .take(SEARCH_PER)
+      .collect();
+    Ok((count, result))
+  }
+
+  fn insert_job(&self, job: &DbJob) -> DbResult<i32> {
+    let mut tables = self.tables.borrow_mut();
+    if job.key.is_some() {
+      remove_where(&mut tables.jobs, |j| j.ext == job.ext && j.key == job.key);
+    }
+    let id = tables.next_id();
+    tables.jobs.push(DbJob {
+      id: Some(id),
+      ..job.clone()
+    });
+    Ok(id)
+  }
+
+  fn due_jobs(&self, now: i64) -> DbResult<Vec<DbJob>> {
+    let tables = self.tables.borrow();
+    let due = tables.jobs.iter().filter(|j| j.next_run <= now);
+    let mut due: Vec<_> = due.cloned().collect();
+    due.sort_by_key(|j| j.next_run);
+    Ok(due)
+  }
+
+  fn ext_jobs(&self, ext: &str) -> DbResult<Vec<DbJob>> {
+    let tables = self.tables.borrow();
+    let jobs = tables.jobs.iter().filter(|j| j.ext == ext);
+    let mut jobs: Vec<_> = jobs.cloned().collect();
+    jobs.sort_by_key(|j| j.next_run);
+    Ok(jobs)
+  }
+
+  fn reschedule_job(&self, id: i32, next_run: i64) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    for job in tables.jobs.iter_mut().filter(|j| j.id == Some(id)) {
+      job.next_run = next_run;
+    }
+    Ok(())
+  }
+
+  fn delete_job(&self, id: i32) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    Ok(remove_where(&mut tables.jobs, |j| j.id == Some(id)))
+  }
+
+  fn insert_audit(&self, entry: &DbAudit) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    let id = Some(tables.next_id());
+    tables.audit.push(DbAudit {
+      id,
+      ..entry.clone()
+    });
+    Ok(())
+  }
+
+  fn audit_entries(
+    &self,
+    kind: Option<&str>,
+    user_id: Option<i64>,
+    chat_id: Option<i64>,
+    limit: usize,
+  ) -> DbResult<Vec<DbAudit>> {
+    let tables = self.tables.borrow();
+    let entries = tables
+      .audit
+      .iter()
+      .rev()
+      .filter(|e| kind.map_or(true, |kind| e.kind == kind))
+      .filter(|e| user_id.map_or(true, |id| e.user_id == Some(id)))
+      .filter(|e| chat_id.map_or(true, |id| e.chat_id == Some(id)))
+      .take(limit);
+    Ok(entries.cloned().collect())
+  }
+
+  fn mark_update(&self, update_id: i64, now: i64) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    let expired = now - UPDATE_RETENTION_SECS;
+    tables
+      .updates
+      .retain(|_, received_at| *received_at >= expired);
+    if tables.updates.contains_key(&update_id) {
+      return Ok(false);
+    }
+    tables.updates.insert(update_id, now);
+    Ok(true)
+  }
+
+  fn insert_reminder(&self, reminder: &DbReminder) -> DbResult<i32> {
+    let mut tables = self.tables.borrow_mut();
+    let id = tables.next_id();
+    tables.reminders.push(DbReminder {
+      id: Some(id),
+      ..reminder.clone()
+    });
+    Ok(id)
+  }
+
+  fn reminders(&self) -> DbResult<Vec<DbReminder>> {
+    Ok(self.tables.borrow().reminders.clone())
+  }
+
+  fn set_reminder_job(&self, id: i32, job_id: i32) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    for rem in tables.reminders.iter_mut().filter(|r| r.id == Some(id)) {
+      rem.job_id = Some(job_id);
+    }
+    Ok(())
+  }
+
+  fn delete_reminder(&self, id: i32) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    Ok(remove_where(&mut tables.reminders, |r| r.id == Some(id)))
+  }
+
+  fn save_location(&self, city: &str, long_lat: &str) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    tables.locations.insert(city.into(), long_lat.into());
+    Ok(())
+  }
+
+  fn locations(&self) -> DbResult<Vec<(String, String)>> {
+    let tables = self.tables.borrow();
+    let locations = tables.locations.iter();
+    Ok(locations.map(|(c, l)| (c.clone(), l.clone())).collect())
+  }
+
+  fn delete_location(&self, city: &str) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    Ok(tables.locations.remove(city).is_some())
+  }
+
+  fn yeelight_devices(&self) -> DbResult<Vec<String>> {
+    Ok(self.tables.borrow().yeelight_devices.clone())
+  }
+
+  fn add_yeelight_device(&self, addr: &str) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    if !tables.yeelight_devices.iter().any(|a| a == addr) {
+      tables.yeelight_devices.push(addr.into());
+    }
+    Ok(())
+  }
+
+  fn delete_yeelight_device(&self, addr: &str) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    Ok(remove_where(&mut tables.yeelight_devices, |a| a == addr))
+  }
+
+  fn yeelight_modes(&self) -> DbResult<Vec<(String, i32, String)>> {
+    Ok(self.tables.borrow().yeelight_modes.clone())
+  }
+
+  fn insert_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+  ) -> DbResult<()> {
+    let mut tables = self.tables.borrow_mut();
+    if tables.yeelight_modes.iter().any(|(n, _, _)| n == name) {
+      return Err(DbError::Exists(format!("Yeelight mode {}", name)));
+    }
+    let mode = (name.into(), version, request.into());
+    tables.yeelight_modes.push(mode);
+    Ok(())
+  }
+
+  fn delete_yeelight_mode(&self, name: &str) -> DbResult<bool> {
+    let mut tables = self.tables.borrow_mut();
+    Ok(remove_where(&mut tables.yeelight_modes, |(n, _, _)| {
+      n == name
+    }))
+  }
+
+  fn back_up_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+    error: &str,
+  ) -> DbResult<i32> {
+    let mut tables = self.tables.borrow_mut();
+    let key = format!("yeelight_modes.{}", name);
+    tables
+      .backups
+      .push((key, version, request.into(), error.into()));
+    remove_where(&mut tables.yeelight_modes, |(n, _, _)| n == name);
+    Ok(tables.next_id())
+  }
+
+  fn search_chats(&self) -> DbResult<Vec<i64>> {
+    let tables = self.tables.borrow();
+    Ok(tables.search_chats.iter().cloned().collect())
+  }
+
+  fn add_search_chat(&self, chat_id: i64) -> DbResult<()> {
+    self.tables.borrow_mut().search_chats.insert(chat_id);
+    Ok(())
+  }
+
+  fn search_users(&self) -> DbResult<Vec<i64>> {
+    let tables = self.tables.borrow();
+    Ok(tables.search_users.iter().cloned().collect())
+  }
+
+  fn add_search_user(&self, user_id: i64) -> DbResult<()> {
+    self.tables.borrow_mut().search_users.insert(user_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_like() {
+    assert!(like("%note 1%", "A NOTE 12"));
+    assert!(like("a_c", "abc"));
+    assert!(!like("a_c", "ac"));
+    assert!(like(&format!("%{}%", escape_like("100%")), "100% sure"));
+    assert!(!like(&escape_like("100%"), "1000"));
+    assert!(like("a\\", "A\\"));
+    assert!(like("%%b", "ab"));
+
+    // would take forever with backtracking on every `%`
+    let text = "a".repeat(5000);
+    assert!(!like("%a%a%a%a%a%a%a%a%b", &text));
+    assert!(like("%a%a%a%a%a%a%a%a%", &text));
+  }
+}
@@ -0,0 +1,546 @@
+use super::schema::{schema_migrations, yeelight_modes};
+use super::{DbError, DbReminder, DbResult, SqliteStorage, Storage};
+
+use chrono::{DateTime, FixedOffset};
+use diesel;
+use diesel::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::collections::HashMap;
+
+/// A step in the evolution of the schema, applied once in a transaction
+pub struct Migration {
+  pub version: i32,
+  pub name: &'static str,
+  sql: &'static str,
+  /// Moves existing data into the new schema, after `sql`. Returns the
+  /// data it could not read and moved to `state_backups`, as
+  /// `DbError::State`.
+  import: Option<fn(&SqliteStorage) -> DbResult<Vec<DbError>>>,
+}
+
+/// What `SqliteStorage::migrate` did
+#[derive(Default)]
+pub struct Migrated {
+  pub applied: Vec<&'static Migration>,
+  /// Unreadable data moved out of the way, for the owners to look at
+  pub backed_up: Vec<DbError>,
+}
+
+/// Every migration, in the order they apply. Append to change the schema,
+/// never edit one already released.
+pub const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    name: "initial tables",
+    // as created before migrations existed, hence IF NOT EXISTS
+    sql: "
+      CREATE TABLE IF NOT EXISTS config (
+        id INTEGER PRIMARY KEY ASC,
+        key TEXT UNIQUE,
+        value TEXT
+      );
+      CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY ASC,
+        msg_id BIGINT NOT NULL,
+        user_id BIGINT NOT NULL,
+        user_name TEXT NOT NULL,
+        chat_id BIGINT NOT NULL,
+        chat_name TEXT NOT NULL,
+        is_group INT NOT NULL,
+        reply_to_msg_id BIGINT,
+        text TEXT,
+        created_at BIGINT,
+        UNIQUE(msg_id, chat_id) ON CONFLICT IGNORE
+      );
+      CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY ASC,
+        ext TEXT NOT NULL,
+        key TEXT,
+        schedule TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        next_run BIGINT NOT NULL,
+        UNIQUE(ext, key) ON CONFLICT REPLACE
+      );
+      CREATE TABLE IF NOT EXISTS audit (
+        id INTEGER PRIMARY KEY ASC,
+        kind TEXT NOT NULL,
+        user_id BIGINT,
+        user_name TEXT,
+        chat_id BIGINT,
+        detail TEXT NOT NULL,
+        created_at BIGINT NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS updates (
+        update_id BIGINT PRIMARY KEY,
+        received_at BIGINT NOT NULL
+      );
+    ",
+    import: None,
+  },
+  Migration {
+    version: 2,
+    name: "match the nullability of the diesel schema",
+    sql: "
+      CREATE TABLE config_new (
+        id INTEGER PRIMARY KEY ASC,
+        key TEXT NOT NULL UNIQUE,
+        value TEXT NOT NULL
+      );
+      INSERT INTO config_new (id, key, value)
+        SELECT id, key, value FROM config
+        WHERE key IS NOT NULL AND value IS NOT NULL;
+      DROP TABLE config;
+      ALTER TABLE config_new RENAME TO config;
+
+      CREATE TABLE messages_new (
+        id INTEGER PRIMARY KEY ASC,
+        msg_id BIGINT NOT NULL,
+        user_id BIGINT NOT NULL,
+        user_name TEXT,
+        chat_id BIGINT NOT NULL,
+        chat_name TEXT,
+        is_group BOOLEAN NOT NULL,
+        reply_to_msg_id BIGINT,
+        text TEXT,
+        created_at BIGINT,
+        UNIQUE(msg_id, chat_id) ON CONFLICT IGNORE
+      );
+      INSERT INTO messages_new
+        SELECT id, msg_id, user_id, user_name, chat_id, chat_name,
+               is_group, reply_to_msg_id, text, created_at
+        FROM messages;
+      DROP TABLE messages;
+      ALTER TABLE messages_new RENAME TO messages;
+    ",
+    import: None,
+  },
+  Migration {
+    version: 3,
+    name: "tables for extension state",
+    sql: "
+      CREATE TABLE reminders (
+        id INTEGER PRIMARY KEY ASC,
+        remind_at BIGINT NOT NULL,
+        set_at BIGINT NOT NULL,
+        content TEXT NOT NULL,
+        chat_id BIGINT NOT NULL,
+        message_id BIGINT NOT NULL,
+        job_id INTEGER
+      );
+      CREATE TABLE weather_locations (
+        city TEXT PRIMARY KEY ON CONFLICT REPLACE,
+        long_lat TEXT NOT NULL
+      );
+      CREATE TABLE yeelight_devices (
+        id INTEGER PRIMARY KEY ASC,
+        addr TEXT NOT NULL UNIQUE ON CONFLICT IGNORE
+      );
+      CREATE TABLE yeelight_modes (
+        id INTEGER PRIMARY KEY ASC,
+        name TEXT NOT NULL UNIQUE,
+        request TEXT NOT NULL
+      );
+      CREATE TABLE search_chats (
+        chat_id BIGINT PRIMARY KEY ON CONFLICT IGNORE
+      );
+      CREATE TABLE search_users (
+        user_id BIGINT PRIMARY KEY ON CONFLICT IGNORE
+      );
+    ",
+    import: Some(import_config_blobs),
+  },
+  Migration {
+    version: 4,
+    name: "versioned config items",
+    // existing items are version 0, as saved before `Versioned`
+    sql: "
+      ALTER TABLE config ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+      CREATE TABLE state_backups (
+        id INTEGER PRIMARY KEY ASC,
+        key TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        value TEXT NOT NULL,
+        error TEXT NOT NULL,
+        created_at BIGINT NOT NULL
+      );
+    ",
+    import: None,
+  },
+  Migration {
+    version: 5,
+    name: "versioned yeelight modes",
+    sql: "
+      ALTER TABLE yeelight_modes ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+    ",
+    import: None,
+  },
+  Migration {
+    version: 6,
+    name: "back up unreadable config blobs",
+    // the data only
+    sql: "",
+    import: Some(back_up_config_blobs),
+  },
+];
+
+/// Extension state as saved in `config` before it had its own tables
+mod legacy {
+  use super::*;
+
+  #[derive(Deserialize)]
+  pub struct Reminder {
+    pub remind_at: DateTime<FixedOffset>,
+    pub set_at: DateTime<FixedOffset>,
+    pub content: String,
+    pub chat_id: i64,
+    pub message_id: i64,
+    #[serde(default)]
+    pub job: Option<i32>,
+    /// Already fired. Those were filtered out before saving, but the
+    /// field was saved along, so skip any that made it anyway.
+    #[serde(default)]
+    pub deleted: bool,
+  }
+
+  #[derive(Deserialize)]
+  pub struct Weather {
+    #[serde(default)]
+    pub weather_loc: HashMap<String, String>,
+  }
+
+  #[derive(Deserialize)]
+  pub struct Yeelight {
+    pub addr: Option<String>,
+    #[serde(default)]
+    pub modes: Vec<(String, serde_json::Value)>,
+  }
+
+  /// A row of `yeelight_modes` before it had a version
+  #[derive(Insertable)]
+  #[table_name = "yeelight_modes"]
+  pub struct Mode<'a> {
+    pub name: &'a str,
+    pub request: &'a str,
+  }
+}
+
+/// A config item `import_blobs` could not read
+struct Unreadable {
+  key: &'static str,
+  value: String,
+  reason: String,
+}
+
+/// The unreadable blobs are left in `config`, `state_backups` comes with
+/// version 4 and version 6 moves them there
+fn import_config_blobs(db: &SqliteStorage) -> DbResult<Vec<DbError>> {
+  import_blobs(db)?;
+  Ok(vec![])
+}
+
+/// Imports the blobs again, in case a build older than version 3 wrote
+/// some since, and backs up those still unreadable
+fn back_up_config_blobs(db: &SqliteStorage) -> DbResult<Vec<DbError>> {
+  let mut backed_up = vec![];
+  for blob in import_blobs(db)? {
+    let backup = db.back_up_conf(blob.key, 0, &blob.value, &blob.reason)?;
+    backed_up.push(DbError::State {
+      key: blob.key.into(),
+      backup,
+      reason: blob.reason,
+    });
+  }
+  Ok(backed_up)
+}
+
+/// Moves the legacy blobs into their tables, returns those unreadable
+fn import_blobs(db: &SqliteStorage) -> DbResult<Vec<Unreadable>> {
+  let mut unreadable = vec![];
+
+  let reminders: Option<Vec<legacy::Reminder>> =
+    take_blob(db, "reminders", &mut unreadable)?;
+  let reminders = reminders.unwrap_or_default();
+  for rem in reminders.into_iter().filter(|rem| !rem.deleted) {
+    db.insert_reminder(&DbReminder {
+      id: None,
+      remind_at: rem.remind_at.timestamp(),
+      set_at: rem.set_at.timestamp(),
+      content: rem.content,
+      chat_id: rem.chat_id,
+      message_id: rem.message_id,
+      job_id: rem.job,
+    })?;
+  }
+
+  let weather: Option<legacy::Weather> =
+    take_blob(db, "weather", &mut unreadable)?;
+  if let Some(weather) = weather {
+    for (city, long_lat) in weather.weather_loc {
+      db.save_location(&city, &long_lat)?;
+    }
+  }
+
+  let yeelight: Option<legacy::Yeelight> =
+    take_blob(db, "yeelight", &mut unreadable)?;
+  if let Some(yeelight) = yeelight {
+    if let Some(addr) = yeelight.addr {
+      db.add_yeelight_device(&addr)?;
+    }
+    for (name, request) in yeelight.modes {
+      let request = serde_json::to_string(&request)?;
+      let mode = legacy::Mode {
+        name: &name,
+        request: &request,
+      };
+      diesel::insert(&mode)
+        .into(yeelight_modes::table)
+        .execute(&db.conn)?;
+    }
+  }
+
+  let chats: Option<Vec<i64>> =
+    take_blob(db, "history.search_chats", &mut unreadable)?;
+  for chat_id in chats.unwrap_or_default() {
+    db.add_search_chat(chat_id)?;
+  }
+  let users: Option<Vec<i64>> =
+    take_blob(db, "history.search_users", &mut unreadable)?;
+  for user_id in users.unwrap_or_default() {
+    db.add_search_user(user_id)?;
+  }
+  Ok(unreadable)
+}
+
+/// The config item at `key`, removed once read. An unreadable one is
+/// left in place and added to `unreadable`.
+fn take_blob<T: DeserializeOwned>(
+  db: &SqliteStorage,
+  key: &'static str,
+  unreadable: &mut Vec<Unreadable>,
+) -> DbResult<Option<T>> {
+  let value = match db.raw_conf(key)? {
+    Some(value) => value,
+    None => return Ok(None),
+  };
+  match serde_json::from_str(&value) {
+    Ok(value) => {
+      db.delete_conf(key)?;
+      Ok(Some(value))
+    }
+    Err(e) => {
+      unreadable.push(Unreadable {
+        key,
+        value,
+        reason: e.to_string(),
+      });
+      Ok(None)
+    }
+  }
+}
+
+// SQLite's catalog, to look for tables without creating them
+table! {
+  sqlite_master (name) {
+    name -> Text,
+  }
+}
+
+#[derive(Insertable, Queryable, Clone, Debug)]
+#[table_name = "schema_migrations"]
+pub struct AppliedMigration {
+  pub version: i32,
+  pub name: String,
+  pub applied_at: i64,
+}
+
+impl SqliteStorage {
+  fn init_table_schema_migrations(&self) -> DbResult<()> {
+    self.execute_sql(
+      "CREATE TABLE IF NOT EXISTS schema_migrations (
+        version INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_at BIGINT NOT NULL
+      );",
+    )
+  }
+
+  /// Oldest first, none for a database never migrated. Does not write,
+  /// so it works on a read-only connection.
+  pub fn applied_migrations(&self) -> DbResult<Vec<AppliedMigration>> {
+    let table = sqlite_master::table
+      .select(sqlite_master::name)
+      .filter(sqlite_master::name.eq("schema_migrations"))
+      .first::<String>(&self.conn)
+      .optional()?;
+    if table.is_none() {
+      return Ok(vec![]);
+    }
+    let applied = schema_migrations::table
+      .order(schema_migrations::version.asc())
+      .load(&self.conn)?;
+    Ok(applied)
+  }
+
+  /// The version of the last migration applied, 0 for a new database
+  pub fn schema_version(&self) -> DbResult<i32> {
+    let applied = self.applied_migrations()?;
+    Ok(applied.last().map_or(0, |m| m.version))
+  }
+
+  /// Apply the pending migrations in order
+  pub fn migrate(&self, now: i64) -> DbResult<Migrated> {
+    self.init_table_schema_migrations()?;
+    let version = self.schema_version()?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if version > latest {
+      return Err(DbError::SchemaTooNew { version, latest });
+    }
+
+    let pending = MIGRATIONS.iter().filter(|m| m.version > version);
+    let mut migrated = Migrated::default();
+    for migration in pending {
+      self.execute_sql("BEGIN")?;
+      match self.apply(migration, now) {
+        Ok(backed_up) => migrated.backed_up.extend(backed_up),
+        Err(e) => {
+          self.execute_sql("ROLLBACK").ok();
+          return Err(e);
+        }
+      }
+      self.execute_sql("COMMIT")?;
+      migrated.applied.push(migration);
+    }
+    Ok(migrated)
+  }
+
+  /// Returns the data backed up by the import
+  fn apply(&self, migration: &Migration, now: i64) -> DbResult<Vec<DbError>> {
+    self.execute_sql(migration.sql)?;
+    let backed_up = match migration.import {
+      Some(import) => import(self)?,
+      None => vec![],
+    };
+    let record = AppliedMigration {
+      version: migration.version,
+      name: migration.name.into(),
+      applied_at: now,
+    };
+    diesel::insert(&record)
+      .into(schema_migrations::table)
+      .execute(&self.conn)?;
+    Ok(backed_up)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_migrate_legacy_database() {
+    let db = SqliteStorage::connect(":memory:").unwrap();
+    // as left by the versions creating tables on start
+    db.execute_sql(
+      "CREATE TABLE config (
+        id INTEGER PRIMARY KEY ASC, key TEXT UNIQUE, value TEXT
+      );
+      INSERT INTO config (key, value) VALUES ('afk', '{}'), ('broken', NULL);
+      INSERT INTO config (key, value) VALUES
+        ('weather', '{\"weather_loc\": {\"Shanghai\": \"121.47,31.23\"}}'),
+        ('history.search_users', '[1, 2]'),
+        ('yeelight', '{\"modes\": [[\"Night\", []]]}'),
+        ('reminders', '[{\"content\": \"unreadable\"}]');",
+    )
+    .unwrap();
+    assert_eq!(db.schema_version().unwrap(), 0);
+
+    let migrated = db.migrate(0).unwrap();
+    assert_eq!(migrated.applied.len(), MIGRATIONS.len());
+    assert_eq!(db.schema_version().unwrap(), 6);
+    assert!(db.migrate(0).unwrap().applied.is_empty());
+
+    // imported into their tables, the unreadable reminders backed up
+    let keys: Vec<_> =
+      db.list_conf().unwrap().into_iter().map(|c| c.0).collect();
+    assert_eq!(keys, vec!["afk"]);
+    assert_eq!(migrated.backed_up.len(), 1);
+    assert!(migrated.backed_up[0]
+      .to_string()
+      .starts_with("Unreadable reminders, moved to backup 1: missing field"));
+    let location = ("Shanghai".to_string(), "121.47,31.23".to_string());
+    assert_eq!(db.locations().unwrap(), vec![location]);
+    assert_eq!(db.search_users().unwrap(), vec![1, 2]);
+    let night = ("Night".to_string(), 0, "[]".to_string());
+    assert_eq!(db.yeelight_modes().unwrap(), vec![night]);
+    assert!(db.reminders().unwrap().is_empty());
+    // names are optional, as in `DbMessage`
+    db.execute_sql(
+      "INSERT INTO messages (msg_id, user_id, chat_id, is_group)
+       VALUES (1, 2, 3, 0);",
+    )
+    .unwrap();
+
+    // left by a later build
+    db.execute_sql(
+      "INSERT INTO schema_migrations (version, name, applied_at)
+       VALUES (99, 'from the future', 0);",
+    )
+    .unwrap();
+    let error = db.migrate(0).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Database schema version 99 is newer than supported (6)"
+    );
+  }
+
+  #[test]
+  fn test_unreadable_blobs_are_backed_up() {
+    let db = SqliteStorage::connect(":memory:").unwrap();
+    let stop = MIGRATIONS.iter().position(|m| m.version == 6).unwrap();
+    db.init_table_schema_migrations().unwrap();
+    for migration in &MIGRATIONS[..stop] {
+      db.apply(migration, 0).unwrap();
+    }
+    // left in place by version 3, and one written by an older build since
+    db.execute_sql(
+      "INSERT INTO config (key, value) VALUES
+        ('weather', '{\"weather_loc\": [\"Shanghai\"]}'),
+        ('history.search_chats', 'not json'),
+        ('reminders', '[{
+          \"remind_at\": \"2018-01-01T08:00:00+08:00\",
+          \"set_at\": \"2018-01-01T07:00:00+08:00\",
+          \"content\": \"fired\", \"chat_id\": 1, \"message_id\": 2,
+          \"deleted\": true
+        }]');",
+    )
+    .unwrap();
+
+    let migrated = db.migrate(0).unwrap();
+    assert_eq!(migrated.applied.len(), 1);
+    let backed_up: Vec<_> =
+      migrated.backed_up.iter().map(|e| e.to_string()).collect();
+    assert_eq!(backed_up.len(), 2);
+    assert!(backed_up[0].starts_with("Unreadable weather, moved to backup 1"));
+    assert!(backed_up[1]
+      .starts_with("Unreadable history.search_chats, moved to backup 2"));
+    assert!(db.list_conf().unwrap().is_empty());
+    assert!(db.search_chats().unwrap().is_empty());
+    // imported, but already fired
+    assert!(db.reminders().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_status_does_not_write() {
+    let path = std::env::temp_dir().join("crate-no-such-database.db");
+    let path = path.to_str().unwrap();
+    assert!(SqliteStorage::connect_read_only(path).unwrap().is_none());
+    assert!(!std::path::Path::new(path).exists());
+
+    let db = SqliteStorage::connect(":memory:").unwrap();
+    db.execute_sql("PRAGMA query_only = ON").unwrap();
+    assert!(db.applied_migrations().unwrap().is_empty());
+    assert_eq!(db.schema_version().unwrap(), 0);
+    assert!(db.migrate(0).is_err());
+  }
+}
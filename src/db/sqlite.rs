@@ -0,0 +1,493 @@
+use super::schema::*;
+use super::*;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use diesel::types::{Bool, Integer, Nullable, Text};
+use std::path::Path;
+
+/// Storage in an SQLite database, see `MIGRATIONS` for its schema
+pub struct SqliteStorage {
+  pub(super) conn: SqliteConnection,
+}
+
+// `like(pattern, text, escape)` is `text LIKE pattern ESCAPE escape`
+sql_function!(
+  like,
+  like_t,
+  (pattern: Text, text: Nullable<Text>, escape: Text) -> Bool
+);
+no_arg_sql_function!(last_insert_rowid, Integer);
+
+#[derive(Insertable)]
+#[table_name = "config"]
+struct NewConf<'a> {
+  key: &'a str,
+  value: &'a str,
+  version: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "updates"]
+struct DbUpdate {
+  update_id: i64,
+  received_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "weather_locations"]
+struct NewLocation<'a> {
+  city: &'a str,
+  long_lat: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "yeelight_devices"]
+struct NewDevice<'a> {
+  addr: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "yeelight_modes"]
+struct NewMode<'a> {
+  name: &'a str,
+  request: &'a str,
+  version: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "search_chats"]
+struct SearchChat {
+  chat_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "search_users"]
+struct SearchUser {
+  user_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "state_backups"]
+struct NewBackup<'a> {
+  key: &'a str,
+  version: i32,
+  value: &'a str,
+  error: &'a str,
+  created_at: i64,
+}
+
+impl SqliteStorage {
+  /// A throwaway database living only as long as the connection
+  #[allow(dead_code)]
+  pub fn in_memory() -> Self {
+    let (db, _) =
+      Self::open(":memory:").expect("Failed to create in-memory database");
+    db
+  }
+
+  /// Open the database at `path`, bringing its schema up to date
+  pub fn open(path: &str) -> DbResult<(Self, Migrated)> {
+    let db = Self::connect(path)?;
+    let migrated = db.migrate(chrono::Local::now().timestamp())?;
+    Ok((db, migrated))
+  }
+
+  /// Open the database without touching its schema
+  pub fn connect(path: &str) -> DbResult<Self> {
+    let conn = SqliteConnection::establish(path)?;
+    Ok(SqliteStorage { conn })
+  }
+
+  /// Open an existing database for reading only, None if there is none
+  pub fn connect_read_only(path: &str) -> DbResult<Option<Self>> {
+    // connecting would create a missing file
+    if !Path::new(path).exists() {
+      return Ok(None);
+    }
+    let db = Self::connect(path)?;
+    db.execute_sql("PRAGMA query_only = ON")?;
+    Ok(Some(db))
+  }
+
+  /// For statements without parameters, like the migrations
+  pub(super) fn execute_sql(&self, s: &str) -> DbResult<()> {
+    self.conn.execute(s)?;
+    Ok(())
+  }
+
+  /// Returns the id of the backup
+  fn insert_backup(
+    &self,
+    key: &str,
+    version: i32,
+    value: &str,
+    error: &str,
+  ) -> DbResult<i32> {
+    let backup = NewBackup {
+      key,
+      version,
+      value,
+      error,
+      created_at: chrono::Local::now().timestamp(),
+    };
+    diesel::insert(&backup)
+      .into(state_backups::table)
+      .execute(&self.conn)?;
+    let id = diesel::select(last_insert_rowid).get_result(&self.conn)?;
+    Ok(id)
+  }
+}
+
+impl Storage for SqliteStorage {
+  fn store_conf(&self, key: &str, version: i32, value: &str) -> DbResult<()> {
+    let target = config::table.filter(config::key.eq(key));
+    let updated = diesel::update(target)
+      .set((config::value.eq(value), config::version.eq(version)))
+      .execute(&self.conn)?;
+    if updated == 0 {
+      let conf = NewConf {
+        key,
+        value,
+        version,
+      };
+      diesel::insert(&conf)
+        .into(config::table)
+        .execute(&self.conn)?;
+    }
+    Ok(())
+  }
+
+  fn conf(&self, key: &str) -> DbResult<Option<(String, i32)>> {
+    let saved = config::table
+      .filter(config::key.eq(key))
+      .select((config::value, config::version))
+      .first(&self.conn)
+      .optional()?;
+    Ok(saved)
+  }
+
+  fn back_up_conf(
+    &self,
+    key: &str,
+    version: i32,
+    value: &str,
+    error: &str,
+  ) -> DbResult<i32> {
+    let id = self.insert_backup(key, version, value, error)?;
+    self.delete_conf(key)?;
+    Ok(id)
+  }
+
+  fn raw_conf(&self, key: &str) -> DbResult<Option<String>> {
+    let value = config::table
+      .filter(config::key.eq(key))
+      .select(config::value)
+      .first(&self.conn)
+      .optional()?;
+    Ok(value)
+  }
+
+  fn list_conf(&self) -> DbResult<Vec<(String, String)>> {
+    let confs = config::table
+      .select((config::key, config::value))
+      .order(config::key.asc())
+      .load(&self.conn)?;
+    Ok(confs)
+  }
+
+  fn delete_conf(&self, key: &str) -> DbResult<bool> {
+    let target = config::table.filter(config::key.eq(key));
+    let deleted = diesel::delete(target).execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn save_msg(&self, msg: &DbMessage) -> DbResult<()> {
+    diesel::insert(msg)
+      .into(messages::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn update_msg_text(
+    &self,
+    chat_id: i64,
+    msg_id: i64,
+    text: &str,
+  ) -> DbResult<()> {
+    let target = messages::table
+      .filter(messages::chat_id.eq(chat_id))
+      .filter(messages::msg_id.eq(msg_id));
+    diesel::update(target)
+      .set(messages::text.eq(Some(text)))
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn search_msg(
+    &self,
+    page: usize,
+    pattern: &str,
+    users: &[i64],
+  ) -> DbResult<(usize, Vec<DbMessage>)> {
+    if pattern.is_empty() || users.is_empty() {
+      return Ok(Default::default());
+    }
+    let pattern = format!("%{}%", pattern);
+    let query = messages::table
+      .filter(like(pattern, messages::text, LIKE_ESCAPE.to_string()))
+      .filter(messages::user_id.eq_any(users.to_vec()))
+      .order(messages::created_at.desc());
+    let count: i64 = query.clone().count().get_result(&self.conn)?;
+    let result = query
+      .offset(((page - 1) * SEARCH_PER) as i64)
+      .limit(SEARCH_PER as i64)
+      .load(&self.conn)?;
+    Ok((count as usize, result))
+  }
+
+  fn insert_job(&self, job: &DbJob) -> DbResult<i32> {
+    diesel::insert(job).into(jobs::table).execute(&self.conn)?;
+    let id = diesel::select(last_insert_rowid).get_result(&self.conn)?;
+    Ok(id)
+  }
+
+  fn due_jobs(&self, now: i64) -> DbResult<Vec<DbJob>> {
+    let jobs = jobs::table
+      .filter(jobs::next_run.le(now))
+      .order(jobs::next_run.asc())
+      .load(&self.conn)?;
+    Ok(jobs)
+  }
+
+  fn ext_jobs(&self, ext: &str) -> DbResult<Vec<DbJob>> {
+    let jobs = jobs::table
+      .filter(jobs::ext.eq(ext))
+      .order(jobs::next_run.asc())
+      .load(&self.conn)?;
+    Ok(jobs)
+  }
+
+  fn reschedule_job(&self, id: i32, next_run: i64) -> DbResult<()> {
+    diesel::update(jobs::table.filter(jobs::id.eq(id)))
+      .set(jobs::next_run.eq(next_run))
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn delete_job(&self, id: i32) -> DbResult<bool> {
+    let deleted = diesel::delete(jobs::table.filter(jobs::id.eq(id)))
+      .execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn insert_audit(&self, entry: &DbAudit) -> DbResult<()> {
+    diesel::insert(entry)
+      .into(audit::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn audit_entries(
+    &self,
+    kind: Option<&str>,
+    user_id: Option<i64>,
+    chat_id: Option<i64>,
+    limit: usize,
+  ) -> DbResult<Vec<DbAudit>> {
+    let mut query = audit::table
+      .order(audit::id.desc())
+      .limit(limit as i64)
+      .into_boxed();
+    if let Some(kind) = kind {
+      query = query.filter(audit::kind.eq(kind));
+    }
+    if let Some(user_id) = user_id {
+      query = query.filter(audit::user_id.eq(user_id));
+    }
+    if let Some(chat_id) = chat_id {
+      query = query.filter(audit::chat_id.eq(chat_id));
+    }
+    Ok(query.load(&self.conn)?)
+  }
+
+  fn mark_update(&self, update_id: i64, now: i64) -> DbResult<bool> {
+    let expired = updates::received_at.lt(now - UPDATE_RETENTION_SECS);
+    diesel::delete(updates::table.filter(expired)).execute(&self.conn)?;
+
+    let seen: i64 = updates::table
+      .filter(updates::update_id.eq(update_id))
+      .count()
+      .get_result(&self.conn)?;
+    if seen > 0 {
+      return Ok(false);
+    }
+    let update = DbUpdate {
+      update_id,
+      received_at: now,
+    };
+    diesel::insert(&update)
+      .into(updates::table)
+      .execute(&self.conn)?;
+    Ok(true)
+  }
+
+  fn insert_reminder(&self, reminder: &DbReminder) -> DbResult<i32> {
+    diesel::insert(reminder)
+      .into(reminders::table)
+      .execute(&self.conn)?;
+    let id = diesel::select(last_insert_rowid).get_result(&self.conn)?;
+    Ok(id)
+  }
+
+  fn reminders(&self) -> DbResult<Vec<DbReminder>> {
+    let reminders = reminders::table
+      .order(reminders::id.asc())
+      .load(&self.conn)?;
+    Ok(reminders)
+  }
+
+  fn set_reminder_job(&self, id: i32, job_id: i32) -> DbResult<()> {
+    diesel::update(reminders::table.filter(reminders::id.eq(id)))
+      .set(reminders::job_id.eq(job_id))
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn delete_reminder(&self, id: i32) -> DbResult<bool> {
+    let target = reminders::table.filter(reminders::id.eq(id));
+    let deleted = diesel::delete(target).execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn save_location(&self, city: &str, long_lat: &str) -> DbResult<()> {
+    let location = NewLocation { city, long_lat };
+    diesel::insert(&location)
+      .into(weather_locations::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn locations(&self) -> DbResult<Vec<(String, String)>> {
+    let locations = weather_locations::table
+      .order(weather_locations::city.asc())
+      .load(&self.conn)?;
+    Ok(locations)
+  }
+
+  fn delete_location(&self, city: &str) -> DbResult<bool> {
+    let target =
+      weather_locations::table.filter(weather_locations::city.eq(city));
+    let deleted = diesel::delete(target).execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn yeelight_devices(&self) -> DbResult<Vec<String>> {
+    let devices = yeelight_devices::table
+      .select(yeelight_devices::addr)
+      .order(yeelight_devices::id.asc())
+      .load(&self.conn)?;
+    Ok(devices)
+  }
+
+  fn add_yeelight_device(&self, addr: &str) -> DbResult<()> {
+    diesel::insert(&NewDevice { addr })
+      .into(yeelight_devices::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn delete_yeelight_device(&self, addr: &str) -> DbResult<bool> {
+    let target =
+      yeelight_devices::table.filter(yeelight_devices::addr.eq(addr));
+    let deleted = diesel::delete(target).execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn yeelight_modes(&self) -> DbResult<Vec<(String, i32, String)>> {
+    let modes = yeelight_modes::table
+      .select((
+        yeelight_modes::name,
+        yeelight_modes::version,
+        yeelight_modes::request,
+      ))
+      .order(yeelight_modes::id.asc())
+      .load(&self.conn)?;
+    Ok(modes)
+  }
+
+  fn insert_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+  ) -> DbResult<()> {
+    let taken = yeelight_modes::table
+      .filter(yeelight_modes::name.eq(name))
+      .select(yeelight_modes::id)
+      .first::<Option<i32>>(&self.conn)
+      .optional()?;
+    if taken.is_some() {
+      return Err(DbError::Exists(format!("Yeelight mode {}", name)));
+    }
+    let mode = NewMode {
+      name,
+      request,
+      version,
+    };
+    diesel::insert(&mode)
+      .into(yeelight_modes::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn delete_yeelight_mode(&self, name: &str) -> DbResult<bool> {
+    let target = yeelight_modes::table.filter(yeelight_modes::name.eq(name));
+    let deleted = diesel::delete(target).execute(&self.conn)?;
+    Ok(deleted > 0)
+  }
+
+  fn back_up_yeelight_mode(
+    &self,
+    name: &str,
+    version: i32,
+    request: &str,
+    error: &str,
+  ) -> DbResult<i32> {
+    let key = format!("yeelight_modes.{}", name);
+    let id = self.insert_backup(&key, version, request, error)?;
+    self.delete_yeelight_mode(name)?;
+    Ok(id)
+  }
+
+  fn search_chats(&self) -> DbResult<Vec<i64>> {
+    let chats = search_chats::table
+      .select(search_chats::chat_id)
+      .order(search_chats::chat_id.asc())
+      .load(&self.conn)?;
+    Ok(chats)
+  }
+
+  fn add_search_chat(&self, chat_id: i64) -> DbResult<()> {
+    diesel::insert(&SearchChat { chat_id })
+      .into(search_chats::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+
+  fn search_users(&self) -> DbResult<Vec<i64>> {
+    let users = search_users::table
+      .select(search_users::user_id)
+      .order(search_users::user_id.asc())
+      .load(&self.conn)?;
+    Ok(users)
+  }
+
+  fn add_search_user(&self, user_id: i64) -> DbResult<()> {
+    diesel::insert(&SearchUser { user_id })
+      .into(search_users::table)
+      .execute(&self.conn)?;
+    Ok(())
+  }
+}
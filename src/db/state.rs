@@ -0,0 +1,137 @@
+use super::{DbError, DbResult, Storage};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use serde_json::Value as JsonValue;
+
+/// Turns state saved at one version into the next
+pub type Upgrade = fn(JsonValue) -> Result<JsonValue, String>;
+
+/// State saved in the `config` table, readable after its type changes
+pub trait Versioned: Serialize + DeserializeOwned {
+  /// `upgrades()[n]` turns state saved at version `n` into `n + 1`.
+  /// Append one whenever the serialized form changes.
+  fn upgrades() -> &'static [Upgrade] {
+    &[]
+  }
+
+  fn version() -> i32 {
+    Self::upgrades().len() as i32
+  }
+
+  /// Read state saved at `version`, upgrading it to the current one
+  fn from_saved(value: &str, version: i32) -> Result<Self, String> {
+    let upgrades = Self::upgrades();
+    if version < 0 || version > upgrades.len() as i32 {
+      return Err(format!("unknown version {}", version));
+    }
+    let mut value: JsonValue =
+      serde_json::from_str(value).map_err(|e| e.to_string())?;
+    for upgrade in &upgrades[version as usize..] {
+      value = upgrade(value)?;
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+  }
+}
+
+/// Versioned state on top of any `Storage`
+pub trait StorageExt {
+  /// Upgraded to the current version of `T`. One that cannot be read is
+  /// moved to a backup, see `DbError::State`.
+  fn load_conf<T: Versioned>(&self, key: &str) -> DbResult<Option<T>>;
+  /// Saved as the current version of `T`
+  fn save_conf<T: Versioned>(&self, key: &str, value: &T) -> DbResult<()>;
+  /// Saved as state of `version`, see `Versioned`
+  fn save_json(
+    &self,
+    key: &str,
+    version: i32,
+    value: &JsonValue,
+  ) -> DbResult<()>;
+}
+
+impl<S: Storage + ?Sized> StorageExt for S {
+  fn load_conf<T: Versioned>(&self, key: &str) -> DbResult<Option<T>> {
+    let (value, version) = match self.conf(key)? {
+      Some(saved) => saved,
+      None => return Ok(None),
+    };
+    match T::from_saved(&value, version) {
+      Ok(state) => Ok(Some(state)),
+      Err(reason) => {
+        let backup = self.back_up_conf(key, version, &value, &reason)?;
+        let key = key.into();
+        Err(DbError::State {
+          key,
+          backup,
+          reason,
+        })
+      }
+    }
+  }
+
+  fn save_conf<T: Versioned>(&self, key: &str, value: &T) -> DbResult<()> {
+    let value = serde_json::to_string_pretty(value)?;
+    self.store_conf(key, T::version(), &value)
+  }
+
+  fn save_json(
+    &self,
+    key: &str,
+    version: i32,
+    value: &JsonValue,
+  ) -> DbResult<()> {
+    let value = serde_json::to_string_pretty(value)?;
+    self.store_conf(key, version, &value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::db::SqliteStorage;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  struct Mode {
+    name: String,
+    brightness: u32,
+  }
+
+  /// Version 0 called it `bright`
+  fn rename_bright(mut value: JsonValue) -> Result<JsonValue, String> {
+    let bright = value["bright"].take();
+    value["brightness"] = bright;
+    Ok(value)
+  }
+
+  impl Versioned for Mode {
+    fn upgrades() -> &'static [Upgrade] {
+      &[rename_bright]
+    }
+  }
+
+  #[test]
+  fn test_upgrade_or_back_up_state() {
+    let db = SqliteStorage::in_memory();
+    let old = json!({"name": "Night", "bright": 1});
+    db.save_json("mode", 0, &old).unwrap();
+    let mode: Option<Mode> = db.load_conf("mode").unwrap();
+    let night = Mode {
+      name: "Night".into(),
+      brightness: 1,
+    };
+    assert_eq!(mode, Some(night));
+
+    // saved as version 1, not upgraded again
+    db.save_conf("mode", &mode.unwrap()).unwrap();
+    assert!(db.load_conf::<Mode>("mode").unwrap().is_some());
+
+    db.save_json("mode", 1, &json!({"name": 1})).unwrap();
+    let error = db.load_conf::<Mode>("mode").unwrap_err();
+    assert!(error
+      .to_string()
+      .starts_with("Unreadable mode, moved to backup 1: invalid type"));
+    assert!(db.load_conf::<Mode>("mode").unwrap().is_none());
+  }
+}
@@ -1,5 +1,6 @@
 use crate::common::*;
 use std::result;
+use crate::db::DbError;
 use crate::extensions as ext;
 
 #[derive(Fail, Debug)]
@@ -13,6 +14,9 @@ pub enum FondbotError {
     #[fail(display = "Extension error: {}", _0)]
     Extension(#[cause] ext::ExtensionError),
 
+    #[fail(display = "{}", _0)]
+    Db(#[cause] DbError),
+
     #[fail(display = "{}", _0)]
     Message(String)
 }
@@ -48,3 +52,9 @@ impl From<ext::ExtensionError> for FondbotError {
         FondbotError::Extension(e)
     }
 }
+
+impl From<DbError> for FondbotError {
+    fn from(e: DbError) -> FondbotError {
+        FondbotError::Db(e)
+    }
+}
@@ -42,14 +42,15 @@ impl Afk {
         Local::now().signed_duration_since(last_notify) >= *NOTIFY_INTERVAL
     }
 
-    fn report_afk(&mut self, msg: &tg::Message, ctx: &Context) {
+    /// Returns whether a notice was sent, updating `last_notify`
+    fn report_afk(&mut self, msg: &tg::Message, ctx: &Context) -> bool {
         if !self.is_afk() {
-            println!("not afk now");
-            return;
+            trace!(ctx.logger, "not afk now");
+            return false;
         }
         if !self.notification_expired() {
-            println!("notify not expired");
-            return;
+            trace!(ctx.logger, "notify not expired");
+            return false;
         }
 
         let text = {
@@ -71,6 +72,7 @@ impl Afk {
         self.state.as_mut().unwrap().last_notify = Local::now();
 
         ctx.bot.reply_md_to(msg, text);
+        true
     }
 
 
@@ -80,38 +82,57 @@ impl Afk {
     }
 }
 
+impl Versioned for Afk {}
+
 impl BotExtension for Afk {
     fn init(ctx: &Context) -> Self {
-        ctx.db.load_conf("afk").unwrap_or_default()
+        ctx.load_conf("afk")
     }
 
-    fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-        if !self.is_afk() && !msg.is_cmd("afk") {
-            return;
+    fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+        if !self.is_afk() && !afk_command().matches(msg) {
+            return Dispatch::Continue;
         }
 
-        if msg.is_cmd("afk") {
+        if afk_command().matches(msg) {
             self.set_afk(msg, ctx);
             ctx.bot.reply_to(msg, "Afk set");
-            ctx.db.save_conf("afk", &self);
-            return;
+            ctx.save_conf("afk", self);
+            return Dispatch::Consumed;
         }
 
-        if msg.is_cmd("noafk") {
+        if noafk_command().matches(msg) {
             self.unset_afk();
             ctx.bot.reply_to(msg, "Afk unset");
-            ctx.db.save_conf("afk", &self);
-            return;
+            ctx.save_conf("afk", self);
+            return Dispatch::Consumed;
         }
 
-        ctx.db.save_conf("afk", &self);
-
-        self.report_afk(msg, ctx);
+        if self.report_afk(msg, ctx) {
+            ctx.save_conf("afk", self);
+        }
 
         if let tg::MessageChat::Private(_) = msg.chat {
-            // don't bypass private chat
+            // don't swallow messages in private chat
+            Dispatch::Continue
         } else {
-            ctx.set_bypass();
+            // nobody else gets to respond while the notice is up
+            Dispatch::Stop
+        }
+    }
+
+    /// Runs before every extension that may respond to the message
+    fn priority(&self) -> i32 {
+        50
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        vec![afk_command(), noafk_command()]
+    }
+
+    fn reload(&mut self, key: &str, ctx: &Context) {
+        if key == Self::NAME {
+            *self = Self::init(ctx);
         }
     }
 
@@ -119,6 +140,48 @@ impl BotExtension for Afk {
         "this is afk!".to_string()
     }
     fn name(&self) -> &str {
-        "afk"
+        Self::NAME
+    }
+}
+
+impl Named for Afk {
+    const NAME: &'static str = "afk";
+}
+
+fn afk_command() -> Command {
+    Command::new("afk", "set afk")
+        .usage("[reason]")
+        .arg("reason", "shown to people who talk while you're away")
+}
+
+fn noafk_command() -> Command {
+    Command::new("noafk", "no afk")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::extensions::link_cleanser::LinkCleanser;
+    use crate::testing::*;
+
+    #[test]
+    fn test_afk_notice_stops_chain_in_groups() {
+        let mut h = Harness::new();
+        // plugged last, but runs first because of its priority
+        h.plug::<LinkCleanser>().plug::<Afk>();
+        h.send_text(GROUP_CHAT, USER_ID, "/afk lunch");
+        assert_eq!(h.take_requests()[0].text(), Some("Afk set"));
+
+        let link = "https://item.m.jd.com/product/4385461.html";
+        h.send_text(GROUP_CHAT, OTHER_USER_ID, link);
+        let reqs = h.take_requests();
+        assert_eq!(reqs.len(), 1);
+        assert!(reqs[0].text().unwrap().contains("is *AFK* now"));
+
+        // private chats are never swallowed
+        h.send_text(PRIVATE_CHAT, OTHER_USER_ID, link);
+        let reqs = h.take_requests();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].text(), Some("https://item.jd.com/4385461.html"));
     }
 }
@@ -0,0 +1,107 @@
+use crate::common::*;
+
+use serde_json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Identifies the session or state version a panel was rendered for
+pub type Token = u32;
+
+/// Telegram rejects buttons carrying more callback data than this
+pub const MAX_CALLBACK_DATA: usize = 64;
+
+pub const PANEL_EXPIRED: &str = "This panel has expired";
+
+/// For buttons of extensions not loaded or disabled in the chat
+pub const FEATURE_DISABLED: &str = "This feature is disabled here";
+
+/// A fresh token, unlikely to collide with those issued before a restart
+pub fn new_token() -> Token {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+  let now = Local::now();
+  let seed = (now.timestamp() as Token) ^ now.timestamp_subsec_nanos();
+  seed.wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed) as Token)
+}
+
+/// Callback data of an inline button, serialized as
+/// `<ext>.<token>.<payload>` with the token in hex and the payload in
+/// compact JSON
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackData<'a> {
+  pub ext: &'a str,
+  pub token: Token,
+  payload: &'a str,
+}
+
+impl<'a> CallbackData<'a> {
+  pub fn parse(data: &'a str) -> Option<Self> {
+    let mut parts = data.splitn(3, '.');
+    let ext = parts.next()?;
+    let token = Token::from_str_radix(parts.next()?, 16).ok()?;
+    let payload = parts.next()?;
+    Some(CallbackData {
+      ext,
+      token,
+      payload,
+    })
+  }
+
+  pub fn payload<T: DeserializeOwned>(&self) -> Option<T> {
+    serde_json::from_str(self.payload).ok()
+  }
+}
+
+pub fn encode<T: Serialize>(
+  ext: &str,
+  token: Token,
+  payload: &T,
+) -> Result<String> {
+  let payload = serde_json::to_string(payload)
+    .map_err(|e| format!("Invalid callback payload: {}", e))?;
+  let data = format!("{}.{:x}.{}", ext, token, payload);
+  if data.len() > MAX_CALLBACK_DATA {
+    return Err(format!("Callback data too long: {}", data).into());
+  }
+  Ok(data)
+}
+
+/// Panics if the payload does not fit, which is a bug in the extension
+pub fn callback_button<T: Serialize>(
+  ext: &str,
+  text: &str,
+  token: Token,
+  payload: &T,
+) -> tg::InlineKeyboardButton {
+  let data = encode(ext, token, payload).unwrap();
+  tg::InlineKeyboardButton::callback(text, data)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  enum Action {
+    Shift(i64),
+    Commit,
+  }
+
+  #[test]
+  fn test_payload_roundtrip() {
+    let data = encode("reminder", 0xbeef, &Action::Shift(-30)).unwrap();
+    assert_eq!(data, "reminder.beef.{\"Shift\":-30}");
+
+    let parsed = CallbackData::parse(&data).unwrap();
+    assert_eq!(parsed.ext, "reminder");
+    assert_eq!(parsed.token, 0xbeef);
+    assert_eq!(parsed.payload(), Some(Action::Shift(-30)));
+    assert_eq!(parsed.payload::<String>(), None);
+  }
+
+  #[test]
+  fn test_rejects_oversized_and_legacy_data() {
+    let long = "x".repeat(MAX_CALLBACK_DATA);
+    assert!(encode("yeelight", new_token(), &long).is_err());
+    assert!(encode("yeelight", Token::max_value(), &Action::Commit).is_ok());
+    assert_eq!(CallbackData::parse("reminder.commit_time"), None);
+  }
+}
@@ -0,0 +1,137 @@
+use crate::common::*;
+
+/// Where a command is offered to users
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+  /// Available in every chat
+  Public,
+  /// Only meaningful in private chats
+  Private,
+  /// Only meaningful in group chats
+  Group,
+}
+
+/// Declarative description of a bot command, used to generate `/help`
+/// and the command list pushed to Telegram.
+#[derive(Debug, Clone)]
+pub struct Command {
+  pub name: &'static str,
+  pub description: &'static str,
+  pub usage: Option<&'static str>,
+  pub args: Vec<(&'static str, &'static str)>,
+  pub visibility: Visibility,
+  /// Least role allowed to run the command, unless overridden in
+  /// `SafetyGuard`
+  pub role: Role,
+  /// The command is a prefix, e.g. `/del_<n>`
+  pub is_prefix: bool,
+}
+
+impl Command {
+  pub fn new(name: &'static str, description: &'static str) -> Self {
+    Command {
+      name,
+      description,
+      usage: None,
+      args: vec![],
+      visibility: Visibility::Public,
+      role: Role::Member,
+      is_prefix: false,
+    }
+  }
+
+  pub fn prefix(name: &'static str, description: &'static str) -> Self {
+    Command {
+      is_prefix: true,
+      ..Command::new(name, description)
+    }
+  }
+
+  pub fn usage(mut self, usage: &'static str) -> Self {
+    self.usage = Some(usage);
+    self
+  }
+
+  pub fn arg(mut self, name: &'static str, description: &'static str) -> Self {
+    self.args.push((name, description));
+    self
+  }
+
+  pub fn visibility(mut self, visibility: Visibility) -> Self {
+    self.visibility = visibility;
+    self
+  }
+
+  pub fn requires(mut self, role: Role) -> Self {
+    self.role = role;
+    self
+  }
+
+  pub fn matches(&self, msg: &tg::Message) -> bool {
+    if self.is_prefix {
+      msg.is_cmd_prefix(self.name)
+    } else {
+      msg.is_cmd(self.name)
+    }
+  }
+
+  /// Whether to offer the command in `chat`, to a user who is `allowed`
+  /// to run it
+  pub fn is_visible_in(&self, chat: &tg::MessageChat, allowed: bool) -> bool {
+    allowed
+      && match self.visibility {
+        Visibility::Public => true,
+        Visibility::Private => is_private(chat),
+        Visibility::Group => !is_private(chat),
+      }
+  }
+
+  /// Whether the command can be pushed to Telegram by `setMyCommands`,
+  /// which shows it to every member
+  pub fn is_listable(&self) -> bool {
+    !self.is_prefix && self.role <= Role::Member
+  }
+
+  pub fn signature(&self) -> String {
+    let name = if self.is_prefix {
+      format!("/{}<n>", self.name)
+    } else {
+      format!("/{}", self.name)
+    };
+
+    match self.usage {
+      Some(usage) => format!("{} {}", name, usage),
+      None => name,
+    }
+  }
+
+  pub fn usage_text(&self) -> String {
+    format!("Usage: {}", self.signature())
+  }
+
+  pub fn help_line(&self) -> String {
+    format!("{} - {}", self.signature(), self.description)
+  }
+
+  pub fn help_detail(&self) -> String {
+    let mut text = String::new();
+    writeln!(text, "{}", self.signature()).ok();
+    writeln!(text, "{}", self.description).ok();
+
+    if !self.args.is_empty() {
+      writeln!(text, "\nArguments:").ok();
+      for (name, desc) in self.args.iter() {
+        writeln!(text, "  {} - {}", name, desc).ok();
+      }
+    }
+
+    text
+  }
+}
+
+fn is_private(chat: &tg::MessageChat) -> bool {
+  match *chat {
+    tg::MessageChat::Private(..) => true,
+    _ => false,
+  }
+}
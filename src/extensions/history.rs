@@ -1,25 +1,30 @@
 use chrono::{DateTime, Local, TimeZone};
 use crate::common::*;
-use crate::db::DbMessage;
-use crate::db::SEARCH_PER;
+use crate::db::{escape_like, DbMessage, SEARCH_PER};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Saver {
   search_chats: HashSet<tg::ChatId>,
-  search_users: HashSet<tg::UserId>,
 }
 
 #[derive(Debug)]
 struct SearchQuery {
+  token: Token,
   pattern: String,
   page: usize,
   total: usize,
   items: Vec<DbMessage>,
 }
 
-#[derive(Debug, Default)]
+/// Payload of the pagination buttons
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+enum Page {
+  Prev,
+  Next,
+}
+
 pub struct Searcher {
-  search: Option<SearchQuery>,
+  search: SessionStore<SearchQuery>,
 }
 
 const EMPTY_PATTERN_PROMPT: &str = "Please enter pattern";
@@ -66,6 +71,38 @@ fn to_db_message(msg: &tg::Message, ctx: &Context) -> DbMessage {
   }
 }
 
+/// Messages containing `pattern`, in which `*` matches anything, only
+/// those of `author` if given
+fn search_db(
+  db: &Storage,
+  page: usize,
+  pattern: &str,
+  author: Option<i64>,
+) -> DbResult<(usize, Vec<DbMessage>)> {
+  let mut users = db.search_users()?;
+  if let Some(author) = author {
+    users.retain(|&user| user == author);
+  }
+  if users.is_empty() {
+    return Ok((0, vec![]));
+  }
+  let parts: Vec<_> = pattern.split('*').map(escape_like).collect();
+  db.search_msg(page, &parts.join("%"), &users)
+}
+
+/// The pattern of an inline query asking for a search, e.g. `search cake`
+fn inline_pattern(query: &str) -> Option<&str> {
+  let query = query.trim();
+  if !query.starts_with("search") {
+    return None;
+  }
+  let rest = &query["search".len()..];
+  match rest.chars().next() {
+    Some(c) if !c.is_whitespace() => None,
+    _ => Some(rest.trim()),
+  }
+}
+
 fn format_time(time: Option<i64>) -> String {
   let time: DateTime<Local> = Local.timestamp(time.unwrap_or(0), 0);
   time.format("%Y-%m-%d").to_string()
@@ -73,21 +110,25 @@ fn format_time(time: Option<i64>) -> String {
 
 impl BotExtension for Saver {
   fn init(ctx: &Context) -> Self {
-    ctx.db.load_conf("history.search_chats").unwrap_or_default()
+    let chats = ctx.or_report("history", ctx.db.search_chats());
+    Saver {
+      search_chats: chats.into_iter().map(tg::ChatId::from).collect(),
+    }
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-    if msg.is_cmd("enable_search_for_chat") {
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    if enable_chat_command().matches(msg) {
       self.search_chats.insert(msg.chat.id());
-      ctx.db.save_conf("history.search_chats", &self.search_chats);
+      let saved = ctx.db.add_search_chat(msg.chat.id().into());
+      ctx.or_report(self.name(), saved);
       ctx
         .bot
         .reply_to(msg, format!("Chat {} added to search group", msg.chat.id()));
-      return;
+      return Dispatch::Consumed;
     }
-    if msg.is_cmd("enable_search_for_me") {
-      self.search_users.insert(msg.from.id);
-      ctx.db.save_conf("history.search_users", &self.search_users);
+    if enable_me_command().matches(msg) {
+      let saved = ctx.db.add_search_user(msg.from.id.into());
+      ctx.or_report(self.name(), saved);
       ctx.bot.reply_to(
         msg,
         format!(
@@ -95,18 +136,18 @@ impl BotExtension for Saver {
           ctx.names.get(&msg.from)
         ),
       );
-      return;
+      return Dispatch::Consumed;
     }
 
     if !self.search_chats.contains(&msg.chat.id()) {
       trace!(ctx.logger, "history: Message not saved: not in group");
-      return;
+      return Dispatch::Continue;
     }
 
     if msg.text_content().is_none() {
       // we only want to search text messages
       trace!(ctx.logger, "history: Message not saved: not text");
-      return;
+      return Dispatch::Continue;
     }
 
     let msg_text = msg.text_content().unwrap();
@@ -118,21 +159,79 @@ impl BotExtension for Saver {
     if msg_text.chars().count() >= 400 {
       // we don't like message too long
       trace!(ctx.logger, "history: Message not saved: too long");
-      return;
+      return Dispatch::Continue;
     }
 
-    ctx.db.save_msg(&to_db_message(msg, ctx));
+    if let Err(e) = ctx.db.save_msg(&to_db_message(msg, ctx)) {
+      ctx.errors.report(self.name(), &e);
+      return Dispatch::Continue;
+    }
     trace!(ctx.logger, "history: Message saved");
+    Dispatch::Continue
+  }
+
+  fn process_edited(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    if !self.search_chats.contains(&msg.chat.id()) {
+      return Dispatch::Continue;
+    }
+
+    if let Some(text) = msg.text_content() {
+      let (chat_id, msg_id) = (msg.chat.id().into(), msg.id.into());
+      match ctx.db.update_msg_text(chat_id, msg_id, &text) {
+        Ok(()) => trace!(ctx.logger, "history: Message edit saved"),
+        Err(e) => {
+          ctx.errors.report(self.name(), &e);
+        }
+      }
+    }
+
+    Dispatch::Continue
+  }
+
+  /// Records every message before anyone gets to consume it
+  fn priority(&self) -> i32 {
+    100
+  }
+
+  fn commands(&self) -> Vec<Command> {
+    vec![enable_chat_command(), enable_me_command()]
   }
 
   fn name(&self) -> &str {
-    "history_saver"
+    Self::NAME
   }
 }
 
+impl Named for Saver {
+  const NAME: &'static str = "history_saver";
+}
+
+fn enable_chat_command() -> Command {
+  Command::new(
+    "enable_search_for_chat",
+    "enable recording history and searching in this group",
+  )
+  .visibility(Visibility::Group)
+  .requires(Role::Admin)
+}
+
+fn enable_me_command() -> Command {
+  Command::new("enable_search_for_me", "enable search for my messages")
+}
+
+fn search_command() -> Command {
+  Command::new("search", "search through history")
+    .usage("[pattern]")
+    .arg("pattern", "text to search for, `*` matches anything")
+}
+
+fn ref_command() -> Command {
+  Command::prefix("ref_", "refer to the n-th search result")
+}
+
 impl Searcher {
   fn beginning_search(&mut self, query_msg: &tg::Message, ctx: &Context) {
-    let pattern = if query_msg.is_cmd("search") {
+    let pattern = if search_command().matches(query_msg) {
       // as /search command arg
       query_msg.cmd_arg()
     } else {
@@ -149,14 +248,23 @@ impl Searcher {
       return;
     }
 
-    self.search = Some(SearchQuery {
-      pattern: pattern.unwrap(),
-      page: 1,
-      total: 0,
-      items: Vec::new(),
-    });
+    {
+      let search = self.search.start_with(query_msg, |token| SearchQuery {
+        token,
+        pattern: pattern.unwrap(),
+        page: 1,
+        total: 0,
+        items: Vec::new(),
+      });
+      if let Err(e) = search.refresh(&ctx.db) {
+        ctx.errors.report_to("history", &e, query_msg);
+        return;
+      }
+    }
 
-    let (reply, pagination_buttons) = self.search_content(&ctx.db);
+    let search = self.search.get(query_msg).unwrap();
+    let (reply, pagination_buttons) =
+      (search.format_reply(), self.pagination(search));
 
     let mut keyboard = tg::InlineKeyboardMarkup::new();
     if !pagination_buttons.is_empty() {
@@ -168,85 +276,93 @@ impl Searcher {
       .spawn(query_msg.text_reply(reply).reply_markup(keyboard).clone());
   }
 
-  fn flip_page(&mut self, action: &str, edit_msg: &tg::Message, ctx: &Context) {
-    if self.search.is_none() {
-      return;
-    }
-
+  fn flip_page(
+    &mut self,
+    page: Page,
+    query: &tg::CallbackQuery,
+    ctx: &Context,
+  ) {
     {
-      let search = self.search.as_mut().unwrap();
-
-      match action {
-        "prev" => search.page -= 1,
-        "next" => search.page += 1,
-        _ => {
-          error!(ctx.logger, "invalid flip page action: {}", action);
-          panic!("invalid flip page action");
+      let search = match self.search.get_by_callback(query) {
+        Some(search) => search,
+        None => {
+          ctx.bot.answer_expired(query);
+          return;
         }
+      };
+
+      match page {
+        Page::Prev if search.page > 1 => search.page -= 1,
+        Page::Prev => {}
+        Page::Next => search.page += 1,
       }
-      if search.page == 0 {
-        search.page = 1;
+
+      if let Err(e) = search.refresh(&ctx.db) {
+        ctx.errors.report_to_query("history", &e, query);
+        return;
       }
     }
 
-    let (reply, pagination_buttons) = self.search_content(&ctx.db);
+    let search = self.search.get(query).unwrap();
+    let (reply, pagination_buttons) =
+      (search.format_reply(), self.pagination(search));
+    let edit_msg = &query.message;
 
     let mut keyboard = tg::InlineKeyboardMarkup::new();
     if !pagination_buttons.is_empty() {
       keyboard.add_row(pagination_buttons);
     };
 
-    let req = ctx
+    ctx
       .bot
-      .send(edit_msg.edit_text(reply).reply_markup(keyboard))
-      .then(|_| ok(()));
-    ctx.handle.spawn(req);
+      .spawn(edit_msg.edit_text(reply).reply_markup(keyboard).clone());
   }
 
-  fn search_content(
-    &mut self,
-    db: &Db,
-  ) -> (String, Vec<tg::InlineKeyboardButton>) {
-    {
-      let users = db
-        .load_conf::<Vec<i64>>("history.search_users")
-        .unwrap_or_default();
-      let search = self.search.as_mut().unwrap();
-      let pattern = &search.pattern;
-      let page = search.page;
-
-      let db_pat: String = pattern.replace("*", "%").replace("'", "''");
-      let (count, result) = db.search_msg(page, &db_pat, &users);
-
-      search.total = count;
-      search.items = result.clone();
+  fn pagination(&self, search: &SearchQuery) -> Vec<tg::InlineKeyboardButton> {
+    let page = search.page;
+
+    let mut pagination = Vec::new();
+
+    if page > 1 {
+      pagination.push(self.callback_button("«", search.token, &Page::Prev));
     }
+    let count_so_far = search.items.len() + (page - 1) * SEARCH_PER;
+    if count_so_far < search.total {
+      pagination.push(self.callback_button("»", search.token, &Page::Next));
+    };
 
-    (self.format_reply(), self.pagination())
+    pagination
   }
+}
 
-  fn format_reply(&self) -> String {
-    let search = self.search.as_ref().unwrap();
+impl SearchQuery {
+  fn refresh(&mut self, db: &Storage) -> DbResult<()> {
+    let (count, result) = search_db(db, self.page, &self.pattern, None)?;
+    self.total = count;
+    self.items = result;
+    Ok(())
+  }
 
+  fn format_reply(&self) -> String {
     let mut reply_buf = String::new();
-    writeln!(&mut reply_buf, "Searching for: {}", search.pattern).ok();
+    writeln!(&mut reply_buf, "Searching for: {}", self.pattern).ok();
 
-    if search.total == 0 {
+    if self.total == 0 {
       return "No matching result found.".into();
     }
 
-    let start = (search.page - 1) * SEARCH_PER + 1;
+    let start = (self.page - 1) * SEARCH_PER + 1;
     writeln!(
       &mut reply_buf,
       "Showing {}-{} of {} search results",
       start,
-      start + search.items.len() - 1,
-      search.total
+      start + self.items.len() - 1,
+      self.total
     )
     .ok();
     writeln!(&mut reply_buf).ok();
 
-    for (i, message) in search.items.iter().enumerate() {
+    for (i, message) in self.items.iter().enumerate() {
       let user = ellipsis(
         &message
           .user_name
@@ -281,22 +397,43 @@ impl Searcher {
 
     reply_buf
   }
+}
 
-  fn pagination(&self) -> Vec<tg::InlineKeyboardButton> {
-    let search = self.search.as_ref().unwrap();
-    let page = search.page;
-
-    let mut pagination = Vec::new();
-
-    if page > 1 {
-      pagination.push(self.callback_button("«", "prev_page"));
+impl Searcher {
+  /// Inline results can be sent to any chat, so they only show the
+  /// user's own messages
+  fn answer_inline(&self, query: &tg::InlineQuery, ctx: &Context) {
+    let pattern = inline_pattern(&query.query).unwrap_or_default();
+    if pattern.is_empty() {
+      ctx.bot.answer_inline(query, vec![]);
+      return;
     }
-    let count_so_far = search.items.len() + (page - 1) * SEARCH_PER;
-    if count_so_far < search.total {
-      pagination.push(self.callback_button("»", "next_page"));
-    };
 
-    pagination
+    let author: i64 = query.from.id.into();
+    let items = match search_db(&ctx.db, 1, pattern, Some(author)) {
+      Ok((_, items)) => items,
+      Err(e) => {
+        ctx.errors.report("history", &e);
+        vec![]
+      }
+    };
+    let results = items
+      .into_iter()
+      .map(|message| {
+        let text = message.text.unwrap_or_default();
+        let user = message.user_name.unwrap_or_else(|| "someone".into());
+        let md_text = format!(
+          "{}\n\u{2014} {}, {}",
+          escape_markdown(&text),
+          escape_markdown(&user),
+          format_time(message.created_at)
+        );
+        let id = format!("{}.{}", message.chat_id, message.msg_id);
+        inline_article(id, ellipsis(&text, 40), md_text)
+      })
+      .collect();
+
+    ctx.bot.answer_inline(query, results);
   }
 
   fn try_refer_result(
@@ -305,8 +442,8 @@ impl Searcher {
     msg: &tg::Message,
     ctx: &Context,
   ) {
-    let ref_msg = match self.search {
-      Some(ref search) => search.items.get(nth_result as usize),
+    let ref_msg = match self.search.get(msg) {
+      Some(search) => search.items.get(nth_result as usize),
       None => None,
     };
 
@@ -319,61 +456,223 @@ impl Searcher {
     let mut req =
       tg::SendMessage::new(tg::ChatId::from(ref_msg.chat_id), "Here you go");
     req.reply_to(tg::MessageId::from(ref_msg.msg_id));
-    let bot = ctx.bot.clone();
+    let errors = ctx.errors.clone();
     let msg = msg.clone();
     let future = ctx.bot.send(req).then(move |e| {
-      match e {
-        Ok(..) => {}
-        Err(e) => {
-          bot.reply_to(msg, format!("Unable to refer to message: {:}", e))
-        }
+      if let Err(e) = e {
+        errors.report_to("history", &e, &msg);
       }
       Ok(())
     });
-    ctx.handle.spawn(future);
+    ctx.spawn(self.name(), future);
   }
 }
 
 impl BotExtension for Searcher {
   fn init(_: &Context) -> Self {
-    Default::default()
+    Searcher {
+      search: SessionStore::new(Duration::minutes(30)),
+    }
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
     lazy_static! {
       static ref RE: Regex = Regex::new(r"^/ref_(\d+)(@\w+bot)?$").unwrap();
     };
-    if msg.is_cmd("search") {
+    if search_command().matches(msg) {
       self.beginning_search(msg, ctx);
-      return;
+      return Dispatch::Consumed;
     }
 
-    if msg.cmd_name().map(|x| x.starts_with("ref")) == Some(true) {
+    if ref_command().matches(msg) {
       let text = msg.text_content().unwrap_or_default();
       let match_reference = RE.captures(&text);
       if let Some(caps) = match_reference {
         let n = caps.get(1).unwrap().as_str().parse::<i32>().unwrap();
         self.try_refer_result(n - 1, msg, ctx);
       }
-      return;
+      return Dispatch::Consumed;
     }
 
     if msg.is_force_reply(EMPTY_PATTERN_PROMPT) {
       self.beginning_search(msg, ctx);
+      return Dispatch::Consumed;
+    }
+
+    Dispatch::Continue
+  }
+
+  fn process_inline_query(
+    &mut self,
+    query: &tg::InlineQuery,
+    ctx: &Context,
+  ) -> Dispatch {
+    if inline_pattern(&query.query).is_none() {
+      return Dispatch::Continue;
     }
+
+    self.answer_inline(query, ctx);
+    Dispatch::Consumed
   }
 
   fn process_callback(&mut self, callback: &tg::CallbackQuery, ctx: &Context) {
-    let edit_msg = &callback.message;
-    match callback.key() {
-      Some("prev_page") => self.flip_page("prev", edit_msg, ctx),
-      Some("next_page") => self.flip_page("next", edit_msg, ctx),
-      Some(_) => {}
-      None => {}
+    match callback.payload() {
+      Some(page) => self.flip_page(page, callback, ctx),
+      None => ctx.bot.answer_expired(callback),
     }
   }
 
+  fn commands(&self) -> Vec<Command> {
+    vec![search_command(), ref_command()]
+  }
+
   fn name(&self) -> &str {
-    "history_searcher"
+    Self::NAME
+  }
+}
+
+impl Named for Searcher {
+  const NAME: &'static str = "history_searcher";
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_search_pagination() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Saver>().plug::<Searcher>();
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_chat");
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_me");
+    for i in 0..12 {
+      h.send_text(GROUP_CHAT, USER_ID, &format!("note {}", i));
+    }
+    h.take_requests();
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/search note");
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    let result = &reqs[0];
+    assert!(result.text().unwrap().contains("Showing 1-10 of 12"));
+    assert_eq!(result.buttons().len(), 1);
+    let next = result.button("»").unwrap();
+
+    h.click(result, USER_ID, &next);
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].method, "editMessageText");
+    assert!(reqs[0].text().unwrap().contains("Showing 11-12 of 12"));
+    assert!(reqs[0].button("«").is_some());
+    assert!(reqs[0].button("»").is_none());
+
+    // a new search expires the panel of the previous one
+    h.send_text(PRIVATE_CHAT, USER_ID, "/search note");
+    h.take_requests();
+    h.click(result, USER_ID, &next);
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].method, "answerCallbackQuery");
+    assert_eq!(reqs[0].body["text"], "This panel has expired");
+  }
+
+  #[test]
+  fn test_search_sees_edits() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Saver>().plug::<Searcher>();
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_chat");
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_me");
+    let id = h.send_text(GROUP_CHAT, USER_ID, "meet at the libary");
+    h.edit_text(id, GROUP_CHAT, USER_ID, "meet at the library");
+    h.take_requests();
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/search library");
+    let reqs = h.take_requests();
+    assert!(reqs[0].text().unwrap().contains("Showing 1-1 of 1"));
+    assert!(reqs[0].text().unwrap().contains("meet at the library"));
+  }
+
+  #[test]
+  fn test_search_special_characters() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Saver>().plug::<Searcher>();
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_chat");
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_me");
+    h.send_text(GROUP_CHAT, USER_ID, "100% sure");
+    h.send_text(GROUP_CHAT, USER_ID, "it's done'); --");
+    h.send_text(GROUP_CHAT, USER_ID, "snake_case");
+    h.take_requests();
+
+    let mut search = |pattern: &str| {
+      h.send_text(PRIVATE_CHAT, USER_ID, &format!("/search {}", pattern));
+      h.take_requests()[0].text().unwrap().to_string()
+    };
+    assert!(search("%").contains("Showing 1-1 of 1"));
+    assert!(search("'); --").contains("it's done'); --"));
+    assert!(search("_").contains("snake_case"));
+    assert!(search("100*sure").contains("Showing 1-1 of 1"));
+    assert_eq!(search("%%"), "No matching result found.");
+  }
+
+  #[test]
+  fn test_inline_search() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Saver>().plug::<Searcher>();
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_chat");
+    h.send_text(GROUP_CHAT, USER_ID, "/enable_search_for_me");
+    h.send_text(GROUP_CHAT, USER_ID, "nothing beats cake");
+    h.take_requests();
+
+    h.inline_query(USER_ID, "search cake");
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].method, "answerInlineQuery");
+    let results = reqs[0].body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["title"], "nothing beats cake");
+
+    // not a search, left to the others
+    h.inline_query(USER_ID, "searching beats");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["results"], json!([]));
+
+    // only their own messages, which they did not opt in
+    let other = tg::ChatId::from(OTHER_USER_ID);
+    h.ctx.guard.add_safe_chat(other);
+    h.inline_query(OTHER_USER_ID, "search cake");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["results"], json!([]));
+    h.ctx.guard.remove_safe_chat(other);
+
+    // not trusted, answered with nothing
+    h.inline_query(OTHER_USER_ID, "search cake");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["results"], json!([]));
+  }
+
+  #[test]
+  fn test_inline_pattern() {
+    assert_eq!(inline_pattern(" search  cake "), Some("cake"));
+    assert_eq!(inline_pattern("search"), Some(""));
+    assert_eq!(inline_pattern("searching cake"), None);
+    assert_eq!(inline_pattern("cake"), None);
+  }
+
+  #[test]
+  fn test_members_cannot_enable_search() {
+    let mut h = Harness::new();
+    h.plug::<Saver>();
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/enable_search_for_chat");
+    let reqs = h.take_requests();
+    assert_eq!(
+      reqs[0].text(),
+      Some("You need to be admin to use /enable_search_for_chat")
+    );
+
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "secret plans");
+    assert_eq!(h.ctx.db.search_chats().unwrap(), Vec::<i64>::new());
   }
 }
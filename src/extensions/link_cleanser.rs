@@ -157,18 +157,25 @@ impl BotExtension for LinkCleanser {
     Self
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
     let text = match msg.text_content() {
-      None => return,
+      None => return Dispatch::Continue,
       Some(t) => t,
     };
 
     if let Some(clean_url) = Self::text_pipeline(&text) {
       ctx.bot.reply_to(msg, clean_url);
     }
+
+    // the message may still be meaningful to others
+    Dispatch::Continue
   }
 
   fn name(&self) -> &str {
-    "link_cleanser"
+    Self::NAME
   }
 }
+
+impl Named for LinkCleanser {
+  const NAME: &'static str = "link_cleanser";
+}
@@ -1,35 +1,505 @@
-use common::*;
+use crate::common::*;
 
+use serde_json::Value as JsonValue;
+
+/// Admin console for the `config` table and extension reports
 #[derive(Debug, Clone, Default)]
 pub struct Manager {}
 
 mod config {
-    pub fn format_config_item(key: &str, value: &str) -> String {
-        format!("Key: [{}]\nValue:\n{}\n", key, value)
+  use super::*;
+  use crate::extensions::*;
+
+  use std::collections::BTreeMap;
+  #[cfg(feature = "yeelight")]
+  use std::net::SocketAddr;
+
+  pub fn format_config_item(key: &str, value: &str) -> String {
+    format!("Key: [{}]\nValue:\n{}\n", key, value)
+  }
+
+  /// The context extensions are always loaded
+  pub const CONTEXT: &str = "the bot";
+
+  /// A config item `/set_conf` can change
+  pub struct Setting {
+    pub key: String,
+    /// The extension reading it, `CONTEXT` for the context extensions
+    pub owner: &'static str,
+    /// As shown by `/get_conf`, `None` if nothing is saved
+    pub get: fn(&str, &Context) -> Result<Option<String>>,
+    /// Check that the value is what the owner expects, so a typo does
+    /// not silently reset it to the default, then save it
+    pub set: fn(&str, &JsonValue, &Context) -> Result<()>,
+    /// Saved in the `config` table, the others have tables of their own
+    pub in_config: bool,
+  }
+
+  impl Setting {
+    fn blob<T: Versioned>(key: String, owner: &'static str) -> Self {
+      Setting {
+        key,
+        owner,
+        get: get_blob,
+        set: set_blob::<T>,
+        in_config: true,
+      }
+    }
+  }
+
+  pub fn settings() -> Vec<Setting> {
+    let mut settings = vec![
+      Setting::blob::<afk::Afk>(afk::Afk::NAME.into(), afk::Afk::NAME),
+      Setting {
+        key: weather::LOCATIONS_KEY.into(),
+        owner: weather::Weather::NAME,
+        get: get_locations,
+        set: set_locations,
+        in_config: false,
+      },
+      Setting::blob::<SafetyGuard>(SafetyGuard::key(), CONTEXT),
+      Setting::blob::<NameMap>(NameMap::key(), CONTEXT),
+      Setting::blob::<ExtSwitch>(ExtSwitch::key(), CONTEXT),
+    ];
+    #[cfg(feature = "music")]
+    settings.push(Setting::blob::<music::Music>(
+      music::Music::NAME.into(),
+      music::Music::NAME,
+    ));
+    #[cfg(feature = "yeelight")]
+    settings.extend(vec![
+      Setting {
+        key: yeelight::DEVICES_KEY.into(),
+        owner: yeelight::Yeelight::NAME,
+        get: get_devices,
+        set: set_devices,
+        in_config: false,
+      },
+      Setting {
+        key: yeelight::MODES_KEY.into(),
+        owner: yeelight::Yeelight::NAME,
+        get: get_modes,
+        set: set_modes,
+        in_config: false,
+      },
+    ]);
+    settings
+  }
+
+  /// `None` for items nothing reads anymore
+  pub fn setting(key: &str) -> Option<Setting> {
+    settings().into_iter().find(|setting| setting.key == key)
+  }
+
+  fn parse<T: DeserializeOwned>(value: &JsonValue) -> Result<T> {
+    serde_json::from_value(value.clone())
+      .map_err(|e| format!("Invalid value: {}", e).into())
+  }
+
+  fn pretty<T: Serialize>(value: &T) -> Result<Option<String>> {
+    let text =
+      serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    Ok(Some(text))
+  }
+
+  fn get_blob(key: &str, ctx: &Context) -> Result<Option<String>> {
+    Ok(ctx.db.raw_conf(key)?)
+  }
+
+  fn set_blob<T: Versioned>(
+    key: &str,
+    value: &JsonValue,
+    ctx: &Context,
+  ) -> Result<()> {
+    parse::<T>(value)?;
+    ctx.db.save_json(key, T::version(), value)?;
+    Ok(())
+  }
+
+  fn get_locations(_: &str, ctx: &Context) -> Result<Option<String>> {
+    let locations: BTreeMap<_, _> = ctx.db.locations()?.into_iter().collect();
+    pretty(&locations)
+  }
+
+  /// An object of city to `long,lat`
+  fn set_locations(_: &str, value: &JsonValue, ctx: &Context) -> Result<()> {
+    let locations: BTreeMap<String, String> = parse(value)?;
+    for long_lat in locations.values() {
+      let coords: Vec<_> = long_lat.split(',').map(str::parse::<f64>).collect();
+      if coords.len() != 2 || coords.iter().any(|x| x.is_err()) {
+        let text = format!("Invalid value: {} is not long,lat", long_lat);
+        return Err(text.into());
+      }
+    }
+    for (city, _) in ctx.db.locations()? {
+      if !locations.contains_key(&city) {
+        ctx.db.delete_location(&city)?;
+      }
     }
+    for (city, long_lat) in &locations {
+      ctx.db.save_location(city, long_lat)?;
+    }
+    Ok(())
+  }
+
+  #[cfg(feature = "yeelight")]
+  fn get_devices(_: &str, ctx: &Context) -> Result<Option<String>> {
+    pretty(&ctx.db.yeelight_devices()?)
+  }
+
+  /// An array of `ip:port`, the first one is controlled
+  #[cfg(feature = "yeelight")]
+  fn set_devices(_: &str, value: &JsonValue, ctx: &Context) -> Result<()> {
+    let devices: Vec<SocketAddr> = parse(value)?;
+    for addr in ctx.db.yeelight_devices()? {
+      ctx.db.delete_yeelight_device(&addr)?;
+    }
+    for addr in devices {
+      ctx.db.add_yeelight_device(&addr.to_string())?;
+    }
+    Ok(())
+  }
+
+  #[cfg(feature = "yeelight")]
+  fn get_modes(_: &str, ctx: &Context) -> Result<Option<String>> {
+    let modes: Vec<_> = ctx
+      .db
+      .yeelight_modes()?
+      .into_iter()
+      .map(|(name, _, request)| {
+        let request = serde_json::from_str(&request)
+          .unwrap_or_else(|_| JsonValue::from(request));
+        json!([name, request])
+      })
+      .collect();
+    pretty(&modes)
+  }
+
+  /// An array of `[name, [query, ...]]`, in the order of the buttons
+  #[cfg(feature = "yeelight")]
+  fn set_modes(_: &str, value: &JsonValue, ctx: &Context) -> Result<()> {
+    let modes: Vec<(String, yeelight::Request)> = parse(value)?;
+    for (i, (name, _)) in modes.iter().enumerate() {
+      if modes[..i].iter().any(|(other, _)| other == name) {
+        return Err(format!("Invalid value: {} is there twice", name).into());
+      }
+    }
+    for (name, _, _) in ctx.db.yeelight_modes()? {
+      ctx.db.delete_yeelight_mode(&name)?;
+    }
+    for (name, request) in &modes {
+      let request =
+        serde_json::to_string(request).map_err(|e| e.to_string())?;
+      let version = yeelight::Request::version();
+      ctx.db.insert_yeelight_mode(name, version, &request)?;
+    }
+    Ok(())
+  }
 }
 
-impl BotExtension for Manager {
-    fn init(_: &Context) -> Self {
-        Default::default()
+/// Splits `/cmd key rest` into key and rest, keeping newlines in rest
+fn key_and_rest(msg: &tg::Message) -> (Option<String>, Option<String>) {
+  let text = msg.text_content().unwrap_or_default();
+  let mut parts = text.trim().splitn(2, char::is_whitespace).skip(1);
+  let args = parts.next().unwrap_or("").trim_start();
+  let mut args = args.splitn(2, char::is_whitespace);
+  let key = args.next().filter(|x| !x.is_empty()).map(Into::into);
+  let rest = args.next().map(str::trim).filter(|x| !x.is_empty());
+  (key, rest.map(Into::into))
+}
+
+impl Manager {
+  fn list_conf(&self, msg: &tg::Message, ctx: &Context) {
+    let confs = match ctx.db.list_conf() {
+      Ok(confs) => confs,
+      Err(e) => return ctx.bot.reply_to(msg, e.to_string()),
+    };
+    let tables: Vec<_> = config::settings()
+      .into_iter()
+      .filter(|setting| !setting.in_config)
+      .collect();
+    let mut buf = String::new();
+    let count = confs.len() + tables.len();
+    writeln!(&mut buf, "Listing {} config items\n---", count).ok();
+    for (key, value) in confs {
+      writeln!(&mut buf, "{} ({} bytes)", key, value.len()).ok();
+    }
+    for setting in tables {
+      writeln!(&mut buf, "{} (table)", setting.key).ok();
+    }
+    ctx.bot.reply_to(msg, buf);
+  }
+
+  fn get_conf(&self, msg: &tg::Message, ctx: &Context) {
+    let key = match key_and_rest(msg) {
+      (Some(key), _) => key,
+      _ => return ctx.bot.reply_to(msg, get_conf_command().usage_text()),
+    };
+    let value = match config::setting(&key) {
+      Some(setting) => (setting.get)(&key, ctx),
+      // left over items are shown too, so they can be cleaned up
+      None => ctx.db.raw_conf(&key).map_err(Into::into),
+    };
+    match value {
+      Ok(Some(value)) => {
+        let text = config::format_config_item(&key, &value);
+        ctx.bot.reply_to(msg, ellipsis(&text, 4000))
+      }
+      Ok(None) => ctx.bot.reply_to(msg, format!("No config item: {}", key)),
+      Err(e) => ctx.bot.reply_to(msg, e.to_string()),
+    }
+  }
+
+  /// Save a checked value and have its owner reload it
+  fn set_conf(&self, msg: &tg::Message, ctx: &Context) {
+    let (key, value) = match key_and_rest(msg) {
+      (Some(key), Some(value)) => (key, value),
+      _ => return ctx.bot.reply_to(msg, set_conf_command().help_detail()),
+    };
+    let setting = match config::setting(&key) {
+      Some(setting) => setting,
+      None => {
+        let text = format!("Not saved: Nothing reads {}", key);
+        return ctx.bot.reply_to(msg, text);
+      }
+    };
+
+    let saved = serde_json::from_str::<JsonValue>(&value)
+      .map_err(|e| FondbotError::from(format!("Invalid JSON: {}", e)))
+      .and_then(|value| (setting.set)(&key, &value, ctx));
+    if let Err(e) = saved {
+      return ctx.bot.reply_to(msg, format!("Not saved: {}", e));
     }
 
-    fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-        use std::fmt::Write;
-        if msg.is_cmd("list_conf") {
-            let confs = ctx.db.list_conf();
-            let mut buf = String::new();
-            writeln!(&mut buf, "Listing {} config items\n---", confs.len()).ok();
-            writeln!(&mut buf, "{}",
-                confs.into_iter()
-                     .map(|(k,v)| config::format_config_item(&k, &v))
-                     .collect::<Vec<String>>()
-                     .join("---\n")).ok();
-            ctx.bot.reply_to(msg, &buf);
-        }
+    ctx.reload_conf(&key);
+    let owner = setting.owner;
+    let text = if owner == config::CONTEXT || ctx.has_ext(owner) {
+      format!("Saved {}, reloaded by {}", key, owner)
+    } else {
+      format!("Saved {}, {} reads it once plugged in", key, owner)
+    };
+    ctx.bot.reply_to(msg, text);
+  }
+
+  fn del_conf(&self, msg: &tg::Message, ctx: &Context) {
+    let key = match key_and_rest(msg) {
+      (Some(key), _) => key,
+      _ => return ctx.bot.reply_to(msg, del_conf_command().usage_text()),
+    };
+    if let Some(ref setting) = config::setting(&key) {
+      if !setting.in_config {
+        let text = format!("Not deleted: {} is a table, use /set_conf", key);
+        return ctx.bot.reply_to(msg, text);
+      }
     }
+    let text = match ctx.db.delete_conf(&key) {
+      Ok(true) => {
+        // the owner starts over from its default
+        ctx.reload_conf(&key);
+        format!("Deleted {}", key)
+      }
+      Ok(false) => format!("No config item: {}", key),
+      Err(e) => e.to_string(),
+    };
+    ctx.bot.reply_to(msg, text);
+  }
 
-    fn name(&self) -> &str {
-        "manager"
+  fn reports(&self, msg: &tg::Message, ctx: &Context) {
+    let filter = msg.cmd_arg().unwrap_or_default();
+    let filter = filter.trim();
+    let mut buf = String::new();
+    for (name, report) in ctx.reports() {
+      if !filter.is_empty() && name != filter {
+        continue;
+      }
+      writeln!(&mut buf, "[{}]\n{}\n", name, report).ok();
+    }
+    if buf.is_empty() {
+      buf = format!("Unknown extension: {}", filter);
     }
+    ctx.bot.reply_to(msg, buf);
+  }
+}
+
+impl BotExtension for Manager {
+  fn init(_: &Context) -> Self {
+    Default::default()
+  }
+
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    if list_conf_command().matches(msg) {
+      self.list_conf(msg, ctx);
+    } else if get_conf_command().matches(msg) {
+      self.get_conf(msg, ctx);
+    } else if set_conf_command().matches(msg) {
+      self.set_conf(msg, ctx);
+    } else if del_conf_command().matches(msg) {
+      self.del_conf(msg, ctx);
+    } else if reports_command().matches(msg) {
+      self.reports(msg, ctx);
+    } else {
+      return Dispatch::Continue;
+    }
+    Dispatch::Consumed
+  }
+
+  fn commands(&self) -> Vec<Command> {
+    vec![
+      list_conf_command(),
+      get_conf_command(),
+      set_conf_command(),
+      del_conf_command(),
+      reports_command(),
+    ]
+  }
+
+  fn name(&self) -> &str {
+    Self::NAME
+  }
+}
+
+impl Named for Manager {
+  const NAME: &'static str = "manager";
+}
+
+fn list_conf_command() -> Command {
+  Command::new("list_conf", "list config items").requires(Role::Admin)
+}
+
+fn get_conf_command() -> Command {
+  Command::new("get_conf", "show a config item")
+    .usage("<key>")
+    .requires(Role::Admin)
+}
+
+fn set_conf_command() -> Command {
+  Command::new("set_conf", "replace a config item")
+    .usage("<key> <json>")
+    .arg("key", "as shown by /list_conf")
+    .arg("json", "new value, checked against what the owner expects")
+    .requires(Role::Admin)
+}
+
+fn del_conf_command() -> Command {
+  Command::new("del_conf", "delete a config item")
+    .usage("<key>")
+    .requires(Role::Admin)
+}
+
+fn reports_command() -> Command {
+  Command::new("reports", "show extension reports")
+    .usage("[extension]")
+    .requires(Role::Admin)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_set_conf_is_validated() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Manager>();
+
+    h.send_text(PRIVATE_CHAT, OTHER_USER_ID, "/list_conf");
+    let reqs = h.take_requests();
+    assert_eq!(
+      reqs[0].text(),
+      Some("You need to be admin to use /list_conf")
+    );
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/set_conf afk {\"a\": 1");
+    let reqs = h.take_requests();
+    assert!(reqs[0]
+      .text()
+      .unwrap()
+      .starts_with("Not saved: Invalid JSON"));
+
+    let bad = "/set_conf afk {\"state\": [1]}";
+    h.send_text(PRIVATE_CHAT, USER_ID, bad);
+    let reqs = h.take_requests();
+    assert!(reqs[0]
+      .text()
+      .unwrap()
+      .starts_with("Not saved: Invalid value"));
+
+    let good = "/set_conf afk\n{\"state\": null}";
+    h.send_text(PRIVATE_CHAT, USER_ID, good);
+    h.send_text(PRIVATE_CHAT, USER_ID, "/get_conf afk");
+    let reqs = h.take_requests();
+    assert_eq!(
+      reqs[0].text(),
+      Some("Saved afk, afk reads it once plugged in")
+    );
+    assert!(reqs[1].text().unwrap().contains("\"state\": null"));
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/del_conf afk");
+    h.send_text(PRIVATE_CHAT, USER_ID, "/get_conf afk");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text(), Some("Deleted afk"));
+    assert_eq!(reqs[1].text(), Some("No config item: afk"));
+  }
+
+  #[test]
+  fn test_set_conf_takes_effect() {
+    use crate::extensions::afk::Afk;
+    use crate::extensions::weather::Weather;
+
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Manager>().plug::<Afk>().plug::<Weather>();
+
+    let afk = json!({
+      "state": {
+        "afk_at": "2020-01-01T00:00:00+00:00",
+        "reason": "lunch",
+        "last_notify": "2020-01-01T00:00:00+00:00",
+        "user_id": USER_ID,
+        "user_name": "Alice",
+      }
+    });
+    h.send_text(PRIVATE_CHAT, USER_ID, &format!("/set_conf afk {}", afk));
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "anyone here?");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text(), Some("Saved afk, reloaded by afk"));
+    assert!(reqs[1].text().unwrap().starts_with("Alice is *AFK* now"));
+
+    let names = "/set_conf exts.name-map {\"names\": {\"101\": \"Bob\"}}";
+    h.send_text(PRIVATE_CHAT, USER_ID, names);
+    let reqs = h.take_requests();
+    assert_eq!(
+      reqs[0].text(),
+      Some("Saved exts.name-map, reloaded by the bot")
+    );
+    let bob = tg::UserId::from(OTHER_USER_ID);
+    assert_eq!(h.ctx.names.get(&bob), "Bob");
+
+    let bad = "/set_conf weather.locations {\"Tokyo\": \"east\"}";
+    let good = "/set_conf weather.locations {\"Tokyo\": \"139.69,35.68\"}";
+    h.ctx.db.save_location("Berlin", "13.40,52.52").unwrap();
+    h.send_text(PRIVATE_CHAT, USER_ID, bad);
+    h.send_text(PRIVATE_CHAT, USER_ID, good);
+    h.send_text(PRIVATE_CHAT, USER_ID, "/del_conf weather.locations");
+    let texts: Vec<_> = h
+      .take_requests()
+      .iter()
+      .map(|req| req.text().unwrap_or_default().to_string())
+      .collect();
+    assert_eq!(
+      texts,
+      vec![
+        "Not saved: Invalid value: east is not long,lat",
+        "Saved weather.locations, reloaded by weather",
+        "Not deleted: weather.locations is a table, use /set_conf",
+      ]
+    );
+    let tokyo = ("Tokyo".to_string(), "139.69,35.68".to_string());
+    assert_eq!(h.ctx.db.locations().unwrap(), vec![tokyo]);
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/set_conf weather {}");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text(), Some("Not saved: Nothing reads weather"));
+  }
 }
@@ -1,27 +1,150 @@
 pub mod afk;
 // pub mod tracker;
 pub mod weather;
-// pub mod manager;
+pub mod manager;
 pub mod history;
+#[cfg(feature = "music")]
 pub mod music;
 pub mod reminder;
+#[cfg(feature = "yeelight")]
 pub mod yeelight;
 pub mod link_cleanser;
+pub mod command;
+pub mod callback;
+pub mod registry;
+
+pub use self::callback::{new_token, CallbackData, Token};
+pub use self::command::{Command, Visibility};
 
 use crate::common::*;
 
+/// Outcome of an extension processing a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+  /// Not handled, or only observed; pass it on to the next extension
+  Continue,
+  /// Handled by this extension, the rest of the chain is skipped
+  Consumed,
+  /// Not handled, but the rest of the chain must not see it either
+  Stop,
+}
+
+#[derive(Debug, Clone)]
+pub enum ChatMemberChange {
+  Joined(Vec<tg::User>),
+  Left(tg::User),
+}
+
+impl ChatMemberChange {
+  pub fn from_message(msg: &tg::Message) -> Option<Self> {
+    match msg.kind {
+      tg::MessageKind::NewChatMembers { ref data, .. } => {
+        Some(ChatMemberChange::Joined(data.clone()))
+      }
+      tg::MessageKind::LeftChatMember { ref data, .. } => {
+        Some(ChatMemberChange::Left(data.clone()))
+      }
+      _ => None,
+    }
+  }
+}
+
+/// The name an extension is plugged, configured and switched by, kept
+/// apart from `BotExtension` so that one stays object safe
+pub trait Named {
+  const NAME: &'static str;
+}
+
 pub trait BotExtension {
   fn init(ctx: &Context) -> Self
   where
     Self: Sized;
 
-  fn process(&mut self, _message: &tg::Message, _ctx: &Context) {}
+  fn process(&mut self, _message: &tg::Message, _ctx: &Context) -> Dispatch {
+    Dispatch::Continue
+  }
+
+  /// Edited messages; commands corrected by editing are re-run
+  fn process_edited(
+    &mut self,
+    message: &tg::Message,
+    ctx: &Context,
+  ) -> Dispatch {
+    if message.cmd_name().is_some() {
+      self.process(message, ctx)
+    } else {
+      Dispatch::Continue
+    }
+  }
+
+  /// New and edited posts in channels the bot administers
+  fn process_channel_post(
+    &mut self,
+    _post: &tg::ChannelPost,
+    _ctx: &Context,
+  ) -> Dispatch {
+    Dispatch::Continue
+  }
+
+  /// Inline queries (`@bot <query>`), possibly from chats the bot is
+  /// not a member of
+  fn process_inline_query(
+    &mut self,
+    _query: &tg::InlineQuery,
+    _ctx: &Context,
+  ) -> Dispatch {
+    Dispatch::Continue
+  }
+
+  /// Users joining or leaving a chat
+  fn process_chat_member(
+    &mut self,
+    _message: &tg::Message,
+    _change: &ChatMemberChange,
+    _ctx: &Context,
+  ) {
+  }
+
   fn process_callback(&mut self, _query: &tg::CallbackQuery, _ctx: &Context) {}
+
+  /// Least role allowed to press this extension's buttons
+  fn callback_role(&self) -> Role {
+    Role::Member
+  }
+
+  /// Run a job this extension scheduled with `ctx.scheduler()`
+  fn process_job(&mut self, _job: &Job, _ctx: &Context) {}
+
+  /// Read the config item `key` again if it is one of this extension's,
+  /// after `/set_conf` changed it behind its back
+  fn reload(&mut self, _key: &str, _ctx: &Context) {}
+
+  /// `Named::NAME` for the plugins in the registry
   fn name(&self) -> &str;
 
-  fn callback_button(&self, text: &str, key: &str) -> tg::InlineKeyboardButton {
-    let callback = format!("{}.{}", self.name(), key);
-    tg::InlineKeyboardButton::callback(text, callback)
+  /// Extensions with higher priority process messages first
+  fn priority(&self) -> i32 {
+    0
+  }
+
+  /// Commands handled by this extension, used for `/help` and
+  /// `setMyCommands`
+  fn commands(&self) -> Vec<Command> {
+    vec![]
+  }
+
+  /// Button whose payload is routed back to this extension's
+  /// `process_callback` along with the token
+  fn callback_button<T: Serialize>(
+    &self,
+    text: &str,
+    token: Token,
+    payload: &T,
+  ) -> tg::InlineKeyboardButton
+  where
+    Self: Sized,
+  {
+    callback::callback_button(self.name(), text, token, payload)
   }
 
   /// Report current status
@@ -53,6 +176,7 @@ pub trait InteractiveBuilder {
 
 #[derive(Fail, Debug)]
 pub enum ExtensionError {
+  #[cfg(feature = "music")]
   #[fail(display = "Error in `music`: {}", _0)]
   Music(#[cause] music::MusicError),
 }
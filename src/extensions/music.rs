@@ -1,4 +1,5 @@
 use crate::common::*;
+use crate::services::metrics;
 
 use curl::easy::Easy;
 use serde_json::Value;
@@ -23,6 +24,9 @@ pub enum MusicError {
 
   #[fail(display = "Invalid song detail for id={}", id)]
   InvalidSongDetail { id: u64 },
+
+  #[fail(display = "Failed downloading song: {}", _0)]
+  Download(#[cause] curl::Error),
 }
 
 fn parse_song_id(url: &str) -> Option<u64> {
@@ -52,15 +56,21 @@ fn parse_song_id(url: &str) -> Option<u64> {
 }
 
 impl AudioDetail {
-  #[allow(dead_code)]
   fn url(&self) -> String {
     format!("https://music.163.com/#/song?id={}", self.id)
   }
 
+  fn describe(&self) -> String {
+    match self.performer {
+      Some(ref performer) => format!("{} - {}", self.title, performer),
+      None => self.title.clone(),
+    }
+  }
+
   fn from_id(id: u64) -> impl Future<Item = Self, Error = MusicError> {
     let api_url =
       format!("https://music.163.com/api/song/detail/?ids=[{}]", id);
-    let song = request(&api_url)
+    let song = metrics::track("netease", request::<Value>(&api_url))
       .map_err(MusicError::Request)
       .and_then(move |value: Value| {
         let song = &value["songs"][0];
@@ -86,42 +96,98 @@ impl AudioDetail {
   }
 }
 
+impl Versioned for Music {}
+
 impl BotExtension for Music {
   fn init(ctx: &Context) -> Self
   where
     Self: Sized,
   {
     ctx
-      .db
-      .load_conf("music")
+      .or_report("music", ctx.db.load_conf("music"))
       .unwrap_or(Music { auto_parse: true })
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-    if msg.is_cmd("music") && !self.auto_parse {
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    if music_command().matches(msg) && !self.auto_parse {
       self.handle_message(msg.cmd_arg(), msg, ctx)
     } else {
       self.handle_message(msg.text_content(), msg, ctx)
     }
   }
+  fn process_inline_query(
+    &mut self,
+    query: &tg::InlineQuery,
+    ctx: &Context,
+  ) -> Dispatch {
+    let id = match parse_song_id(query.query.trim()) {
+      Some(id) => id,
+      None => return Dispatch::Continue,
+    };
+
+    let bot = ctx.bot.clone();
+    let errors = ctx.errors.clone();
+    let query = query.clone();
+    let future = AudioDetail::from_id(id).then(move |detail| {
+      let results = match detail {
+        Ok(detail) => {
+          let title = detail.describe();
+          let md_text =
+            format!("[{}]({})", escape_markdown(&title), detail.url());
+          vec![inline_article(id.to_string(), title, md_text)]
+        }
+        Err(e) => {
+          errors.report("music", &e);
+          vec![]
+        }
+      };
+      bot.answer_inline(&query, results);
+      ok(())
+    });
+    ctx.spawn(self.name(), future);
+
+    Dispatch::Consumed
+  }
+
+  fn commands(&self) -> Vec<Command> {
+    vec![music_command()]
+  }
+
+  fn reload(&mut self, key: &str, ctx: &Context) {
+    if key == Self::NAME {
+      *self = Self::init(ctx);
+    }
+  }
+
   fn name(&self) -> &str {
-    "music"
+    Self::NAME
   }
 }
 
+impl Named for Music {
+  const NAME: &'static str = "music";
+}
+
+fn music_command() -> Command {
+  Command::new("music", "send the song of a netease link")
+    .usage("<url>")
+    .arg("url", "netease music link of the song")
+}
+
 impl Music {
   fn handle_message(
     &self,
     url: Option<String>,
     msg: &tg::Message,
     ctx: &Context,
-  ) {
+  ) -> Dispatch {
     let id = url.and_then(|x| parse_song_id(&x));
     if id.is_none() {
       if !self.auto_parse {
         ctx.bot.spawn(reply(msg, "Invalid netease url"));
+        return Dispatch::Consumed;
       }
-      return;
+      return Dispatch::Continue;
     }
     let id = id.unwrap();
 
@@ -134,7 +200,9 @@ impl Music {
       .map_err(ExtensionError::Music)
       .map_err(FondbotError::Extension);
     let bot = ctx.bot.clone();
+    let errors = ctx.errors.clone();
     let msg = msg.clone();
+    let orig_msg = msg.clone();
 
     let download_action = bot
       .send(msg.chat.chat_action(tg::ChatAction::RecordAudio))
@@ -158,9 +226,13 @@ impl Music {
         }
         bot.send(req).from_err()
       })
-      .map_err(|_| ());
+      .map(|_| ())
+      .map_err(move |e| {
+        errors.report_to("music", &e, &orig_msg);
+      });
 
-    ctx.handle.spawn(upload_fut);
+    ctx.spawn(self.name(), upload_fut);
+    Dispatch::Consumed
   }
 
   fn download(id: u64) -> impl Future<Item = Vec<u8>, Error = MusicError> {
@@ -177,20 +249,20 @@ impl Music {
     curl.http_headers(headers).unwrap();
     curl.follow_location(true).unwrap();
     // FnMut(&[u8]) -> Result<usize, WriteError> + Send + 'static
-    future::lazy(move || {
+    let download = future::lazy(move || {
       let mut buf = Vec::new();
-      {
+      let result = {
         let mut transfer = curl.transfer();
         transfer
           .write_function(|data| {
             buf.extend_from_slice(data);
             Ok(data.len())
           })
-          .unwrap();
-        transfer.perform().unwrap();
-      }
-      ok(buf)
-    })
+          .and_then(|_| transfer.perform())
+      };
+      result.map(|_| buf).map_err(MusicError::Download)
+    });
+    metrics::track("netease", download)
   }
 
   #[allow(dead_code)]
@@ -0,0 +1,104 @@
+use crate::common::*;
+use crate::config::ConfigError;
+
+use crate::extensions::*;
+
+/// An extension that can be loaded by name from the config
+pub struct Plugin {
+  pub name: &'static str,
+  pub description: &'static str,
+  plug: fn(&mut Context),
+}
+
+fn plugin<T: BotExtension + Named + 'static>(
+  description: &'static str,
+) -> Plugin {
+  Plugin {
+    name: T::NAME,
+    description,
+    plug: Context::plug_ext::<T>,
+  }
+}
+
+/// Plugins compiled into this build, in the default loading order
+pub fn plugins() -> Vec<Plugin> {
+  let mut plugins = vec![
+    plugin::<history::Saver>("Save messages for searching"),
+    plugin::<afk::Afk>("Tell others when someone is away"),
+    plugin::<weather::Weather>("Weather reports"),
+    plugin::<history::Searcher>("Search saved messages"),
+    plugin::<reminder::ReminderPool>("Reminders"),
+  ];
+  #[cfg(feature = "music")]
+  plugins.push(plugin::<music::Music>("Music from Netease"));
+  #[cfg(feature = "yeelight")]
+  plugins.push(plugin::<yeelight::Yeelight>("Yeelight control"));
+  plugins.push(plugin::<link_cleanser::LinkCleanser>(
+    "Strip tracking parameters from links",
+  ));
+  plugins.push(plugin::<manager::Manager>("Admin console"));
+  plugins
+}
+
+/// Plug the plugins listed in the config, or all of them. The listed
+/// order breaks ties in priority, nothing is plugged if any of the names
+/// is unknown.
+pub fn plug(ctx: &mut Context) -> std::result::Result<(), ConfigError> {
+  let available = plugins();
+  let names: Vec<String> = match ctx.config.plugins {
+    Some(ref names) => names.clone(),
+    None => available.iter().map(|p| p.name.into()).collect(),
+  };
+
+  let mut chosen: Vec<&Plugin> = vec![];
+  for name in &names {
+    let invalid = |reason: String| ConfigError::Invalid {
+      key: "plugins".into(),
+      reason,
+    };
+    if chosen.iter().any(|p| p.name == name) {
+      return Err(invalid(format!("{} is listed twice", name)));
+    }
+    match available.iter().find(|p| p.name == name) {
+      Some(plugin) => chosen.push(plugin),
+      None => {
+        let reason = format!("{} is not available, see --list-plugins", name);
+        return Err(invalid(reason));
+      }
+    }
+  }
+
+  for plugin in chosen {
+    (plugin.plug)(ctx);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_plug_from_config() {
+    let mut h = Harness::new();
+    h.ctx.config.plugins = Some(vec!["afk".into(), "nope".into()]);
+    let error = plug(&mut h.ctx).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Invalid plugins: nope is not available, see --list-plugins"
+    );
+    assert!(h.ctx.exts.is_empty());
+
+    let names = vec!["manager".into(), "link_cleanser".into()];
+    h.ctx.config.plugins = Some(names);
+    plug(&mut h.ctx).unwrap();
+    let names: Vec<_> = h
+      .ctx
+      .exts
+      .iter()
+      .map(|ext| ext.borrow().name().to_string())
+      .collect();
+    assert_eq!(names, vec!["manager", "link_cleanser"]);
+  }
+}
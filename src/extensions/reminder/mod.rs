@@ -3,25 +3,33 @@ mod set_reminder;
 use self::set_reminder::*;
 
 use crate::common::*;
+use crate::db::DbReminder;
 
 use std::sync::Arc;
 
-#[derive(Clone, Debug, Serialize, Deserialize, Hash, PartialEq)]
+#[derive(Clone, Debug, Hash, PartialEq)]
 pub struct Reminder {
+  /// Row in the `reminders` table, once saved
+  id: Option<i32>,
   remind_at: DateTime<Local>,
   set_at: DateTime<Local>,
   content: String,
   chat_id: tg::ChatId,
   message_id: tg::MessageId,
-  deleted: bool,
+  /// The scheduled alert, missing in reminders saved by older versions
+  job: Option<JobId>,
 }
 
-type RemindersType = Vec<Arc<RefCell<Reminder>>>;
 pub struct ReminderPool {
-  reminders: RemindersType,
-  set_reminder: Option<SetReminder>,
-  deletion: Option<Vec<Reminder>>,
-  listing_message: Arc<RefCell<Option<tg::Message>>>,
+  reminders: Vec<Reminder>,
+  set_reminder: SessionStore<SetReminder>,
+  listings: SessionStore<Listing>,
+}
+
+/// Reminders as shown to a user by /list_reminders, /del_<n> refers to it
+struct Listing {
+  reminders: Vec<Reminder>,
+  message: Arc<RefCell<Option<tg::Message>>>,
 }
 
 impl BotExtension for ReminderPool {
@@ -29,104 +37,155 @@ impl BotExtension for ReminderPool {
   where
     Self: Sized,
   {
-    let now = Local::now();
-    let reminders: RemindersType = ctx
-      .db
-      .load_conf::<Vec<Reminder>>("reminders")
-      .unwrap_or_default()
+    let rows = ctx.or_report("reminder", ctx.db.reminders());
+
+    // schedule alerts for reminders saved before the scheduler existed,
+    // dropping those already gone by
+    let reminders = rows
       .into_iter()
-      .filter(|x| x.remind_at >= now && !x.deleted)
-      .map(|rem| Arc::new(RefCell::new(rem)))
+      .map(Reminder::from_db)
+      .filter_map(|mut rem| {
+        if rem.job.is_some() {
+          return Some(rem);
+        }
+        if !rem.schedule(ctx) {
+          rem.delete(ctx);
+          return None;
+        }
+        let (id, job) = (rem.id.unwrap(), rem.job.unwrap());
+        ctx.or_report("reminder", ctx.db.set_reminder_job(id, job));
+        Some(rem)
+      })
       .collect();
 
-    for rem in reminders.iter() {
-      Reminder::settle(rem.clone(), ctx);
-    }
-
     ReminderPool {
-      set_reminder: None,
-      deletion: None,
-      listing_message: Arc::new(RefCell::new(None)),
+      set_reminder: SessionStore::new(Duration::minutes(30)),
+      listings: SessionStore::new(Duration::minutes(30)),
       reminders,
     }
   }
 
-  fn process(&mut self, message: &tg::Message, ctx: &Context) {
-    if message.is_cmd("remind_me") {
-      self.set_reminder = Some(SetReminder::init(message, ctx));
-      self.set_reminder.as_mut().unwrap().on_message(message, ctx);
-      self.settle_new_reminder(Some(message), None, ctx);
-    } else if message.is_reply_to_bot() && self.set_reminder.is_some() {
-      self.set_reminder.as_mut().unwrap().on_message(message, ctx);
-      self.settle_new_reminder(Some(message), None, ctx);
-    } else if message.is_cmd("list_reminders") {
-      self.list_reminders(message, false, ctx);
-    } else if self.deletion.is_some() && message.is_cmd_prefix("del_") {
+  fn process(&mut self, message: &tg::Message, ctx: &Context) -> Dispatch {
+    if remind_me_command().matches(message) {
+      self
+        .set_reminder
+        .start_with(message, |token| SetReminder::init(message, token));
+      let reminder = self.set_reminder.feed_message(message, ctx);
+      self.settle_new_reminder(reminder, Some(message), None, ctx);
+    } else if message.is_reply_to_bot() && self.set_reminder.contains(message) {
+      let reminder = self.set_reminder.feed_message(message, ctx);
+      self.settle_new_reminder(reminder, Some(message), None, ctx);
+    } else if list_command().matches(message) {
+      self.list_reminders(message, ctx);
+    } else if self.listings.contains(message) && del_command().matches(message)
+    {
       self.delete_reminder(message, ctx);
+    } else {
+      return Dispatch::Continue;
     }
+
+    Dispatch::Consumed
   }
   fn process_callback(&mut self, query: &tg::CallbackQuery, ctx: &Context) {
-    if self.set_reminder.is_some() {
-      self.set_reminder.as_mut().unwrap().on_callback(query, ctx);
-      self.settle_new_reminder(None, Some(query), ctx);
+    let reminder = self.set_reminder.feed_callback(query, ctx);
+    self.settle_new_reminder(reminder, None, Some(query), ctx);
+  }
+  fn process_job(&mut self, job: &Job, ctx: &Context) {
+    let pos = self
+      .reminders
+      .iter()
+      .position(|rem| rem.job == Some(job.id));
+    if let Some(pos) = pos {
+      let reminder = self.reminders.remove(pos);
+      reminder.send_alert(&ctx.bot);
+      reminder.delete(ctx);
     }
   }
+  fn commands(&self) -> Vec<Command> {
+    vec![remind_me_command(), list_command(), del_command()]
+  }
+
   fn name(&self) -> &str {
-    "reminder"
+    Self::NAME
   }
 }
 
+impl Named for ReminderPool {
+  const NAME: &'static str = "reminder";
+}
+
+fn remind_me_command() -> Command {
+  Command::new("remind_me", "set reminder")
+    .usage("[content]")
+    .arg("content", "what to be reminded about, asked if omitted")
+}
+
+fn list_command() -> Command {
+  Command::new("list_reminders", "list reminders")
+}
+
+fn del_command() -> Command {
+  Command::prefix("del_", "delete the n-th reminder in the listing")
+}
+
 impl ReminderPool {
   fn list(&self) -> Vec<Reminder> {
-    self
-      .reminders
-      .iter()
-      .map(|x| x.deref().borrow().clone())
-      .filter(|x| !x.deleted)
-      .collect::<Vec<_>>()
+    self.reminders.clone()
   }
 
   fn settle_new_reminder(
     &mut self,
+    reminder: Option<Reminder>,
     msg: Option<&tg::Message>,
     query: Option<&tg::CallbackQuery>,
     ctx: &Context,
   ) {
-    if self.set_reminder.is_none() {
-      return;
-    }
+    let mut reminder = match reminder {
+      Some(reminder) => reminder,
+      None => return,
+    };
 
-    {
-      let set_reminder = self.set_reminder.as_ref().unwrap();
-      if !set_reminder.ready() {
-        return;
+    let reply = if !reminder.schedule(ctx) {
+      "Failed to set reminder"
+    } else {
+      match ctx.db.insert_reminder(&reminder.to_db()) {
+        Ok(id) => {
+          reminder.id = Some(id);
+          self.drop_replaced(&reminder, ctx);
+          self.reminders.push(reminder);
+          "Reminder set"
+        }
+        Err(e) => {
+          ctx.errors.report(self.name(), &e);
+          "Failed to save reminder"
+        }
       }
+    };
 
-      let reminder = set_reminder.build().unwrap();
-      let reminder = Arc::new(RefCell::new(reminder));
-      Reminder::settle(reminder.clone(), ctx);
-
-      self.reminders.push(reminder);
-
-      if let Some(msg) = msg {
-        ctx.bot.spawn(msg.text_reply("Reminder set"));
-      }
-      if let Some(query) = query {
-        ctx.bot.spawn(query.answer("Reminder set"));
-      }
+    if let Some(msg) = msg {
+      ctx.bot.spawn(msg.text_reply(reply));
+    }
+    if let Some(query) = query {
+      ctx.bot.spawn(query.answer(reply));
     }
+  }
 
-    self.set_reminder.take();
-    self.save(ctx);
+  /// Drop what an earlier version of the command behind `reminder` set,
+  /// so correcting `/remind_me` by editing it leaves one reminder
+  fn drop_replaced(&mut self, reminder: &Reminder, ctx: &Context) {
+    let (chat_id, message_id) = (reminder.chat_id, reminder.message_id);
+    let (replaced, kept) = self
+      .reminders
+      .drain(..)
+      .partition(|rem| rem.chat_id == chat_id && rem.message_id == message_id);
+    self.reminders = kept;
+    for rem in replaced {
+      rem.cancel(ctx);
+      rem.delete(ctx);
+    }
   }
 
-  fn list_reminders(
-    &mut self,
-    msg: &tg::Message,
-    edit_mode: bool,
-    ctx: &Context,
-  ) {
-    let reminders = self.list();
+  fn format_listing(reminders: &[Reminder]) -> String {
     let mut text = String::new();
 
     writeln!(text, "Reminders ({})\n----------", reminders.len()).ok();
@@ -143,28 +202,55 @@ impl ReminderPool {
     if reminders.is_empty() {
       writeln!(text, "no reminders").ok();
     }
+    text
+  }
 
-    let slot = self.listing_message.clone();
-    self.deletion = Some(reminders);
-
-    if edit_mode {
-      ctx.bot.spawn(msg.edit_text(text))
-    } else {
-      let future = ctx.bot.send(msg.chat.text(text));
-      let future = future
-        .map(move |listing_msg| {
-          (*slot.as_ref().borrow_mut()) = Some(listing_msg);
-        })
-        .map_err(|_| ());
+  fn list_reminders(&mut self, msg: &tg::Message, ctx: &Context) {
+    let reminders = self.list();
+    let text = Self::format_listing(&reminders);
+    let slot = Arc::new(RefCell::new(None));
+    let listing = Listing {
+      reminders,
+      message: slot.clone(),
+    };
+    self.listings.start(msg, listing);
+
+    let errors = ctx.errors.clone();
+    let msg = msg.clone();
+    let future = ctx.bot.send(msg.chat.text(text));
+    let future = future
+      .map(move |listing_msg| {
+        (*slot.as_ref().borrow_mut()) = Some(listing_msg);
+      })
+      .map_err(move |e| {
+        errors.report_to("reminder", &e, &msg);
+      });
+
+    ctx.spawn(self.name(), future);
+  }
 
-      ctx.handle.spawn(future);
+  /// Update the listing the user is deleting from
+  fn refresh_listing(&mut self, msg: &tg::Message, ctx: &Context) {
+    let reminders = self.list();
+    let text = Self::format_listing(&reminders);
+    let listing = match self.listings.get_mut(msg) {
+      Some(listing) => listing,
+      None => return,
+    };
+
+    listing.reminders = reminders;
+    let listing_msg = listing.message.deref().borrow().clone();
+    if let Some(listing_msg) = listing_msg {
+      ctx.bot.spawn(listing_msg.edit_text(text))
     }
   }
 
   fn delete_reminder(&mut self, msg: &tg::Message, ctx: &Context) {
     {
-      let n: usize = msg.cmd_suffix("del_").unwrap().parse().unwrap();
-      let reminder = if let Some(rem) = self.deletion.as_ref().unwrap().get(n) {
+      let n = msg.cmd_suffix("del_").and_then(|n| n.parse::<usize>().ok());
+      let listing = self.listings.get(msg);
+      let reminder = n.and_then(|n| listing?.reminders.get(n));
+      let reminder = if let Some(rem) = reminder {
         rem
       } else {
         let req = msg.chat.text("Invalid index, please try another one");
@@ -172,53 +258,69 @@ impl ReminderPool {
         return;
       };
 
-      let pred =
-        |x: &Arc<RefCell<Reminder>>| x.deref().borrow().deref() == reminder;
-
-      if let Some(loc) = self.reminders.iter().position(pred) {
-        self.reminders.remove(loc);
+      if let Some(loc) = self.reminders.iter().position(|x| x == reminder) {
+        let removed = self.reminders.remove(loc);
+        removed.cancel(ctx);
+        removed.delete(ctx);
       }
     }
 
-    self.save(ctx);
-    let listing_msg = self.listing_message.deref().borrow().clone();
-    if let Some(msg) = listing_msg {
-      self.list_reminders(&msg, true, ctx);
-    }
-  }
-
-  fn save(&self, ctx: &Context) {
-    let reminders = self
-      .reminders
-      .iter()
-      .map(|x| x.deref().borrow().clone())
-      .filter(|x| !x.deleted)
-      .collect::<Vec<_>>();
-    ctx.db.save_conf("reminders", reminders);
+    self.refresh_listing(msg, ctx);
   }
 }
 
 impl Reminder {
-  fn settle(this: Arc<RefCell<Reminder>>, ctx: &Context) {
-    let that = this.clone();
-    let this = this.deref().borrow();
+  fn from_db(row: DbReminder) -> Self {
+    Reminder {
+      id: row.id,
+      remind_at: Local.timestamp(row.remind_at, 0),
+      set_at: Local.timestamp(row.set_at, 0),
+      content: row.content,
+      chat_id: row.chat_id.into(),
+      message_id: row.message_id.into(),
+      job: row.job_id,
+    }
+  }
 
-    let duration = this
-      .remind_at
-      .signed_duration_since(Local::now())
-      .to_std()
-      .unwrap();
+  fn to_db(&self) -> DbReminder {
+    DbReminder {
+      id: self.id,
+      remind_at: self.remind_at.timestamp(),
+      set_at: self.set_at.timestamp(),
+      content: self.content.clone(),
+      chat_id: self.chat_id.into(),
+      message_id: self.message_id.into(),
+      job_id: self.job,
+    }
+  }
 
-    let timeout = reactor::Timeout::new(duration, &ctx.handle).unwrap();
-    let bot = ctx.bot.clone();
+  fn delete(&self, ctx: &Context) {
+    if let Some(id) = self.id {
+      ctx.or_report("reminder", ctx.db.delete_reminder(id));
+    }
+  }
 
-    let future = timeout.then(move |_| {
-      let mut this = that.deref().borrow_mut();
-      this.deref_mut().send_alert(&bot);
-      ok(())
-    });
+  fn cancel(&self, ctx: &Context) {
+    if let Some(job) = self.job {
+      if let Err(e) = ctx.scheduler().cancel(job) {
+        ctx.errors.report("reminder", &e);
+      }
+    }
+  }
 
-    ctx.handle.spawn(future);
+  /// Schedule the alert, false if it is already past
+  fn schedule(&mut self, ctx: &Context) -> bool {
+    let trigger = Trigger::Once(self.remind_at);
+    match ctx.scheduler().schedule("reminder", trigger, &()) {
+      Ok(job) => {
+        self.job = Some(job);
+        true
+      }
+      Err(e) => {
+        warn!(ctx.logger, "Unable to schedule reminder: {}", e);
+        false
+      }
+    }
   }
 
   fn describe(&self) -> String {
@@ -236,14 +338,132 @@ impl Reminder {
     output.join("\n")
   }
 
-  fn send_alert(&mut self, bot: &tg::Api) {
+  fn send_alert(&self, bot: &Bot) {
     let req = tg::SendMessage::new(self.chat_id, self.describe())
       .reply_to(self.message_id)
       .clone();
+    bot.spawn(req);
+  }
+}
 
-    if !self.deleted {
-      bot.spawn(req);
-      self.deleted = true;
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_remind_me_wizard() {
+    let mut h = Harness::new();
+    h.plug::<ReminderPool>();
+    h.send_text(PRIVATE_CHAT, USER_ID, "/remind_me take pill");
+
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    let panel = &reqs[0];
+    assert_eq!(panel.method, "sendMessage");
+    let shift = panel.button("+5 min").unwrap();
+    // too close to now to be committed
+    assert!(panel.button("Done").is_none());
+
+    h.click(panel, USER_ID, &shift);
+    let reqs = h.take_requests();
+    let methods: Vec<_> = reqs.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, vec!["editMessageText", "answerCallbackQuery"]);
+    let done = reqs[0].button("Done").unwrap();
+
+    h.click(panel, USER_ID, &done);
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].method, "editMessageText");
+    assert!(reqs[0].text().unwrap().starts_with("Done, I'll remind you"));
+    assert_eq!(reqs[1].method, "answerCallbackQuery");
+    assert_eq!(reqs[1].body["text"], "Reminder set");
+
+    h.click(panel, USER_ID, &shift);
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["text"], "This panel has expired");
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/list_reminders");
+    let reqs = h.take_requests();
+    assert!(reqs[0].text().unwrap().contains("take pill (/del_0)"));
+
+    h.run_jobs_at(Local::now() + Duration::minutes(6));
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert!(reqs[0]
+      .text()
+      .unwrap()
+      .starts_with("It's time for take pill"));
+
+    h.send_text(PRIVATE_CHAT, USER_ID, "/list_reminders");
+    let reqs = h.take_requests();
+    assert!(reqs[0].text().unwrap().contains("no reminders"));
+  }
+
+  #[test]
+  fn test_wizards_of_different_users_are_isolated() {
+    let mut h = Harness::new();
+    h.plug::<ReminderPool>();
+    h.send_text(GROUP_CHAT, USER_ID, "/remind_me take pill");
+    let panel = h.take_requests().remove(0);
+    h.send_text(GROUP_CHAT, OTHER_USER_ID, "/remind_me water plants");
+    let other_panel = h.take_requests().remove(0);
+
+    // panels only respond to the user who opened them
+    h.click(&panel, OTHER_USER_ID, &panel.button("+5 min").unwrap());
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].body["text"], "This panel has expired");
+
+    for &(panel, user) in &[(&panel, USER_ID), (&other_panel, OTHER_USER_ID)] {
+      h.click(panel, user, &panel.button("+5 min").unwrap());
+      let done = h.take_requests()[0].button("Done").unwrap();
+      h.click(panel, user, &done);
+      let reqs = h.take_requests();
+      assert_eq!(reqs[1].body["text"], "Reminder set");
     }
+
+    h.send_text(GROUP_CHAT, USER_ID, "/list_reminders");
+    let reqs = h.take_requests();
+    let text = reqs[0].text().unwrap();
+    assert!(text.contains("take pill"));
+    assert!(text.contains("water plants"));
+  }
+
+  #[test]
+  fn test_edited_command_is_rerun() {
+    let mut h = Harness::new();
+    h.plug::<ReminderPool>();
+    let id = h.send_text(GROUP_CHAT, USER_ID, "/remindme take pill");
+    assert!(h.take_requests().is_empty());
+
+    h.edit_text(id, GROUP_CHAT, USER_ID, "/remind_me take pill");
+    let reqs = h.take_requests();
+    assert_eq!(reqs.len(), 1);
+    assert!(reqs[0].text().unwrap().starts_with("*Reminder time:*"));
+  }
+
+  #[test]
+  fn test_edited_command_replaces_its_reminder() {
+    let mut h = Harness::new();
+    h.plug::<ReminderPool>();
+    let id = h.send_text(GROUP_CHAT, USER_ID, "/remind_me take pill");
+    let set = |h: &mut Harness| {
+      let panel = h.take_requests().remove(0);
+      h.click(&panel, USER_ID, &panel.button("+5 min").unwrap());
+      let done = h.take_requests()[0].button("Done").unwrap();
+      h.click(&panel, USER_ID, &done);
+      assert_eq!(h.take_requests()[1].body["text"], "Reminder set");
+    };
+    set(&mut h);
+
+    h.edit_text(id, GROUP_CHAT, USER_ID, "/remind_me take pills");
+    set(&mut h);
+
+    h.send_text(GROUP_CHAT, USER_ID, "/list_reminders");
+    let reqs = h.take_requests();
+    let text = reqs[0].text().unwrap();
+    assert!(text.starts_with("Reminders (1)"));
+    assert!(text.contains("take pills"));
+    assert_eq!(h.ctx.db.reminders().unwrap().len(), 1);
+    assert_eq!(h.ctx.scheduler().jobs("reminder").unwrap().len(), 1);
   }
 }
@@ -1,10 +1,20 @@
 use super::*;
 use crate::common::*;
+use crate::extensions::callback::callback_button;
+
+/// Payload of the buttons on the reminder time panel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TimeAction {
+  /// Move the reminder time by the given minutes
+  Shift(i64),
+  Commit,
+}
 
 #[derive(Clone, Debug)]
 pub struct SetReminder {
   chat_id: tg::ChatId,
   message_id: tg::MessageId,
+  token: Token,
 
   content: Option<String>,
   remind_at: Option<DateTime<Local>>,
@@ -23,17 +33,18 @@ impl InteractiveBuilder for SetReminder {
     }
 
     Some(Reminder {
+      id: None,
       remind_at: self.remind_at.unwrap(),
       set_at: Local::now(),
       content: self.content.clone().unwrap(),
       chat_id: self.chat_id,
       message_id: self.message_id,
-      deleted: false,
+      job: None,
     })
   }
 
   fn on_message(&mut self, msg: &tg::Message, ctx: &Context) {
-    if msg.is_cmd("remind_me") {
+    if super::remind_me_command().matches(msg) {
       if self.stage == "content" {
         self.prompt("set_content", None, ctx);
       } else {
@@ -75,21 +86,22 @@ impl InteractiveBuilder for SetReminder {
       return;
     }
 
-    if query.key() == Some("commit_time") {
-      if self.duration_too_short() {
-        self.prompt("invalid_time", Some(&query.message), ctx);
-        return;
+    match query.payload() {
+      Some(TimeAction::Commit) => {
+        if self.duration_too_short() {
+          self.prompt("invalid_time", Some(&query.message), ctx);
+          return;
+        }
+        self.stage = "finish";
+        self.prompt("finish", Some(&query.message), ctx);
       }
-      self.stage = "finish";
-      self.prompt("finish", Some(&query.message), ctx);
-    } else {
-      let new = {
-        let current = self.remind_at.as_ref().unwrap();
-        self.key_to_time(current, query.key().unwrap_or("unknown"))
-      };
-      self.remind_at = Some(new);
-      self.prompt("set_time", Some(&query.message), ctx);
-      ctx.bot.spawn(query.acknowledge());
+      Some(TimeAction::Shift(minutes)) => {
+        let new = self.remind_at.unwrap() + Duration::minutes(minutes);
+        self.remind_at = Some(new);
+        self.prompt("set_time", Some(&query.message), ctx);
+        ctx.bot.spawn(query.acknowledge());
+      }
+      None => ctx.bot.answer_expired(query),
     }
   }
 
@@ -122,13 +134,14 @@ impl InteractiveBuilder for SetReminder {
 }
 
 impl SetReminder {
-  pub fn init(message: &tg::Message, _ctx: &Context) -> SetReminder {
+  pub fn init(message: &tg::Message, token: Token) -> SetReminder {
     let content = message.cmd_arg();
     let stage = if content.is_none() { "content" } else { "time" };
 
     SetReminder {
       message_id: message.id,
       chat_id: message.chat.id(),
+      token,
       remind_at: Some(Local::now()),
       remind_at_type: None,
       content,
@@ -141,41 +154,38 @@ impl SetReminder {
     message_to_update: Option<&tg::Message>,
     ctx: &Context,
   ) {
-    let callback_button = |text: &str, key: &str| -> tg::InlineKeyboardButton {
-      let callback = format!("{}.{}", "reminder", key);
-      tg::InlineKeyboardButton::callback(text, callback)
+    let shift = |text: &str, minutes: i64| {
+      let action = TimeAction::Shift(minutes);
+      callback_button("reminder", text, self.token, &action)
     };
+    let (hr, day) = (60, 24 * 60);
     let mut keyboard = {
       let rows = vec![
+        vec![shift("+1 min", 1), shift("+5 min", 5), shift("+30 min", 30)],
         vec![
-          callback_button("+1 min", "+/1/min"),
-          callback_button("+5 min", "+/5/min"),
-          callback_button("+30 min", "+/30/min"),
+          shift("-1 min", -1),
+          shift("-5 min", -5),
+          shift("-30 min", -30),
         ],
         vec![
-          callback_button("-1 min", "-/1/min"),
-          callback_button("-5 min", "-/5/min"),
-          callback_button("-30 min", "-/30/min"),
+          shift("+1 hour", hr),
+          shift("+2 hours", 2 * hr),
+          shift("+4 hours", 4 * hr),
         ],
         vec![
-          callback_button("+1 hour", "+/1/hr"),
-          callback_button("+2 hours", "+/2/hr"),
-          callback_button("+4 hours", "+/4/hr"),
+          shift("-1 hour", -hr),
+          shift("-2 hours", -2 * hr),
+          shift("-4 hours", -4 * hr),
         ],
         vec![
-          callback_button("-1 hour", "-/1/hr"),
-          callback_button("-2 hours", "-/2/hr"),
-          callback_button("-4 hours", "-/4/hr"),
+          shift("+1 day", day),
+          shift("+5 day", 5 * day),
+          shift("+1 month", 30 * day),
         ],
         vec![
-          callback_button("+1 day", "+/1/day"),
-          callback_button("+5 day", "+/5/day"),
-          callback_button("+1 month", "+/30/day"),
-        ],
-        vec![
-          callback_button("-1 day", "-/1/day"),
-          callback_button("-5 day", "-/5/day"),
-          callback_button("-1 month", "-/30/day"),
+          shift("-1 day", -day),
+          shift("-5 day", -5 * day),
+          shift("-1 month", -30 * day),
         ],
       ];
       let mut keyboard = tg::InlineKeyboardMarkup::new();
@@ -199,7 +209,9 @@ impl SetReminder {
     if self.duration_too_short() {
       text.push_str("_Unable to save this reminder_");
     } else {
-      keyboard.add_row(vec![callback_button("Done", "commit_time")]);
+      let done = TimeAction::Commit;
+      let done = callback_button("reminder", "Done", self.token, &done);
+      keyboard.add_row(vec![done]);
     }
 
     if let Some(msg) = message_to_update {
@@ -240,30 +252,6 @@ impl SetReminder {
     ctx.bot.spawn(req);
   }
 
-  fn key_to_time<Tz: TimeZone>(
-    &self,
-    current: &DateTime<Tz>,
-    key: &str,
-  ) -> DateTime<Tz> {
-    let current = current.clone();
-    let parts: Vec<&str> = key.splitn(3, '/').collect();
-    let (sign, num, unit) =
-      (parts[0], parts[1].parse::<i64>().unwrap(), parts[2]);
-
-    let mut dur = match unit {
-      "min" => Duration::minutes(num),
-      "hr" => Duration::hours(num),
-      "day" => Duration::days(num),
-      _ => Duration::minutes(1),
-    };
-
-    if sign == "-" {
-      dur = -dur;
-    }
-
-    current + dur
-  }
-
   fn duration_too_short(&self) -> bool {
     let remind_at = self.remind_at.as_ref().unwrap();
     let duration = remind_at.signed_duration_since(Local::now());
@@ -1,14 +1,27 @@
 use crate::common::*;
+use crate::services::metrics;
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Default)]
 pub struct Weather {
   weather_loc: HashMap<String, String>,
+  config: WeatherConfig,
 }
 
+/// The `[extensions.weather]` section
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherConfig {
+  caiyun_api_key: Option<String>,
+}
+
+/// The `weather_locations` table as shown and set by `/set_conf`
+pub const LOCATIONS_KEY: &str = "weather.locations";
+
 pub trait WeatherProvider: Display {
   fn from_query(
     city: &str,
     extra: Option<&str>,
+    config: &WeatherConfig,
   ) -> Box<Future<Item = Self, Error = FondbotError>>
   where
     Self: Sized;
@@ -19,70 +32,154 @@ impl BotExtension for Weather {
   where
     Self: Sized,
   {
-    ctx.db.load_conf("weather").unwrap_or_default()
+    let locations = ctx.or_report("weather", ctx.db.locations());
+    Weather {
+      weather_loc: locations.into_iter().collect(),
+      config: ctx.config.extension("weather"),
+    }
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-    if msg.cmd_name().is_none() {
-      return;
-    }
-    match msg.cmd_name().unwrap().as_ref() {
-      "weather" => self.send_weather_report(msg, ctx),
-      "add_loc" => {
-        let args = msg.cmd_args();
-        let (city, long_lat) = (args.get(0), args.get(1));
-        if city.is_none() || long_lat.is_none() {
-          ctx.bot.reply_to(msg, "Usage /add_loc <city> <long,lat>");
-          return;
-        }
-
-        let (city, long_lat) = (city.unwrap(), long_lat.unwrap());
-
-        self.weather_loc.insert(city.clone(), long_lat.clone());
-        ctx.db.save_conf("weather", self);
-        ctx
-          .bot
-          .reply_to(msg, format!("Location {} ({}) added.", city, long_lat));
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    if weather_command().matches(msg) {
+      self.send_weather_report(msg, ctx);
+    } else if add_loc_command().matches(msg) {
+      let args = msg.cmd_args();
+      let (city, long_lat) = (args.get(0), args.get(1));
+      if city.is_none() || long_lat.is_none() {
+        ctx.bot.reply_to(msg, add_loc_command().usage_text());
+        return Dispatch::Consumed;
       }
-      _ => {}
+
+      let (city, long_lat) = (city.unwrap(), long_lat.unwrap());
+
+      self.weather_loc.insert(city.clone(), long_lat.clone());
+      ctx.or_report(self.name(), ctx.db.save_location(city, long_lat));
+      ctx
+        .bot
+        .reply_to(msg, format!("Location {} ({}) added.", city, long_lat));
+    } else {
+      return Dispatch::Continue;
+    }
+
+    Dispatch::Consumed
+  }
+
+  fn process_inline_query(
+    &mut self,
+    query: &tg::InlineQuery,
+    ctx: &Context,
+  ) -> Dispatch {
+    let text = query.query.trim();
+    if !text.starts_with("weather") {
+      return Dispatch::Continue;
+    }
+
+    let filter = text.trim_start_matches("weather").trim();
+    self.answer_inline(query, filter, ctx);
+    Dispatch::Consumed
+  }
+
+  fn commands(&self) -> Vec<Command> {
+    vec![weather_command(), add_loc_command()]
+  }
+
+  fn reload(&mut self, key: &str, ctx: &Context) {
+    if key == LOCATIONS_KEY {
+      *self = Self::init(ctx);
     }
   }
 
   fn name(&self) -> &str {
-    "weather"
+    Self::NAME
   }
 }
 
+impl Named for Weather {
+  const NAME: &'static str = "weather";
+}
+
+fn weather_command() -> Command {
+  Command::new("weather", "check weather")
+}
+
+fn add_loc_command() -> Command {
+  Command::new("add_loc", "add location for weather")
+    .usage("<city> <long,lat>")
+    .arg("city", "name of the location")
+    .arg("long,lat", "longitude and latitude, e.g. 121.47,31.23")
+}
+
 impl Weather {
   fn send_weather_report(&self, msg: &tg::Message, ctx: &Context) {
     trace!(ctx.logger, "User requests for weather report");
     trace!(ctx.logger, "Available locs: {:?}", &self.weather_loc);
-    use std::fmt::Write;
     for (ref city, ref long_lat) in self.weather_loc.iter() {
       trace!(ctx.logger, "Querying weather for {}", city);
       let waiting = msg.from.chat_action(tg::ChatAction::Typing);
-      let mut out = format!("*Weather Report for {}*\n", city);
       let msg = msg.clone();
       let bot = ctx.bot.clone();
-      let future =
-        Caiyun::from_query(&city, Some(&long_lat)).then(move |result| {
-          match result {
-            Ok(w) => writeln!(out, "{}", w).ok(),
-            Err(e) => writeln!(out, "Error: {}", e).ok(),
-          };
-          bot.spawn(msg.chat.text(out).parse_mode(Markdown));
-          ok(())
-        });
-      ctx.handle.spawn(
+      let report = Self::report(city, long_lat, &self.config);
+      let future = report.map(move |out| {
+        bot.spawn(msg.chat.text(out).parse_mode(Markdown));
+      });
+      let errors = ctx.errors.clone();
+      ctx.spawn(
+        self.name(),
         ctx
           .bot
           .send(waiting)
-          .map_err(|_| ())
+          .map_err(move |e| {
+            errors.report("weather", &e);
+          })
           .join(future)
           .map(|_| ()),
       );
     }
   }
+
+  fn answer_inline(
+    &self,
+    query: &tg::InlineQuery,
+    filter: &str,
+    ctx: &Context,
+  ) {
+    let filter = filter.to_lowercase();
+    let reports: Vec<_> = self
+      .weather_loc
+      .iter()
+      .filter(|(city, _)| city.to_lowercase().contains(&filter))
+      .map(|(city, long_lat)| {
+        let city = city.clone();
+        Self::report(&city, long_lat, &self.config).map(move |out| {
+          let title = format!("Weather for {}", city);
+          inline_article(city, title, out)
+        })
+      })
+      .collect();
+
+    let bot = ctx.bot.clone();
+    let query = query.clone();
+    let future = future::join_all(reports)
+      .map(move |results| bot.answer_inline(&query, results));
+    ctx.spawn(self.name(), future);
+  }
+
+  /// Markdown weather report, errors are reported in the text
+  fn report(
+    city: &str,
+    long_lat: &str,
+    config: &WeatherConfig,
+  ) -> impl Future<Item = String, Error = ()> {
+    use std::fmt::Write;
+    let mut out = format!("*Weather Report for {}*\n", city);
+    Caiyun::from_query(city, Some(long_lat), config).then(move |result| {
+      match result {
+        Ok(w) => writeln!(out, "{}", w).ok(),
+        Err(e) => writeln!(out, "Error: {}", e).ok(),
+      };
+      ok(out)
+    })
+  }
 }
 
 #[derive(Deserialize, PartialEq, PartialOrd)]
@@ -142,13 +239,21 @@ impl WeatherProvider for Caiyun {
   fn from_query(
     _: &str,
     long_lat: Option<&str>,
+    config: &WeatherConfig,
   ) -> Box<Future<Item = Self, Error = FondbotError>> {
     let long_lat = long_lat.unwrap();
-    let api_key = env::var("CAIYUN_API_KEY").unwrap();
+    let api_key = match config.caiyun_api_key {
+      Some(ref key) => key,
+      None => {
+        let error = FondbotError::from("caiyun_api_key is not configured");
+        return Box::new(err::<Self, _>(error));
+      }
+    };
     let url =
       format!("{}/{}/{}/forecast.json", CAIYUN_API_BASE, api_key, long_lat);
 
-    Box::new(request(&url).from_err().map(|mut weather_data: Self| {
+    let forecast = metrics::track("caiyun", request::<Self>(&url));
+    Box::new(forecast.from_err().map(|mut weather_data: Self| {
       weather_data.truncate_result();
       weather_data
     }))
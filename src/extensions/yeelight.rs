@@ -1,4 +1,5 @@
 use crate::common::*;
+use crate::services::metrics;
 
 use std;
 use std::sync::Arc;
@@ -27,14 +28,43 @@ pub struct State {
   updated_at: DateTime<Local>,
 }
 
-type Request = Vec<Query>;
+/// Sent in order when a mode is switched to
+pub type Request = Vec<Query>;
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Versioned for Request {}
+
+/// The `[extensions.yeelight]` section
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct YeelightConfig {
+  /// Overrides the saved address of the bulb
+  addr: Option<SocketAddr>,
+}
+
+const REFRESH_SCHEDULE: &str = "*/5 * * * *";
+
+/// The `yeelight_devices` table as shown and set by `/set_conf`
+pub const DEVICES_KEY: &str = "yeelight.devices";
+/// The `yeelight_modes` table as shown and set by `/set_conf`
+pub const MODES_KEY: &str = "yeelight.modes";
+
+#[derive(Debug)]
 pub struct Yeelight {
   pub addr: Option<SocketAddr>,
   pub modes: Vec<(String, Request)>,
-  #[serde(skip_serializing, skip_deserializing)]
   pub current_state: Arc<Mutex<Option<State>>>,
+  /// Changes with the modes, expiring panels showing the old ones
+  modes_version: Token,
+}
+
+/// Payload of the buttons on the control panel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+enum PanelAction {
+  Refresh,
+  On,
+  Off,
+  /// Index into the modes
+  Mode(usize),
 }
 
 #[derive(Deserialize, Debug)]
@@ -117,6 +147,20 @@ impl Yeelight {
       })
   }
 
+  /// Refresh the state in the background, e.g. for `report()`
+  fn spawn_refresh(&self, ctx: &Context) {
+    let errors = ctx.errors.clone();
+    ctx.spawn(
+      self.name(),
+      self.refresh_state().then(move |result| {
+        if let Err(e) = result {
+          errors.report("yeelight", &e);
+        }
+        ok(())
+      }),
+    );
+  }
+
   fn refresh_state(&self) -> Box<Future<Item = State, Error = Error>> {
     let state_ref = self.current_state.clone();
 
@@ -166,7 +210,7 @@ impl Yeelight {
       }));
     }
 
-    conn.map(|(_, carry)| carry)
+    metrics::track("yeelight", conn.map(|(_, carry)| carry))
   }
 
   fn request1(&self, q: &Query) -> impl Future<Item = Response, Error = Error> {
@@ -183,21 +227,48 @@ impl Yeelight {
 
   fn switch_to_mode(
     &self,
-    name: &str,
+    index: usize,
   ) -> Box<Future<Item = (), Error = Error>> {
-    let req = self
-      .modes
-      .iter()
-      .find(|(mode_name, _)| name == mode_name)
-      .map(|(_, req)| req);
+    let req = self.modes.get(index).map(|(_, req)| req);
 
     if req.is_none() {
-      return Box::new(err(Error::Mode(name.into())));
+      return Box::new(err(Error::Mode(format!("#{}", index))));
     }
 
     Box::new(self.request(req.unwrap().as_slice()).map(|_| ()))
   }
 
+  /// `/yeelight <mode>`, for callers that cannot press buttons, like the
+  /// control API
+  fn switch_to_named_mode(&self, name: &str, msg: &tg::Message, ctx: &Context) {
+    let index = self
+      .modes
+      .iter()
+      .position(|(mode, _)| mode.eq_ignore_ascii_case(name));
+    let index = match index {
+      Some(index) => index,
+      None => {
+        let text = format!("Unknown mode: {}", name);
+        return ctx.bot.reply_to(msg, text);
+      }
+    };
+
+    let text = format!("Switched to {}", self.modes[index].0);
+    let bot = ctx.bot.clone();
+    let errors = ctx.errors.clone();
+    let msg = msg.clone();
+    let fut = self.switch_to_mode(index).then(move |result| {
+      match result {
+        Ok(()) => bot.reply_to(&msg, text),
+        Err(e) => {
+          errors.report_to("yeelight", &e, &msg);
+        }
+      }
+      ok(())
+    });
+    ctx.spawn(self.name(), fut);
+  }
+
   fn switch_power(
     &self,
     power: Power,
@@ -213,7 +284,7 @@ impl Yeelight {
     &self,
     chat: tg::ChatRef,
     edit: Option<&tg::Message>,
-    bot: &tg::Api,
+    bot: &Bot,
   ) -> Box<Future<Item = (), Error = Error>> {
     // to be moved into futures
     let bot = bot.clone();
@@ -221,10 +292,11 @@ impl Yeelight {
 
     // generate keyboard markup
     let mut markup = tg::InlineKeyboardMarkup::new();
+    let version = self.modes_version;
     let functional_row = vec![
-      self.callback_button("Refresh", "update"),
-      self.callback_button("Turn on", "on"),
-      self.callback_button("Turn off", "off"),
+      self.callback_button("Refresh", version, &PanelAction::Refresh),
+      self.callback_button("Turn on", version, &PanelAction::On),
+      self.callback_button("Turn off", version, &PanelAction::Off),
     ];
     markup.add_row(functional_row);
     for row in self.render_modes() {
@@ -272,12 +344,13 @@ impl Yeelight {
   }
 
   fn render_modes(&self) -> Vec<Vec<tg::InlineKeyboardButton>> {
+    let modes: Vec<_> = self.modes.iter().enumerate().collect();
     let mut rows = Vec::new();
-    for chunk in self.modes.chunks(4) {
+    for chunk in modes.chunks(4) {
       let mut row = Vec::new();
-      for (name, _) in chunk {
-        let key = format!("mode.{}", name);
-        row.push(self.callback_button(name, &key));
+      for (i, (name, _)) in chunk {
+        let action = PanelAction::Mode(*i);
+        row.push(self.callback_button(name, self.modes_version, &action));
       }
       rows.push(row);
     }
@@ -300,9 +373,64 @@ impl Yeelight {
       return Err(Error::ModeAlreadyExist(name.into()));
     }
     self.modes.push((name.into(), mode));
+    self.modes_version = new_token();
     Ok(name.into())
   }
 
+  /// The first bulb saved in `yeelight_devices`, unless the config
+  /// overrides it
+  fn load_addr(&mut self, ctx: &Context) {
+    let devices = ctx.or_report(self.name(), ctx.db.yeelight_devices());
+    self.addr = devices.first().and_then(|addr| addr.parse().ok());
+    let config: YeelightConfig = ctx.config.extension(self.name());
+    if config.addr.is_some() {
+      self.addr = config.addr;
+    }
+  }
+
+  /// Modes saved in the `yeelight_modes` table, adding the default ones
+  /// if there is none
+  fn load_modes(&mut self, ctx: &Context) {
+    let rows = ctx.or_report(self.name(), ctx.db.yeelight_modes());
+    if rows.is_empty() {
+      for (name, _) in &self.modes {
+        self.save_mode(name, ctx);
+      }
+      return;
+    }
+
+    self.modes.clear();
+    for (name, version, request) in rows {
+      match Request::from_saved(&request, version) {
+        Ok(request) => self.modes.push((name, request)),
+        Err(reason) => {
+          let backed_up = ctx
+            .db
+            .back_up_yeelight_mode(&name, version, &request, &reason);
+          let error = match backed_up {
+            Ok(backup) => DbError::State {
+              key: format!("yeelight mode {}", name),
+              backup,
+              reason,
+            },
+            Err(e) => e,
+          };
+          ctx.errors.report(self.name(), &error);
+        }
+      }
+    }
+  }
+
+  fn save_mode(&self, name: &str, ctx: &Context) {
+    let mode = self.modes.iter().find(|(n, _)| n == name);
+    if let Some((_, request)) = mode {
+      let request = serde_json::to_string(request).unwrap();
+      let version = Request::version();
+      let saved = ctx.db.insert_yeelight_mode(name, version, &request);
+      ctx.or_report(self.name(), saved);
+    }
+  }
+
   pub fn default_modes() -> Vec<(String, Request)> {
     let set_prop = |method: &str, value| {
       vec![Query {
@@ -341,41 +469,72 @@ impl Yeelight {
 
 impl BotExtension for Yeelight {
   fn init(ctx: &Context) -> Self {
-    let o: Yeelight = ctx.db.load_conf("yeelight").unwrap_or_default();
-    ctx.handle.spawn(o.refresh_state().then(|_| ok(())));
+    let mut o = Yeelight::default();
+    o.load_addr(ctx);
+    o.load_modes(ctx);
+    o.spawn_refresh(ctx);
+
+    // keep the state shown on panels fresh
+    let every = Trigger::cron(REFRESH_SCHEDULE).unwrap();
+    let scheduler = ctx.scheduler();
+    if let Err(e) = scheduler.schedule_unique("yeelight", "refresh", every, &())
+    {
+      warn!(ctx.logger, "Unable to schedule yeelight refresh: {}", e);
+    }
     o
   }
 
-  fn process(&mut self, msg: &tg::Message, ctx: &Context) {
-    if msg.is_cmd("yeelight") {
+  fn process_job(&mut self, _job: &Job, ctx: &Context) {
+    self.spawn_refresh(ctx);
+  }
+
+  fn reload(&mut self, key: &str, ctx: &Context) {
+    if key == DEVICES_KEY {
+      self.load_addr(ctx);
+      self.spawn_refresh(ctx);
+    } else if key == MODES_KEY {
+      self.modes = Self::default_modes();
+      self.load_modes(ctx);
+      self.modes_version = new_token();
+    }
+  }
+
+  fn process(&mut self, msg: &tg::Message, ctx: &Context) -> Dispatch {
+    let mode = msg.cmd_arg().filter(|arg| !arg.trim().is_empty());
+    if panel_command().matches(msg) && mode.is_some() {
+      self.switch_to_named_mode(mode.unwrap().trim(), msg, ctx);
+    } else if panel_command().matches(msg) {
       let fut = self.show_panel(msg.chat.to_chat_ref(), None, &ctx.bot);
-      ctx.handle.spawn(fut.map_err(|_| ()));
-    } else if msg.is_cmd("add_yeelight_mode") {
+      let errors = ctx.errors.clone();
+      let msg = msg.clone();
+      ctx.spawn(
+        self.name(),
+        fut.map_err(move |e| {
+          errors.report_to("yeelight", &e, &msg);
+        }),
+      );
+    } else if add_mode_command().matches(msg) {
       msg
         .cmd_arg()
         .ok_or(Error::ModeFormat)
         .and_then(|arg| self.add_mode(&arg))
         .map(|m| {
+          self.save_mode(&m, ctx);
           ctx.bot.spawn(
             msg.text_reply(format!("Successfully added mode for: {}", m)),
           )
         })
         .map_err(|e| {
-          let notice =
-            "Usage: /add_yeelight_mode <mode_name> - [<req>, <req>, ...]\n\
-             <req>: {\"method\": <method>, \"params\": [<param>, <param>, ...]";
+          let notice = add_mode_command().help_detail();
           ctx.bot.spawn(
             msg.text_reply(format!("Failed to add mode: {}\n\n{}", e, notice)),
           )
         })
         .ok();
-      ctx.db.save_conf("yeelight", &self);
-    } else if msg.is_cmd("del_yeelight_mode") {
+    } else if del_mode_command().matches(msg) {
       let mode_name = msg.cmd_arg();
       if mode_name.is_none() {
-        ctx
-          .bot
-          .reply_to(msg, "Usage: /del_yeelight_mode <mode_name>");
+        ctx.bot.reply_to(msg, del_mode_command().usage_text());
       } else {
         let mode_name = mode_name.unwrap();
         self
@@ -384,6 +543,9 @@ impl BotExtension for Yeelight {
           .position(|(name, _)| name.as_str() == mode_name)
           .map(|n| {
             self.modes.remove(n);
+            self.modes_version = new_token();
+            let deleted = ctx.db.delete_yeelight_mode(&mode_name);
+            ctx.or_report("yeelight", deleted);
             ctx
               .bot
               .reply_to(msg, format!("Successfully removed {}", mode_name))
@@ -393,20 +555,27 @@ impl BotExtension for Yeelight {
             None
           });
       }
-      ctx.db.save_conf("yeelight", &self);
+    } else {
+      return Dispatch::Continue;
     }
+
+    Dispatch::Consumed
   }
 
   fn process_callback(&mut self, query: &tg::CallbackQuery, ctx: &Context) {
-    if query.key().is_none() {
-      return;
-    }
+    let action = match query.payload() {
+      Some(action) if query.token() == Some(self.modes_version) => action,
+      _ => {
+        ctx.bot.answer_expired(query);
+        return;
+      }
+    };
 
-    let control_fut = match query.key().unwrap() {
-      "update" => Box::new(ok(())),
-      "on" => self.switch_power(Power::On),
-      "off" => self.switch_power(Power::Off),
-      k => self.switch_to_mode(k.trim_start_matches("mode.")),
+    let control_fut = match action {
+      PanelAction::Refresh => Box::new(ok(())),
+      PanelAction::On => self.switch_power(Power::On),
+      PanelAction::Off => self.switch_power(Power::Off),
+      PanelAction::Mode(i) => self.switch_to_mode(i),
     };
 
     let msg = query.message.clone();
@@ -417,11 +586,11 @@ impl BotExtension for Yeelight {
     let api1 = ctx.bot.clone();
     let notify_fut = api1.send(query.acknowledge()).then(|_| ok(()));
 
-    let api2 = ctx.bot.clone();
+    let errors = ctx.errors.clone();
     let query = query.clone();
     let notify_err_fut = move |e: Error| {
-      let req = query.answer(format!("Failed: {}", e));
-      api2.send(req).then(|_| ok(()))
+      errors.report_to_query("yeelight", &e, &query);
+      ok(())
     };
 
     let fut = control_fut
@@ -430,7 +599,7 @@ impl BotExtension for Yeelight {
       .or_else(notify_err_fut)
       .map(|_| ());
 
-    ctx.handle.spawn(fut);
+    ctx.spawn(self.name(), fut);
   }
 
   fn report(&self) -> String {
@@ -440,11 +609,48 @@ impl BotExtension for Yeelight {
     }
     state.as_ref().unwrap().report()
   }
+  fn commands(&self) -> Vec<Command> {
+    vec![panel_command(), add_mode_command(), del_mode_command()]
+  }
+
+  fn callback_role(&self) -> Role {
+    Role::Admin
+  }
+
   fn name(&self) -> &str {
-    "yeelight"
+    Self::NAME
   }
 }
 
+impl Named for Yeelight {
+  const NAME: &'static str = "yeelight";
+}
+
+fn panel_command() -> Command {
+  Command::new("yeelight", "yeelight control panel")
+    .usage("[mode]")
+    .arg("mode", "switch to this mode instead of showing the panel")
+    .requires(Role::Admin)
+}
+
+fn add_mode_command() -> Command {
+  Command::new("add_yeelight_mode", "add a mode to the yeelight panel")
+    .usage("<mode_name> - [<req>, <req>, ...]")
+    .arg("mode_name", "name of the button on the panel")
+    .arg(
+      "req",
+      "{\"method\": <method>, \"params\": [<param>, <param>, ...]}",
+    )
+    .requires(Role::Admin)
+}
+
+fn del_mode_command() -> Command {
+  Command::new("del_yeelight_mode", "remove a mode from the yeelight panel")
+    .usage("<mode_name>")
+    .arg("mode_name", "name of the mode to remove")
+    .requires(Role::Admin)
+}
+
 // impl Response {
 //   fn is_ok(&self) -> bool {
 //     true
@@ -511,9 +717,45 @@ impl State {
 impl Default for Yeelight {
   fn default() -> Self {
     Yeelight {
-      addr: env::var("YEELIGHT_ADDR").ok().and_then(|x| x.parse().ok()),
+      addr: None,
       modes: Yeelight::default_modes(),
       current_state: Arc::new(Mutex::new(None)),
+      modes_version: new_token(),
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_unreadable_modes_are_backed_up() {
+    let h = Harness::new();
+    h.ctx.db.insert_yeelight_mode("Night", 0, "[]").unwrap();
+    h.ctx.db.insert_yeelight_mode("Party", 0, "{").unwrap();
+
+    let yeelight = Yeelight::init(&h.ctx);
+    let names: Vec<_> = yeelight.modes.iter().map(|(n, _)| n).collect();
+    assert_eq!(names, vec!["Night"]);
+    let errors = h.ctx.errors.recent();
+    assert!(errors[0]
+      .message
+      .starts_with("Unreadable yeelight mode Party, moved to backup"));
+    assert_eq!(h.ctx.db.yeelight_modes().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_mode_switched_by_name() {
+    let mut h = Harness::new();
+    h.add_admin(USER_ID);
+    h.plug::<Yeelight>();
+    h.send_text(PRIVATE_CHAT, USER_ID, "/yeelight disco");
+    h.send_text(PRIVATE_CHAT, USER_ID, "/yeelight brightest");
+    let reqs = h.take_requests();
+    assert_eq!(reqs[0].text(), Some("Unknown mode: disco"));
+    // found, but there is no bulb to switch
+    assert!(reqs[1].text().unwrap().starts_with("Something went wrong"));
+  }
+}
@@ -24,13 +24,16 @@ pub extern crate serde_json;
 pub extern crate telegram_bot;
 pub extern crate tokio_core;
 
+#[cfg(feature = "music")]
 pub extern crate curl;
 pub extern crate hyper;
 pub extern crate hyper_rustls;
+pub extern crate toml;
 pub extern crate url;
 
 mod bot;
 mod common;
+mod config;
 mod context;
 mod context_extensions;
 mod db;
@@ -39,12 +42,17 @@ mod extensions;
 mod services;
 mod util;
 
+#[cfg(test)]
+mod testing;
+
 use crate::common::*;
+use crate::config::{Backend, ConfigError, DbConfig};
 use crate::context::Context;
+use crate::extensions::registry;
 
-const DEBUG: bool = false;
+use std::process;
 
-const TELEGRAM_DEFAULT_BIND: &str = "127.0.0.1:6407";
+const DEBUG: bool = false;
 
 fn main() {
   let mut core = reactor::Core::new().unwrap();
@@ -55,9 +63,34 @@ fn main() {
     return;
   }
 
+  if env::args().any(|arg| arg == "--list-plugins") {
+    for plugin in registry::plugins() {
+      println!("{:<20}{}", plugin.name, plugin.description);
+    }
+    return;
+  }
+
   // load env
   dotenv::dotenv().ok();
 
+  let config = load_config().unwrap_or_else(|e| {
+    eprintln!("Invalid configuration: {}", e);
+    process::exit(1)
+  });
+
+  if env::args().any(|arg| arg == "--db-status") {
+    if let Err(e) = print_db_status(&config.db.path) {
+      eprintln!("{}: {}", config.db.path, e);
+      process::exit(1);
+    }
+    return;
+  }
+
+  if let Err(e) = config.validate() {
+    eprintln!("Invalid configuration: {}", e);
+    process::exit(1);
+  }
+
   // make sure the logger lives long enough
   let logger = {
     use slog::*;
@@ -72,11 +105,12 @@ fn main() {
 
   info!(logger, "Initializing bot API");
   let bot = {
-    let token = env::var("TELEGRAM_BOT_TOKEN")
-      .expect("TELEGRAM_BOT_TOKEN env var not defined");
-    tg::Api::configure(token)
+    // validated by the config
+    let token = config.telegram.token.clone().unwrap();
+    let api = tg::Api::configure(token)
       .build(core.handle())
-      .expect("Failed building bot API")
+      .expect("Failed building bot API");
+    Bot::new(api, core.handle(), logger.clone())
   };
 
   let consume_updates = bot.consume_updates().and_then(|updates| {
@@ -85,29 +119,67 @@ fn main() {
   });
 
   info!(logger, "Initializing bot context");
-  let mut ctx = Context::new(bot.clone(), core.handle(), logger.clone());
-
-  use crate::extensions::*;
-  ctx.plug_ext::<history::Saver>();
-  ctx.plug_ext::<afk::Afk>();
-  ctx.plug_ext::<weather::Weather>();
-  ctx.plug_ext::<history::Searcher>();
-  ctx.plug_ext::<reminder::ReminderPool>();
-  ctx.plug_ext::<music::Music>();
-  ctx.plug_ext::<yeelight::Yeelight>();
-  ctx.plug_ext::<link_cleanser::LinkCleanser>();
+  let (db, migrated) = open_storage(&config.db).unwrap_or_else(|e| {
+    eprintln!("{}: {}", config.db.path, e);
+    process::exit(1)
+  });
+  for migration in migrated.applied {
+    info!(
+      logger,
+      "Applied migration {}: {}", migration.version, migration.name
+    );
+  }
+  let mut ctx =
+    Context::new(bot.clone(), core.handle(), logger.clone(), db, config);
+  for backup in migrated.backed_up {
+    ctx.errors.report("db", &backup);
+  }
 
+  // along with the sections the extensions failed to read
+  let mut errors = vec![];
+  if let Err(e) = registry::plug(&mut ctx) {
+    errors.push(e);
+  }
+  errors.extend(ctx.config.take_errors());
+  if !errors.is_empty() {
+    for e in errors {
+      eprintln!("Invalid configuration: {}", e);
+    }
+    process::exit(1);
+  }
+
+  ctx.publish_commands();
+
+  {
+    use crate::services::metrics;
+    let addr = ctx.config.metrics.bind;
+    match metrics::serve(&addr, &core.handle(), logger.clone()) {
+      Ok(()) => info!(logger, "Serving metrics on {}", addr),
+      Err(e) => error!(logger, "Failed to serve metrics on {}: {}", addr, e),
+    }
+  }
+
+  if let Some(token) = ctx.config.control.token.clone() {
+    let addr = ctx.config.control.bind;
+    match ctx.serve_control(&addr, token) {
+      Ok(()) => info!(logger, "Serving control API on {}", addr),
+      Err(e) => {
+        error!(logger, "Failed to serve control API on {}: {}", addr, e)
+      }
+    }
+  }
+
+  let consume = ctx.config.telegram.consume_updates;
   let serve = {
-    let webhook_callback = env::var("TELEGRAM_WEBHOOK_CALLBACK");
-    let bind = env::var("TELEGRAM_WEBHOOK_BIND")
-      .unwrap_or_else(|_| TELEGRAM_DEFAULT_BIND.into());
+    let webhook_callback = ctx.config.telegram.webhook_callback.clone();
+    let addr = ctx.config.telegram.webhook_bind;
 
-    if let Ok(callback_url) = webhook_callback {
+    if let Some(callback_url) = webhook_callback {
       info!(
         logger,
-        "Started serving with webhook at {}, bind on {}", callback_url, bind
+        "Started serving with webhook at {}, bind on {}", callback_url, addr
       );
-      ctx.serve_webhook(&callback_url, &bind)
+      ctx.serve_webhook(&callback_url, &addr)
     } else {
       info!(logger, "Started serving with long polling");
       ctx.serve_poll()
@@ -115,15 +187,71 @@ fn main() {
   };
 
   let future = {
-    match env::var("CONSUME_UPDATES") {
-      Ok(ref x) if x == "1" => Box::new(consume_updates.then(|_| serve)),
-      _ => serve,
+    if consume {
+      Box::new(consume_updates.then(|_| serve))
+    } else {
+      serve
     }
   };
 
   core.run(future).unwrap();
 }
 
+/// From `--config <path>` or `FONDBOT_CONFIG`, otherwise `fondbot.toml`
+/// if there is one
+fn load_config() -> std::result::Result<Config, ConfigError> {
+  let mut path = env::var("FONDBOT_CONFIG").ok();
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--config" {
+      path = args.next();
+    }
+  }
+
+  match path {
+    Some(path) => Config::load(&path, true),
+    None => Config::load(config::DEFAULT_PATH, false),
+  }
+}
+
+/// The backend chosen by the `[db]` section, and what migrating it did
+fn open_storage(config: &DbConfig) -> DbResult<(Box<Storage>, db::Migrated)> {
+  match config.backend {
+    Backend::Sqlite => {
+      let (db, migrated) = SqliteStorage::open(&config.path)?;
+      Ok((Box::new(db), migrated))
+    }
+    Backend::Memory => {
+      Ok((Box::new(MemoryStorage::default()), db::Migrated::default()))
+    }
+  }
+}
+
+/// The schema version of the database and the migrations pending
+fn print_db_status(path: &str) -> DbResult<()> {
+  let db = match SqliteStorage::connect_read_only(path)? {
+    Some(db) => db,
+    None => {
+      println!("No database at {}", path);
+      return Ok(());
+    }
+  };
+  let applied = db.applied_migrations()?;
+  let latest = db::MIGRATIONS.last().map_or(0, |m| m.version);
+  let version = applied.last().map_or(0, |m| m.version);
+  println!("Database {} at version {} of {}", path, version, latest);
+
+  for migration in db::MIGRATIONS {
+    let record = applied.iter().find(|m| m.version == migration.version);
+    let status = match record {
+      Some(m) => Local.timestamp(m.applied_at, 0).to_rfc3339(),
+      None => "pending".into(),
+    };
+    println!("{:>4} {:<50}{}", migration.version, migration.name, status);
+  }
+  Ok(())
+}
+
 #[allow(dead_code)]
 fn debug() {
   // let mut yee = extensions::yeelight::Yeelight {
@@ -0,0 +1,265 @@
+use crate::common::*;
+use crate::db::DbAudit;
+
+use std::str::FromStr;
+
+/// Entries shown by `/audit` unless asked otherwise
+const DEFAULT_LIMIT: usize = 20;
+
+/// Incidents of the same kind by the same user within this many minutes
+/// are recorded but not forwarded again
+const FORWARD_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuditKind {
+  /// An update from a chat or user not marked safe
+  Unsafe,
+  /// A button pressed in a chat not marked safe
+  UnsafeCallback,
+  /// A command or button the user's role is not enough for
+  Denied,
+  /// A command requiring admin or above, run by someone allowed to
+  Privileged,
+  /// A command run through the control API
+  Control,
+}
+
+impl AuditKind {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      AuditKind::Unsafe => "unsafe",
+      AuditKind::UnsafeCallback => "unsafe_callback",
+      AuditKind::Denied => "denied",
+      AuditKind::Privileged => "privileged",
+      AuditKind::Control => "control",
+    }
+  }
+
+  /// Whether the owner wants to hear about it as it happens
+  pub fn is_incident(self) -> bool {
+    match self {
+      AuditKind::Privileged | AuditKind::Control => false,
+      _ => true,
+    }
+  }
+}
+
+impl FromStr for AuditKind {
+  type Err = FondbotError;
+
+  fn from_str(s: &str) -> Result<AuditKind> {
+    match s {
+      "unsafe" => Ok(AuditKind::Unsafe),
+      "unsafe_callback" => Ok(AuditKind::UnsafeCallback),
+      "denied" => Ok(AuditKind::Denied),
+      "privileged" => Ok(AuditKind::Privileged),
+      "control" => Ok(AuditKind::Control),
+      _ => Err(format!("Unknown audit kind: {}", s).into()),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+  pub kind: AuditKind,
+  pub user_id: Option<tg::UserId>,
+  pub user_name: Option<String>,
+  pub chat_id: Option<tg::ChatId>,
+  pub detail: String,
+  pub created_at: DateTime<Local>,
+}
+
+impl AuditEntry {
+  pub fn new<S: Into<String>>(
+    kind: AuditKind,
+    user: &tg::User,
+    chat_id: Option<tg::ChatId>,
+    detail: S,
+  ) -> Self {
+    AuditEntry {
+      kind,
+      user_id: Some(user.id),
+      user_name: Some(user.first_name.clone()),
+      chat_id,
+      detail: detail.into(),
+      created_at: Local::now(),
+    }
+  }
+
+  pub fn message(kind: AuditKind, msg: &tg::Message) -> Self {
+    let detail = msg
+      .text_content()
+      .unwrap_or_else(|| "<non-text message>".into());
+    Self::new(kind, &msg.from, Some(msg.chat.id()), detail)
+  }
+
+  pub fn callback(kind: AuditKind, query: &tg::CallbackQuery) -> Self {
+    let chat = query.message.chat.id();
+    let detail = format!("callback {}", query.data);
+    Self::new(kind, &query.from, Some(chat), detail)
+  }
+
+  pub fn inline_query(kind: AuditKind, query: &tg::InlineQuery) -> Self {
+    let detail = format!("inline query {}", query.query);
+    Self::new(kind, &query.from, None, detail)
+  }
+
+  pub fn format(&self) -> String {
+    let user = match (self.user_id, self.user_name.as_ref()) {
+      (Some(id), Some(name)) => format!("{} ({})", name, id),
+      (Some(id), None) => id.to_string(),
+      _ => "unknown user".into(),
+    };
+    let chat = match self.chat_id {
+      Some(chat) => format!(" in {}", chat),
+      None => String::new(),
+    };
+    format!(
+      "{} [{}] {}{}: {}",
+      self.created_at.format("%m-%d %H:%M"),
+      self.kind.as_str(),
+      user,
+      chat,
+      ellipsis(&self.detail, 100)
+    )
+  }
+
+  fn to_db(&self) -> DbAudit {
+    DbAudit {
+      id: None,
+      kind: self.kind.as_str().into(),
+      user_id: self.user_id.map(Into::into),
+      user_name: self.user_name.clone(),
+      chat_id: self.chat_id.map(Into::into),
+      detail: self.detail.clone(),
+      created_at: self.created_at.timestamp(),
+    }
+  }
+
+  fn from_db(entry: DbAudit) -> Option<Self> {
+    Some(AuditEntry {
+      kind: entry.kind.parse().ok()?,
+      user_id: entry.user_id.map(tg::UserId::from),
+      user_name: entry.user_name,
+      chat_id: entry.chat_id.map(tg::ChatId::from),
+      detail: entry.detail,
+      created_at: Local.timestamp(entry.created_at, 0),
+    })
+  }
+}
+
+/// Filters of `/audit`, e.g. `/audit denied user:101 chat:-200 50`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditFilter {
+  pub kind: Option<AuditKind>,
+  pub user_id: Option<i64>,
+  pub chat_id: Option<i64>,
+  pub limit: usize,
+}
+
+impl Default for AuditFilter {
+  fn default() -> Self {
+    AuditFilter {
+      kind: None,
+      user_id: None,
+      chat_id: None,
+      limit: DEFAULT_LIMIT,
+    }
+  }
+}
+
+impl AuditFilter {
+  pub fn parse(args: &[String]) -> Result<AuditFilter> {
+    let mut filter = AuditFilter::default();
+    let invalid = |arg: &str| format!("Invalid filter: {}", arg);
+
+    for arg in args.iter().filter(|arg| !arg.is_empty()) {
+      if arg.starts_with("user:") {
+        let id = arg["user:".len()..].parse().map_err(|_| invalid(arg))?;
+        filter.user_id = Some(id);
+      } else if arg.starts_with("chat:") {
+        let id = arg["chat:".len()..].parse().map_err(|_| invalid(arg))?;
+        filter.chat_id = Some(id);
+      } else if let Ok(limit) = arg.parse() {
+        filter.limit = limit;
+      } else {
+        filter.kind = Some(arg.parse()?);
+      }
+    }
+    Ok(filter)
+  }
+}
+
+/// Persisted record of access decisions worth looking back at
+pub struct AuditLog<'a> {
+  db: &'a Storage,
+}
+
+impl<'a> AuditLog<'a> {
+  pub fn new(db: &'a Storage) -> Self {
+    AuditLog { db }
+  }
+
+  pub fn record(&self, entry: &AuditEntry) -> DbResult<()> {
+    self.db.insert_audit(&entry.to_db())
+  }
+
+  /// Latest matching entries first
+  pub fn query(&self, filter: &AuditFilter) -> DbResult<Vec<AuditEntry>> {
+    let kind = filter.kind.map(AuditKind::as_str);
+    let entries = self
+      .db
+      .audit_entries(kind, filter.user_id, filter.chat_id, filter.limit)?
+      .into_iter()
+      .filter_map(AuditEntry::from_db)
+      .collect();
+    Ok(entries)
+  }
+}
+
+/// Decides which incidents reach the audit chat, so that a stranger
+/// cannot flood it
+#[derive(Debug, Default)]
+pub struct IncidentThrottle {
+  /// When each kind of incident by a user was last forwarded
+  forwarded: RefCell<HashMap<(AuditKind, Option<tg::UserId>), DateTime<Local>>>,
+}
+
+impl IncidentThrottle {
+  /// False for a repeat of an incident forwarded recently
+  pub fn admit(&self, entry: &AuditEntry) -> bool {
+    let now = entry.created_at;
+    let window = Duration::minutes(FORWARD_MINUTES);
+    let mut forwarded = self.forwarded.borrow_mut();
+    forwarded.retain(|_, at| now - *at < window);
+
+    let key = (entry.kind, entry.user_id);
+    if forwarded.contains_key(&key) {
+      return false;
+    }
+    forwarded.insert(key, now);
+    true
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn args(s: &str) -> Vec<String> {
+    s.split(' ').map(Into::into).collect()
+  }
+
+  #[test]
+  fn test_audit_filter() {
+    let filter = AuditFilter::parse(&args("denied user:101 5")).unwrap();
+    assert_eq!(filter.kind, Some(AuditKind::Denied));
+    assert_eq!(filter.user_id, Some(101));
+    assert_eq!(filter.chat_id, None);
+    assert_eq!(filter.limit, 5);
+
+    assert_eq!(AuditFilter::parse(&[]).unwrap(), AuditFilter::default());
+    assert!(AuditFilter::parse(&args("chat:abc")).is_err());
+    assert!(AuditFilter::parse(&args("everything")).is_err());
+  }
+}
@@ -0,0 +1,203 @@
+use crate::bot::SYNTHETIC_MESSAGE_ID;
+use crate::common::*;
+use crate::services::http::{
+  self, respond, response, same_secret, ResponseFuture,
+};
+
+use futures::sync::{mpsc, oneshot};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, StatusCode};
+use serde_json::Value as JsonValue;
+
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Deserialize, Debug)]
+pub struct SendText {
+  pub chat_id: i64,
+  pub text: String,
+  #[serde(default)]
+  pub markdown: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RunCommand {
+  pub chat_id: i64,
+  /// Whose permissions the command runs with, one of `control.users`
+  pub user_id: i64,
+  pub text: String,
+}
+
+impl RunCommand {
+  /// The command as if `user_id`, named `name`, sent it. It is never
+  /// sent to the chat, see `SYNTHETIC_MESSAGE_ID`.
+  pub fn message(&self, name: &str) -> serde_json::Result<tg::Message> {
+    let chat = match self.chat_id {
+      id if id > 0 => json!({"id": id, "type": "private", "first_name": name}),
+      // supergroup ids are -100 followed by ten or more digits
+      id if id <= -1_000_000_000_000 => {
+        json!({"id": id, "type": "supergroup", "title": ""})
+      }
+      id => json!({"id": id, "type": "group", "title": ""}),
+    };
+    serde_json::from_value(json!({
+      "message_id": SYNTHETIC_MESSAGE_ID,
+      "from": {"id": self.user_id, "is_bot": false, "first_name": name},
+      "chat": chat,
+      "date": Local::now().timestamp(),
+      "text": self.text,
+    }))
+  }
+}
+
+#[derive(Debug)]
+pub enum Action {
+  Send(SendText),
+  Command(RunCommand),
+  Reports,
+}
+
+pub type Reply = std::result::Result<JsonValue, String>;
+
+/// A request to the control API, answered by `Context` on its reactor
+pub struct Call {
+  pub action: Action,
+  pub reply: oneshot::Sender<Reply>,
+}
+
+impl Call {
+  pub fn new(action: Action) -> (Self, oneshot::Receiver<Reply>) {
+    let (reply, replied) = oneshot::channel();
+    (Call { action, reply }, replied)
+  }
+}
+
+#[derive(Clone)]
+struct Endpoint {
+  token: String,
+  calls: mpsc::UnboundedSender<Call>,
+  logger: Logger,
+}
+
+impl Endpoint {
+  fn handle(&self, req: Request<Body>) -> ResponseFuture {
+    let token = req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .filter(|v| v.starts_with("Bearer "))
+      .map(|v| &v["Bearer ".len()..]);
+    let expected = self.token.as_bytes();
+    if !token.map_or(false, |t| same_secret(t.as_bytes(), expected)) {
+      warn!(self.logger, "Rejected control request without valid token");
+      return respond(StatusCode::UNAUTHORIZED, "");
+    }
+
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let this = self.clone();
+    let action = req.into_body().concat2().map(move |body| {
+      let parse = |e: serde_json::Error| e.to_string();
+      if method == Method::POST && path == "/send" {
+        serde_json::from_slice(&body)
+          .map(Action::Send)
+          .map_err(parse)
+      } else if method == Method::POST && path == "/command" {
+        serde_json::from_slice(&body)
+          .map(Action::Command)
+          .map_err(parse)
+      } else if method == Method::GET && path == "/reports" {
+        Ok(Action::Reports)
+      } else {
+        Err("Unknown endpoint".into())
+      }
+    });
+
+    Box::new(action.and_then(move |action| match action {
+      Ok(action) => this.call(action),
+      Err(e) => respond(StatusCode::BAD_REQUEST, json_error(&e).to_string()),
+    }))
+  }
+
+  fn call(&self, action: Action) -> ResponseFuture {
+    let (call, replied) = Call::new(action);
+    self.calls.unbounded_send(call).ok();
+    Box::new(replied.then(|reply| {
+      let (status, body) = match reply {
+        Ok(Ok(result)) => {
+          (StatusCode::OK, json!({"ok": true, "result": result}))
+        }
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, json_error(&e)),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, json_error("Bot stopped")),
+      };
+      ok::<_, hyper::Error>(response(status, body.to_string()))
+    }))
+  }
+}
+
+fn json_error(e: &str) -> JsonValue {
+  json!({"ok": false, "error": e})
+}
+
+/// Serve the control API on `addr`, requests bearing `token` come out
+/// as calls on `calls`
+pub fn serve(
+  addr: &SocketAddr,
+  token: String,
+  calls: mpsc::UnboundedSender<Call>,
+  handle: &reactor::Handle,
+  logger: Logger,
+) -> io::Result<()> {
+  let endpoint = Endpoint {
+    token,
+    calls,
+    logger: logger.clone(),
+  };
+  http::serve(addr, handle, logger, move |req| endpoint.handle(req))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::thread;
+
+  #[test]
+  fn test_control_endpoint() {
+    let (calls, received) = mpsc::unbounded();
+    let endpoint = Endpoint {
+      token: "t0ken".into(),
+      calls,
+      logger: Logger::root(slog::Discard, o!()),
+    };
+    let request = |token: &str, path: &str, body: &str| {
+      let mut req = Request::post(path);
+      req.header(AUTHORIZATION, format!("Bearer {}", token));
+      endpoint.handle(req.body(Body::from(body.to_string())).unwrap())
+    };
+
+    let status = |res: ResponseFuture| res.wait().unwrap().status();
+    let send = r#"{"chat_id": 1, "text": "hi"}"#;
+    assert_eq!(
+      status(request("nope", "/send", send)),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+      status(request("t0ken", "/send", "{}")),
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+      status(request("t0ken", "/nope", send)),
+      StatusCode::BAD_REQUEST
+    );
+
+    // answered once the context replies to the call
+    let pending = request("t0ken", "/send", send);
+    let pending = thread::spawn(move || status(pending));
+    let call = received.wait().next().unwrap().unwrap();
+    match call.action {
+      Action::Send(ref send) => assert_eq!(send.text, "hi"),
+      ref action => panic!("unexpected action {:?}", action),
+    }
+    call.reply.send(Ok(json!("queued"))).unwrap();
+    assert_eq!(pending.join().unwrap(), StatusCode::OK);
+  }
+}
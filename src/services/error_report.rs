@@ -0,0 +1,184 @@
+use crate::common::*;
+
+use std::rc::Rc;
+
+/// Reference shown to users, to find the error in the logs
+pub type ErrorRef = Token;
+
+/// Repeats within this many minutes are folded into the first report
+const DEDUP_MINUTES: i64 = 60;
+
+/// Errors kept for `ErrorReporter::recent`
+const MAX_RECORDS: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct ErrorRecord {
+  pub id: ErrorRef,
+  pub source: String,
+  pub message: String,
+  pub count: u32,
+  pub first_seen: DateTime<Local>,
+  pub last_seen: DateTime<Local>,
+}
+
+impl ErrorRecord {
+  pub fn format(&self) -> String {
+    let time = |t: &DateTime<Local>| t.format("%m-%d %H:%M").to_string();
+    format!(
+      "#{:x} in {}: {} ({} times, {} to {})",
+      self.id,
+      self.source,
+      self.message,
+      self.count,
+      time(&self.first_seen),
+      time(&self.last_seen)
+    )
+  }
+}
+
+struct Inner {
+  bot: Bot,
+  logger: Logger,
+  /// From `operator_chat` in the config
+  operator_chat: Option<tg::ChatId>,
+  records: RefCell<Vec<ErrorRecord>>,
+}
+
+/// Where extensions hand errors they cannot handle themselves. Errors
+/// are logged, repeats of the same error folded together, and new ones
+/// summarized to the operator chat if set.
+#[derive(Clone)]
+pub struct ErrorReporter {
+  inner: Rc<Inner>,
+}
+
+impl ErrorReporter {
+  pub fn new(
+    bot: Bot,
+    logger: Logger,
+    operator_chat: Option<tg::ChatId>,
+  ) -> Self {
+    let inner = Inner {
+      bot,
+      logger,
+      operator_chat,
+      records: RefCell::new(Vec::new()),
+    };
+    ErrorReporter {
+      inner: Rc::new(inner),
+    }
+  }
+
+  /// Record an error raised in `source`, returns its reference
+  pub fn report<E: Display>(&self, source: &str, error: &E) -> ErrorRef {
+    let message = error.to_string();
+    let now = Local::now();
+    let mut records = self.inner.records.borrow_mut();
+
+    let window = Duration::minutes(DEDUP_MINUTES);
+    let repeated = records.iter_mut().find(|r| {
+      r.source == source && r.message == message && now - r.last_seen < window
+    });
+    if let Some(record) = repeated {
+      record.count += 1;
+      record.last_seen = now;
+      warn!(
+        self.inner.logger,
+        "Error #{:x} in {} repeated ({} times): {}",
+        record.id,
+        source,
+        record.count,
+        message
+      );
+      return record.id;
+    }
+
+    let record = ErrorRecord {
+      id: new_token(),
+      source: source.into(),
+      message,
+      count: 1,
+      first_seen: now,
+      last_seen: now,
+    };
+    error!(
+      self.inner.logger,
+      "Error #{:x} in {}: {}", record.id, source, record.message
+    );
+    if let Some(chat) = self.inner.operator_chat {
+      let text = format!("Error {}", ellipsis(&record.format(), 300));
+      self.inner.bot.spawn(tg::SendMessage::new(chat, text));
+    }
+
+    let id = record.id;
+    if records.len() == MAX_RECORDS {
+      records.remove(0);
+    }
+    records.push(record);
+    id
+  }
+
+  /// Report the error and tell the sender of `msg` about it
+  pub fn report_to<E: Display>(
+    &self,
+    source: &str,
+    error: &E,
+    msg: &tg::Message,
+  ) -> ErrorRef {
+    let id = self.report(source, error);
+    self.inner.bot.reply_to(msg, user_notice(id));
+    id
+  }
+
+  /// Report the error and tell the user who pressed a button about it
+  pub fn report_to_query<E: Display>(
+    &self,
+    source: &str,
+    error: &E,
+    query: &tg::CallbackQuery,
+  ) -> ErrorRef {
+    let id = self.report(source, error);
+    self.inner.bot.spawn(query.answer(user_notice(id)));
+    id
+  }
+
+  /// The latest errors, oldest first
+  pub fn recent(&self) -> Vec<ErrorRecord> {
+    self.inner.records.borrow().clone()
+  }
+}
+
+fn user_notice(id: ErrorRef) -> String {
+  format!("Something went wrong (ref #{:x})", id)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_repeated_errors_are_folded() {
+    let mut h = Harness::new();
+    let errors = h.ctx.errors.clone();
+    let first = errors.report("music", &"connection refused");
+    let again = errors.report("music", &"connection refused");
+    let other = errors.report("weather", &"connection refused");
+    assert_eq!(first, again);
+    assert_ne!(first, other);
+
+    let recent = errors.recent();
+    assert_eq!(recent.len(), 2);
+    assert_eq!(recent[0].count, 2);
+
+    let msg = fixtures::message(1, PRIVATE_CHAT, USER_ID, "/music");
+    let msg: tg::Message = serde_json::from_value(msg).unwrap();
+    errors.report_to("music", &"connection refused", &msg);
+    h.settle();
+
+    let reqs = h.take_requests();
+    let notice = format!("Something went wrong (ref #{:x})", first);
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].text(), Some(notice.as_str()));
+  }
+}
@@ -0,0 +1,63 @@
+use crate::common::*;
+
+use hyper;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio_core::net::TcpListener;
+
+use std::io;
+use std::net::SocketAddr;
+
+pub type ResponseFuture =
+  Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+pub fn response<B: Into<Body>>(status: StatusCode, body: B) -> Response<Body> {
+  let mut response = Response::new(body.into());
+  *response.status_mut() = status;
+  response
+}
+
+pub fn respond<B: Into<Body>>(status: StatusCode, body: B) -> ResponseFuture {
+  Box::new(ok(response(status, body)))
+}
+
+/// Compare without bailing out on the first differing byte
+pub fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+  given.len() == expected.len()
+    && given
+      .iter()
+      .zip(expected)
+      .fold(0, |acc, (a, b)| acc | (a ^ b))
+      == 0
+}
+
+/// Serve HTTP/1 on `addr` with the reactor of `handle`, answering each
+/// request with `handler`
+pub fn serve<H>(
+  addr: &SocketAddr,
+  handle: &reactor::Handle,
+  logger: Logger,
+  handler: H,
+) -> io::Result<()>
+where
+  H: Fn(Request<Body>) -> ResponseFuture + Clone + 'static,
+{
+  let listener = TcpListener::bind(addr, handle)?;
+  let http = Http::new();
+  let conn_handle = handle.clone();
+  let conn_logger = logger.clone();
+  let server = listener
+    .incoming()
+    .for_each(move |(socket, _)| {
+      let logger = conn_logger.clone();
+      let conn = http
+        .serve_connection(socket, service_fn(handler.clone()))
+        .map_err(move |e| debug!(logger, "HTTP connection failed: {}", e));
+      conn_handle.spawn(conn);
+      Ok(())
+    })
+    .map_err(move |e| error!(logger, "HTTP listener failed: {}", e));
+  handle.spawn(server);
+  Ok(())
+}
@@ -0,0 +1,282 @@
+use crate::common::*;
+use crate::services::http::{self, respond, ResponseFuture};
+
+use hyper::{Body, Request, StatusCode};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds of the latency buckets, in seconds
+const BUCKETS: &[f64] = &[
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+  Counter,
+  Histogram,
+}
+
+/// A metric as exposed to Prometheus, samples are told apart by labels
+pub struct Family {
+  name: &'static str,
+  help: &'static str,
+  kind: Kind,
+}
+
+pub const UPDATES: Family = Family {
+  name: "fondbot_updates_total",
+  help: "Updates received, by kind",
+  kind: Kind::Counter,
+};
+
+pub const UPDATE_SECONDS: Family = Family {
+  name: "fondbot_update_duration_seconds",
+  help: "Time spent processing an update, by kind",
+  kind: Kind::Histogram,
+};
+
+pub const COMMANDS: Family = Family {
+  name: "fondbot_commands_total",
+  help: "Commands matched, by extension",
+  kind: Kind::Counter,
+};
+
+pub const API_REQUESTS: Family = Family {
+  name: "fondbot_api_requests_total",
+  help: "Bot API calls, by method and outcome",
+  kind: Kind::Counter,
+};
+
+pub const EXTERNAL_REQUESTS: Family = Family {
+  name: "fondbot_external_requests_total",
+  help: "Requests to external services, by service and outcome",
+  kind: Kind::Counter,
+};
+
+pub const EXTERNAL_SECONDS: Family = Family {
+  name: "fondbot_external_request_duration_seconds",
+  help: "Time taken by requests to external services, by service",
+  kind: Kind::Histogram,
+};
+
+const FAMILIES: &[&Family] = &[
+  &UPDATES,
+  &UPDATE_SECONDS,
+  &COMMANDS,
+  &API_REQUESTS,
+  &EXTERNAL_REQUESTS,
+  &EXTERNAL_SECONDS,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+  /// Observations per bucket, not cumulative
+  buckets: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+impl Histogram {
+  fn observe(&mut self, value: f64) {
+    if self.buckets.is_empty() {
+      self.buckets = vec![0; BUCKETS.len()];
+    }
+    if let Some(i) = BUCKETS.iter().position(|&le| value <= le) {
+      self.buckets[i] += 1;
+    }
+    self.sum += value;
+    self.count += 1;
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+  counters: BTreeMap<(&'static str, Labels), u64>,
+  histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+lazy_static! {
+  /// Shared by the whole process, the outbox and plain requests record
+  /// into it without a `Context` at hand
+  static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+impl Registry {
+  fn inc(&mut self, family: &Family, labels: &[(&'static str, &str)]) {
+    *self.counters.entry(key(family, labels)).or_insert(0) += 1;
+  }
+
+  fn observe(
+    &mut self,
+    family: &Family,
+    labels: &[(&'static str, &str)],
+    value: f64,
+  ) {
+    let histogram = self.histograms.entry(key(family, labels));
+    histogram.or_insert_with(Histogram::default).observe(value);
+  }
+
+  /// Everything in the Prometheus text format
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    for family in FAMILIES {
+      let kind = match family.kind {
+        Kind::Counter => "counter",
+        Kind::Histogram => "histogram",
+      };
+      writeln!(out, "# HELP {} {}", family.name, family.help).ok();
+      writeln!(out, "# TYPE {} {}", family.name, kind).ok();
+
+      let counters =
+        self.counters.iter().filter(|((n, _), _)| *n == family.name);
+      for ((name, labels), value) in counters {
+        let labels = format_labels(labels, None);
+        writeln!(out, "{}{} {}", name, labels, value).ok();
+      }
+
+      let histograms = self
+        .histograms
+        .iter()
+        .filter(|((n, _), _)| *n == family.name);
+      for ((name, labels), histogram) in histograms {
+        let mut cumulative = 0;
+        for (le, n) in BUCKETS.iter().zip(&histogram.buckets) {
+          cumulative += n;
+          let le = le.to_string();
+          let labels = format_labels(labels, Some(&le));
+          writeln!(out, "{}_bucket{} {}", name, labels, cumulative).ok();
+        }
+        let inf = format_labels(labels, Some("+Inf"));
+        let labels = format_labels(labels, None);
+        writeln!(out, "{}_bucket{} {}", name, inf, histogram.count).ok();
+        writeln!(out, "{}_sum{} {}", name, labels, histogram.sum).ok();
+        writeln!(out, "{}_count{} {}", name, labels, histogram.count).ok();
+      }
+    }
+    out
+  }
+}
+
+fn key(
+  family: &Family,
+  labels: &[(&'static str, &str)],
+) -> (&'static str, Labels) {
+  let labels = labels.iter().map(|&(k, v)| (k, v.to_string())).collect();
+  (family.name, labels)
+}
+
+fn format_labels(
+  labels: &[(&'static str, String)],
+  le: Option<&str>,
+) -> String {
+  let escape = |v: &str| {
+    v.replace('\\', "\\\\")
+      .replace('"', "\\\"")
+      .replace('\n', "\\n")
+  };
+  let mut pairs: Vec<_> = labels
+    .iter()
+    .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+    .collect();
+  if let Some(le) = le {
+    pairs.push(format!("le=\"{}\"", le));
+  }
+  if pairs.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", pairs.join(","))
+  }
+}
+
+impl Family {
+  pub fn inc(&self, labels: &[(&'static str, &str)]) {
+    REGISTRY.lock().unwrap().inc(self, labels)
+  }
+
+  pub fn observe(&self, labels: &[(&'static str, &str)], value: f64) {
+    REGISTRY.lock().unwrap().observe(self, labels, value)
+  }
+
+  /// Observe the time passed since `start`
+  pub fn observe_since(&self, labels: &[(&'static str, &str)], start: Instant) {
+    self.observe(labels, seconds(start.elapsed()))
+  }
+}
+
+fn seconds(d: std::time::Duration) -> f64 {
+  d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// Count the outcome and time of a request to an external `service`
+pub fn track<F: Future>(
+  service: &'static str,
+  future: F,
+) -> impl Future<Item = F::Item, Error = F::Error> {
+  let start = Instant::now();
+  future.then(move |result| {
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    EXTERNAL_REQUESTS.inc(&[("service", service), ("outcome", outcome)]);
+    EXTERNAL_SECONDS.observe_since(&[("service", service)], start);
+    result
+  })
+}
+
+/// Serve `/metrics` on `addr`
+pub fn serve(
+  addr: &SocketAddr,
+  handle: &reactor::Handle,
+  logger: Logger,
+) -> io::Result<()> {
+  http::serve(
+    addr,
+    handle,
+    logger,
+    |req: Request<Body>| -> ResponseFuture {
+      if req.uri().path() != "/metrics" {
+        return respond(StatusCode::NOT_FOUND, "");
+      }
+      let text = REGISTRY.lock().unwrap().render();
+      respond(StatusCode::OK, text)
+    },
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_render_prometheus_text() {
+    let mut registry = Registry::default();
+    registry.inc(&UPDATES, &[("kind", "message")]);
+    registry.inc(&UPDATES, &[("kind", "message")]);
+    registry.inc(&COMMANDS, &[("ext", "we\"ird")]);
+    registry.observe(&UPDATE_SECONDS, &[("kind", "message")], 0.02);
+    registry.observe(&UPDATE_SECONDS, &[("kind", "message")], 30.0);
+
+    let text = registry.render();
+    assert!(text.contains("# TYPE fondbot_updates_total counter\n"));
+    assert!(text.contains("fondbot_updates_total{kind=\"message\"} 2\n"));
+    assert!(text.contains("fondbot_commands_total{ext=\"we\\\"ird\"} 1\n"));
+    let seconds = |suffix: &str, le: Option<&str>, n: u64| {
+      let labels = match le {
+        Some(le) => format!("{{kind=\"message\",le=\"{}\"}}", le),
+        None => "{kind=\"message\"}".to_string(),
+      };
+      let sample = format!("{}{}", suffix, labels);
+      text.contains(&format!("update_duration_seconds{} {}\n", sample, n))
+    };
+    assert!(seconds("_bucket", Some("0.01"), 0));
+    assert!(seconds("_bucket", Some("0.025"), 1));
+    assert!(seconds("_bucket", Some("+Inf"), 2));
+    assert!(seconds("_count", None, 2));
+    // families without samples are still described
+    assert!(text.contains("# HELP fondbot_api_requests_total "));
+  }
+}
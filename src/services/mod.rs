@@ -1 +1,11 @@
+pub mod audit;
+pub mod control;
+pub mod error_report;
+pub mod http;
+pub mod metrics;
+pub mod outbox;
 pub mod request;
+pub mod scheduler;
+pub mod session;
+pub mod supervisor;
+pub mod webhook;
@@ -0,0 +1,542 @@
+use crate::common::*;
+use crate::services::metrics;
+
+use serde_json::Value as JsonValue;
+use std::cmp;
+use std::collections::{BTreeSet, VecDeque};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::{Duration as StdDuration, Instant};
+
+/// Sends of a request before it is given up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Failures kept for `Outbox::failures`
+const MAX_FAILURES: usize = 20;
+
+/// Minimal intervals between requests, after Telegram's bot FAQ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimits {
+  /// Between any two requests, about 30 per second
+  pub global: StdDuration,
+  /// Between messages to the same private chat
+  pub private_chat: StdDuration,
+  /// Between messages to the same group, 20 per minute
+  pub group_chat: StdDuration,
+}
+
+impl Default for RateLimits {
+  fn default() -> Self {
+    RateLimits {
+      global: StdDuration::from_millis(34),
+      private_chat: StdDuration::from_secs(1),
+      group_chat: StdDuration::from_secs(3),
+    }
+  }
+}
+
+impl RateLimits {
+  pub fn unlimited() -> Self {
+    RateLimits {
+      global: StdDuration::from_secs(0),
+      private_chat: StdDuration::from_secs(0),
+      group_chat: StdDuration::from_secs(0),
+    }
+  }
+
+  fn chat_interval(&self, chat: tg::ChatId) -> StdDuration {
+    if tg::Integer::from(chat) < 0 {
+      self.group_chat
+    } else {
+      self.private_chat
+    }
+  }
+}
+
+/// Hands out the earliest time each request may be sent at
+#[derive(Debug)]
+struct Limiter {
+  limits: RateLimits,
+  /// Reserved send times, kept apart by the global interval
+  slots: BTreeSet<Instant>,
+  chat_next: HashMap<tg::ChatId, Instant>,
+}
+
+impl Limiter {
+  fn new(limits: RateLimits) -> Self {
+    Limiter {
+      limits,
+      slots: BTreeSet::new(),
+      chat_next: HashMap::new(),
+    }
+  }
+
+  /// Reserve a slot for a request to `chat`, not earlier than `earliest`
+  fn reserve(
+    &mut self,
+    chat: Option<tg::ChatId>,
+    earliest: Instant,
+    now: Instant,
+  ) -> Instant {
+    let global = self.limits.global;
+    self.slots = self.slots.split_off(&(now - global));
+    self.chat_next.retain(|_, next| *next > now);
+
+    let mut at = earliest;
+    if let Some(&next) = chat.and_then(|chat| self.chat_next.get(&chat)) {
+      at = cmp::max(at, next);
+    }
+    // the first gap around `at` among the other reservations
+    for &slot in self.slots.range(at - global..) {
+      if slot >= at + global {
+        break;
+      }
+      at = slot + global;
+    }
+
+    self.slots.insert(at);
+    if let Some(chat) = chat {
+      self
+        .chat_next
+        .insert(chat, at + self.limits.chat_interval(chat));
+    }
+    at
+  }
+
+  /// Hold back `chat` as told by a flood-control error
+  fn hold_back(&mut self, chat: tg::ChatId, until: Instant) {
+    let next = self.chat_next.entry(chat).or_insert(until);
+    *next = cmp::max(*next, until);
+  }
+}
+
+/// A request serialized up front, so it can be sent again
+#[derive(Debug, Clone)]
+struct Serialized(tg::HttpRequest);
+
+impl tg::Request for Serialized {
+  type Type = tg::JsonRequestType<JsonValue>;
+  type Response = tg::JsonIdResponse<JsonValue>;
+
+  fn serialize(
+    &self,
+  ) -> std::result::Result<tg::HttpRequest, tg::types::Error> {
+    Ok(self.0.clone())
+  }
+}
+
+/// A request serialized up front by `Outbox::send`, answered as the
+/// original one
+struct Answered<Resp>(tg::HttpRequest, PhantomData<Resp>);
+
+impl<Resp: tg::ResponseType + 'static> tg::Request for Answered<Resp> {
+  type Type = tg::JsonRequestType<JsonValue>;
+  type Response = Resp;
+
+  fn serialize(
+    &self,
+  ) -> std::result::Result<tg::HttpRequest, tg::types::Error> {
+    Ok(self.0.clone())
+  }
+}
+
+/// What the bot API answers to `Req`
+pub type Response<Req> =
+  <<Req as tg::Request>::Response as tg::ResponseType>::Type;
+
+#[derive(Debug, Clone)]
+struct Pending {
+  request: Serialized,
+  method: String,
+  chat: Option<tg::ChatId>,
+  attempts: u32,
+}
+
+impl Pending {
+  fn new(request: tg::HttpRequest) -> Self {
+    let method = request.url.url("");
+    let method = method.rsplit('/').next().unwrap_or("").to_string();
+    let chat = match request.body {
+      tg::Body::Json(ref body) => serde_json::from_slice::<JsonValue>(body)
+        .ok()
+        .and_then(|body| body["chat_id"].as_i64())
+        .map(tg::ChatId::from),
+      _ => None,
+    };
+    Pending {
+      request: Serialized(request),
+      method,
+      chat,
+      attempts: 0,
+    }
+  }
+}
+
+/// What to do about a failed request
+#[derive(Debug, Clone, PartialEq)]
+enum Verdict {
+  /// Try again, after the given time if Telegram said so
+  Retry(Option<StdDuration>),
+  /// Nothing went wrong from the user's point of view
+  Ignore,
+  Fail,
+}
+
+fn verdict(e: &tg::Error) -> Verdict {
+  use crate::tg::types::ErrorKind as RawErrorKind;
+
+  match *e.kind() {
+    tg::ErrorKind::Raw(RawErrorKind::TelegramError {
+      ref description,
+      ref parameters,
+    }) => {
+      let retry_after = parameters.as_ref().and_then(|p| p.retry_after);
+      match retry_after {
+        Some(secs) => Verdict::Retry(Some(StdDuration::from_secs(secs as u64))),
+        // editing a panel to what it already shows
+        None if description.contains("message is not modified") => {
+          Verdict::Ignore
+        }
+        None => Verdict::Fail,
+      }
+    }
+    // Telegram got the request but its answer was unreadable, sending
+    // it again may post twice
+    tg::ErrorKind::Raw(_) => Verdict::Fail,
+    _ if not_sent(e) => Verdict::Retry(None),
+    _ => Verdict::Fail,
+  }
+}
+
+/// Whether a network error means the request never reached Telegram,
+/// i.e. it failed while connecting. Any later failure, a read timeout
+/// included, may come after Telegram acted on the request. The HTTP
+/// client's error types are not ours to match on, so this goes by the
+/// messages of the error and its causes.
+fn not_sent(e: &tg::Error) -> bool {
+  const NOT_SENT: &[&str] = &[
+    "connection refused",
+    "failed to lookup address",
+    "network is unreachable",
+    "no route to host",
+    // `ETIMEDOUT` of connect(2)
+    "connection timed out",
+    "canceled internally before starting",
+  ];
+  e.iter().any(|cause| {
+    let message = cause.to_string().to_lowercase();
+    NOT_SENT.iter().any(|s| message.contains(s))
+  })
+}
+
+/// Exponential backoff for retries Telegram gave no time for
+fn backoff(attempts: u32) -> StdDuration {
+  StdDuration::from_secs(1 << cmp::min(attempts.saturating_sub(1), 6))
+}
+
+fn count(method: &str, outcome: &str) {
+  metrics::API_REQUESTS.inc(&[("method", method), ("outcome", outcome)]);
+}
+
+/// A request given up on
+#[derive(Debug, Clone)]
+pub struct SendFailure {
+  pub method: String,
+  pub chat: Option<tg::ChatId>,
+  pub attempts: u32,
+  pub error: String,
+  pub at: DateTime<Local>,
+}
+
+impl SendFailure {
+  pub fn format(&self) -> String {
+    format!("{} {}", self.at.format("%m-%d %H:%M"), self.summary())
+  }
+
+  /// Without the time, so repeats are folded by the `ErrorReporter`
+  fn summary(&self) -> String {
+    let chat = match self.chat {
+      Some(chat) => format!(" to {}", chat),
+      None => String::new(),
+    };
+    format!(
+      "{}{} after {} attempts: {}",
+      self.method, chat, self.attempts, self.error
+    )
+  }
+}
+
+struct Inner {
+  api: tg::Api,
+  handle: reactor::Handle,
+  logger: Logger,
+  limiter: RefCell<Limiter>,
+  failures: RefCell<VecDeque<SendFailure>>,
+  /// Set once the reporter, which sends through this outbox, exists
+  errors: RefCell<Option<ErrorReporter>>,
+}
+
+/// Queue of fire-and-forget requests. Requests are sent within
+/// Telegram's rate limits, retried with backoff on flood control and
+/// network errors, and reported and kept in `failures` when given up.
+#[derive(Clone)]
+pub struct Outbox {
+  inner: Rc<Inner>,
+}
+
+impl Outbox {
+  pub fn new(api: tg::Api, handle: reactor::Handle, logger: Logger) -> Self {
+    let inner = Inner {
+      api,
+      handle,
+      logger,
+      limiter: RefCell::new(Limiter::new(RateLimits::default())),
+      failures: RefCell::new(VecDeque::new()),
+      errors: RefCell::new(None),
+    };
+    Outbox {
+      inner: Rc::new(inner),
+    }
+  }
+
+  pub fn set_limits(&self, limits: RateLimits) {
+    self.inner.limiter.borrow_mut().limits = limits;
+  }
+
+  /// Report the requests given up on to `errors`
+  pub fn set_errors(&self, errors: ErrorReporter) {
+    *self.inner.errors.borrow_mut() = Some(errors);
+  }
+
+  /// The latest requests given up on, oldest first
+  pub fn failures(&self) -> Vec<SendFailure> {
+    self.inner.failures.borrow().iter().cloned().collect()
+  }
+
+  pub fn push<Req: tg::Request>(&self, request: Req) {
+    match request.serialize() {
+      Ok(request) => self.schedule(Pending::new(request), Instant::now()),
+      Err(e) => self.fail(SendFailure {
+        method: "unknown".into(),
+        chat: None,
+        attempts: 0,
+        error: format!("Invalid request: {}", e),
+        at: Local::now(),
+      }),
+    }
+  }
+
+  /// Send a request whose response is needed. It waits for its turn
+  /// and is counted like the queued ones, but is not retried; failures
+  /// are left to the caller.
+  pub fn send<Req: tg::Request>(
+    &self,
+    request: Req,
+  ) -> Box<Future<Item = Response<Req>, Error = tg::Error>> {
+    let pending = match request.serialize() {
+      Ok(request) => Pending::new(request),
+      Err(e) => {
+        count("unknown", "failed");
+        return Box::new(err(e.into()));
+      }
+    };
+    let api = self.inner.api.clone();
+    let Pending {
+      request: Serialized(request),
+      method,
+      chat,
+      ..
+    } = pending;
+    let answered = Answered::<Req::Response>(request, PhantomData);
+    let send = self.slot(chat, Instant::now()).then(move |_| {
+      api.send(answered).then(move |result| {
+        count(&method, if result.is_ok() { "ok" } else { "failed" });
+        result
+      })
+    });
+    Box::new(send)
+  }
+
+  fn schedule(&self, pending: Pending, earliest: Instant) {
+    let this = self.clone();
+    let send = self
+      .slot(pending.chat, earliest)
+      .and_then(move |_| this.attempt(pending));
+    self.inner.handle.spawn(send);
+  }
+
+  /// Resolves when a request to `chat` is due within the rate limits
+  fn slot(
+    &self,
+    chat: Option<tg::ChatId>,
+    earliest: Instant,
+  ) -> Box<Future<Item = (), Error = ()>> {
+    let now = Instant::now();
+    let at = {
+      let mut limiter = self.inner.limiter.borrow_mut();
+      limiter.reserve(chat, earliest, now)
+    };
+    if at <= now {
+      Box::new(ok(()))
+    } else {
+      match reactor::Timeout::new_at(at, &self.inner.handle) {
+        Ok(timeout) => Box::new(timeout.map_err(|_| ())),
+        Err(_) => Box::new(ok(())),
+      }
+    }
+  }
+
+  fn attempt(
+    &self,
+    mut pending: Pending,
+  ) -> impl Future<Item = (), Error = ()> {
+    pending.attempts += 1;
+    let this = self.clone();
+    let request = pending.request.clone();
+    self.inner.api.send(request).then(move |result| {
+      match result {
+        Ok(_) => count(&pending.method, "ok"),
+        Err(e) => this.on_error(pending, &e),
+      }
+      Ok(())
+    })
+  }
+
+  fn on_error(&self, pending: Pending, e: &tg::Error) {
+    let logger = &self.inner.logger;
+    match verdict(e) {
+      Verdict::Ignore => {
+        count(&pending.method, "ignored");
+        trace!(logger, "Ignored failure of {}: {}", pending.method, e)
+      }
+      Verdict::Retry(wait) if pending.attempts < MAX_ATTEMPTS => {
+        let until = Instant::now() + wait.unwrap_or(backoff(pending.attempts));
+        if let (Some(_), Some(chat)) = (wait, pending.chat) {
+          self.inner.limiter.borrow_mut().hold_back(chat, until);
+        }
+        count(&pending.method, "retried");
+        warn!(
+          logger,
+          "Retrying {} (attempt {}): {}", pending.method, pending.attempts, e
+        );
+        self.schedule(pending, until);
+      }
+      _ => self.fail(SendFailure {
+        method: pending.method,
+        chat: pending.chat,
+        attempts: pending.attempts,
+        error: e.to_string(),
+        at: Local::now(),
+      }),
+    }
+  }
+
+  fn fail(&self, failure: SendFailure) {
+    count(&failure.method, "failed");
+    match *self.inner.errors.borrow() {
+      Some(ref errors) => {
+        errors.report("outbox", &format!("Gave up {}", failure.summary()));
+      }
+      None => error!(self.inner.logger, "Gave up {}", failure.format()),
+    }
+    let mut failures = self.inner.failures.borrow_mut();
+    if failures.len() == MAX_FAILURES {
+      failures.pop_front();
+    }
+    failures.push_back(failure);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  #[test]
+  fn test_rate_limits() {
+    let limits = RateLimits::default();
+    let mut limiter = Limiter::new(limits);
+    let now = Instant::now();
+    let private = Some(tg::ChatId::from(100));
+    let group = Some(tg::ChatId::from(-200));
+
+    let mut reserve = |chat| limiter.reserve(chat, now, now);
+
+    assert_eq!(reserve(private), now);
+    assert_eq!(reserve(group), now + limits.global);
+    assert_eq!(reserve(private), now + limits.private_chat);
+    assert_eq!(reserve(group), now + limits.global + limits.group_chat);
+    // fits in between the earlier reservations
+    assert_eq!(reserve(None), now + limits.global * 2);
+
+    // flood control holds back only the chat it was about
+    let later = now + StdDuration::from_secs(30);
+    limiter.hold_back(private.unwrap(), later);
+    let mut reserve = |chat| limiter.reserve(chat, now, now);
+    assert_eq!(reserve(private), later);
+    assert_eq!(reserve(group), now + limits.global + limits.group_chat * 2);
+  }
+
+  #[test]
+  fn test_verdict() {
+    use crate::tg::types::ErrorKind as RawErrorKind;
+
+    let network = |message: &str| verdict(&tg::Error::from(message));
+    let refused = network("Connection refused (os error 111)");
+    assert_eq!(refused, Verdict::Retry(None));
+    let unreachable = network("Network is unreachable (os error 101)");
+    assert_eq!(unreachable, Verdict::Retry(None));
+    // the request may have been read before the answer timed out
+    assert_eq!(network("Timeout"), Verdict::Fail);
+    assert_eq!(network("operation timed out"), Verdict::Fail);
+    assert_eq!(
+      network("connection closed before message completed"),
+      Verdict::Fail
+    );
+
+    // unreadable answer to a request Telegram acted on
+    let decode = RawErrorKind::Msg("expected value at line 1 column 1".into());
+    let decode = tg::Error::from_kind(tg::ErrorKind::Raw(decode));
+    assert_eq!(verdict(&decode), Verdict::Fail);
+  }
+
+  #[test]
+  fn test_failures_are_reported() {
+    let mut h = Harness::new();
+    let blocked = tg::ChatId::from(BLOCKED_CHAT);
+    h.ctx.bot.spawn(tg::SendMessage::new(blocked, "hello"));
+    h.settle();
+
+    let failures = h.ctx.bot.outbox().failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].attempts, 1);
+    let errors = h.ctx.errors.recent();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].source, "outbox");
+    assert!(errors[0].message.starts_with("Gave up sendMessage to -403"));
+    assert!(errors[0].message.contains("bot was blocked by the user"));
+  }
+
+  #[test]
+  fn test_sent_requests_are_answered() {
+    let mut h = Harness::new();
+    let chat = tg::ChatId::from(PRIVATE_CHAT);
+    let send = h.ctx.bot.send(tg::SendMessage::new(chat, "hello"));
+    let msg = h.core.run(send).unwrap();
+    assert_eq!(msg.text_content(), Some("hello".into()));
+
+    // not retried nor reported, the caller sees the error
+    let blocked = tg::ChatId::from(BLOCKED_CHAT);
+    let send = h.ctx.bot.send(tg::SendMessage::new(blocked, "hello"));
+    assert!(h.core.run(send).is_err());
+    assert_eq!(h.take_requests().len(), 2);
+    assert!(h.ctx.bot.outbox().failures().is_empty());
+  }
+
+  #[test]
+  fn test_backoff() {
+    assert_eq!(backoff(1), StdDuration::from_secs(1));
+    assert_eq!(backoff(3), StdDuration::from_secs(4));
+    assert_eq!(backoff(100), StdDuration::from_secs(64));
+  }
+}
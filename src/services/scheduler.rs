@@ -0,0 +1,388 @@
+use crate::common::*;
+use crate::db::DbJob;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use serde_json;
+
+pub type JobId = i32;
+
+/// When a job runs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Trigger {
+  Once(DateTime<Local>),
+  /// Five-field cron expression: minute, hour, day of month, month and
+  /// day of week
+  Cron(String),
+}
+
+impl Trigger {
+  pub fn cron(expr: &str) -> Result<Trigger> {
+    CronSchedule::parse(expr)?;
+    Ok(Trigger::Cron(expr.into()))
+  }
+
+  /// The first run strictly after `after`, if any
+  pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+    match *self {
+      Trigger::Once(at) if at > after => Some(at),
+      Trigger::Once(_) => None,
+      Trigger::Cron(ref expr) => {
+        CronSchedule::parse(expr).ok()?.next_after(after)
+      }
+    }
+  }
+}
+
+/// A scheduled job, handed back to the owning extension's
+/// `process_job` when due
+#[derive(Debug, Clone)]
+pub struct Job {
+  pub id: JobId,
+  pub ext: String,
+  pub key: Option<String>,
+  pub trigger: Trigger,
+  pub next_run: DateTime<Local>,
+  payload: String,
+}
+
+impl Job {
+  pub fn payload<T: DeserializeOwned>(&self) -> Option<T> {
+    serde_json::from_str(&self.payload).ok()
+  }
+
+  fn from_db(job: DbJob) -> Option<Job> {
+    Some(Job {
+      id: job.id?,
+      trigger: serde_json::from_str(&job.schedule).ok()?,
+      next_run: Local.timestamp(job.next_run, 0),
+      ext: job.ext,
+      key: job.key,
+      payload: job.payload,
+    })
+  }
+}
+
+/// Jobs persisted in the database, so they survive restarts. Due jobs
+/// are collected by `Context` on every tick.
+pub struct Scheduler<'a> {
+  db: &'a Storage,
+}
+
+impl<'a> Scheduler<'a> {
+  pub fn new(db: &'a Storage) -> Self {
+    Scheduler { db }
+  }
+
+  pub fn schedule<T: Serialize>(
+    &self,
+    ext: &str,
+    trigger: Trigger,
+    payload: &T,
+  ) -> Result<JobId> {
+    self.insert(ext, None, trigger, payload)
+  }
+
+  /// Schedule a job under `key`, for recurring jobs registered on every
+  /// start. The extension's job of that key is kept as is if it has the
+  /// same trigger and payload, and replaced otherwise.
+  pub fn schedule_unique<T: Serialize>(
+    &self,
+    ext: &str,
+    key: &str,
+    trigger: Trigger,
+    payload: &T,
+  ) -> Result<JobId> {
+    let existing = self
+      .jobs(ext)?
+      .into_iter()
+      .find(|job| job.key.as_ref().map(String::as_str) == Some(key));
+    if let Some(job) = existing {
+      let same_payload =
+        serde_json::to_string(payload).ok() == Some(job.payload);
+      if job.trigger == trigger && same_payload {
+        return Ok(job.id);
+      }
+    }
+    self.insert(ext, Some(key), trigger, payload)
+  }
+
+  /// False if there was no such job
+  pub fn cancel(&self, id: JobId) -> Result<bool> {
+    Ok(self.db.delete_job(id)?)
+  }
+
+  pub fn jobs(&self, ext: &str) -> Result<Vec<Job>> {
+    let jobs = self.db.ext_jobs(ext)?.into_iter();
+    Ok(jobs.filter_map(Job::from_db).collect())
+  }
+
+  /// Jobs due by `now`. They stay due until passed to `done`, so a job
+  /// whose extension is not loaded waits for it.
+  pub fn due(&self, now: DateTime<Local>) -> Result<Vec<Job>> {
+    let mut due = Vec::new();
+    for db_job in self.db.due_jobs(now.timestamp())? {
+      let id = db_job.id;
+      let job = match Job::from_db(db_job) {
+        Some(job) => job,
+        None => {
+          // unreadable, drop it instead of failing on every tick
+          if let Some(id) = id {
+            self.db.delete_job(id)?;
+          }
+          continue;
+        }
+      };
+      due.push(job);
+    }
+    Ok(due)
+  }
+
+  /// Remove a one-shot job that ran at `now`, or move a recurring one to
+  /// its next run, so a job missed while the bot was down runs once
+  pub fn done(&self, job: &Job, now: DateTime<Local>) -> Result<()> {
+    match job.trigger.next_after(now) {
+      Some(next) => self.db.reschedule_job(job.id, next.timestamp())?,
+      None => {
+        self.db.delete_job(job.id)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Put off a due job its extension cannot run now
+  pub fn defer(&self, job: &Job, until: DateTime<Local>) -> Result<()> {
+    Ok(self.db.reschedule_job(job.id, until.timestamp())?)
+  }
+
+  fn insert<T: Serialize>(
+    &self,
+    ext: &str,
+    key: Option<&str>,
+    trigger: Trigger,
+    payload: &T,
+  ) -> Result<JobId> {
+    let next_run = trigger
+      .next_after(Local::now())
+      .ok_or_else(|| format!("Trigger never fires: {:?}", trigger))?;
+    let to_json = |e: serde_json::Error| format!("Invalid job: {}", e);
+    let job = DbJob {
+      id: None,
+      ext: ext.into(),
+      key: key.map(Into::into),
+      schedule: serde_json::to_string(&trigger).map_err(to_json)?,
+      payload: serde_json::to_string(payload).map_err(to_json)?,
+      next_run: next_run.timestamp(),
+    };
+    Ok(self.db.insert_job(&job)?)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CronSchedule {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  // as in cron, days match either field if both are restricted
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl CronSchedule {
+  fn parse(expr: &str) -> Result<CronSchedule> {
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    if fields.len() != 5 {
+      return Err(format!("Expected 5 fields in cron: {}", expr).into());
+    }
+
+    let field = |i: usize, min: u32, max: u32| {
+      parse_cron_field(fields[i], min, max)
+        .ok_or_else(|| format!("Invalid cron field: {}", fields[i]))
+    };
+    let weekdays = field(4, 0, 7)?;
+
+    Ok(CronSchedule {
+      minutes: field(0, 0, 59)?,
+      hours: field(1, 0, 23)?,
+      days: field(2, 1, 31)?,
+      months: field(3, 1, 12)?,
+      // both 0 and 7 are sunday
+      weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+      any_day: fields[2] == "*",
+      any_weekday: fields[4] == "*",
+    })
+  }
+
+  fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+    let start = after.naive_local().with_second(0)?.with_nanosecond(0)?;
+    let mut t = start + Duration::minutes(1);
+    let limit = start + Duration::days(5 * 366);
+
+    while t < limit {
+      if !has_bit(self.months, t.month()) {
+        let (y, m) = (t.year(), t.month());
+        let (y, m) = if m == 12 { (y + 1, 1) } else { (y, m + 1) };
+        t = NaiveDate::from_ymd(y, m, 1).and_hms(0, 0, 0);
+      } else if !self.day_matches(&t) {
+        t = t.date().succ().and_hms(0, 0, 0);
+      } else if !has_bit(self.hours, t.hour()) {
+        t = t.with_minute(0)? + Duration::hours(1);
+      } else if !has_bit(self.minutes, t.minute()) {
+        t += Duration::minutes(1);
+      } else if let Some(dt) = Local.from_local_datetime(&t).earliest() {
+        return Some(dt);
+      } else {
+        // skipped by a DST transition
+        t += Duration::minutes(1);
+      }
+    }
+    None
+  }
+
+  fn day_matches(&self, t: &NaiveDateTime) -> bool {
+    let day = has_bit(self.days, t.day());
+    let weekday = has_bit(self.weekdays, t.weekday().num_days_from_sunday());
+    match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    }
+  }
+}
+
+fn has_bit(set: u64, n: u32) -> bool {
+  set & (1 << n) != 0
+}
+
+/// Parse `*`, `n`, `a-b`, with optional `/step`, separated by commas
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Option<u64> {
+  let mut set = 0;
+  for part in field.split(',') {
+    let mut it = part.splitn(2, '/');
+    let range = it.next()?;
+    let step = match it.next() {
+      Some(step) => step.parse::<u32>().ok().filter(|&s| s > 0)?,
+      None => 1,
+    };
+    let (lo, hi) = match range {
+      "*" => (min, max),
+      _ if range.contains('-') => {
+        let mut bounds = range.splitn(2, '-');
+        let lo = bounds.next()?.parse().ok()?;
+        (lo, bounds.next()?.parse().ok()?)
+      }
+      _ if step > 1 => (range.parse().ok()?, max),
+      _ => {
+        let n = range.parse().ok()?;
+        (n, n)
+      }
+    };
+    if lo < min || hi > max || lo > hi {
+      return None;
+    }
+    for n in (lo..=hi).step_by(step as usize) {
+      set |= 1 << n;
+    }
+  }
+  Some(set)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn at(s: &str) -> DateTime<Local> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+    Local.from_local_datetime(&naive).unwrap()
+  }
+
+  #[test]
+  fn test_cron_next_run() {
+    let next = |expr: &str, after: &str| {
+      Trigger::cron(expr).unwrap().next_after(at(after)).unwrap()
+    };
+    assert_eq!(
+      next("* * * * *", "2019-04-01 10:00"),
+      at("2019-04-01 10:01")
+    );
+    assert_eq!(
+      next("*/15 9-17 * * *", "2019-04-01 17:50"),
+      at("2019-04-02 09:00")
+    );
+    // 2019-04-06 is a saturday
+    assert_eq!(
+      next("30 8 * * 1-5", "2019-04-05 09:00"),
+      at("2019-04-08 08:30")
+    );
+    assert_eq!(
+      next("0 0 1 1 *", "2019-04-01 00:00"),
+      at("2020-01-01 00:00")
+    );
+    assert_eq!(
+      next("0 12 * * 7", "2019-04-01 00:00"),
+      at("2019-04-07 12:00")
+    );
+
+    assert!(Trigger::cron("* * *").is_err());
+    assert!(Trigger::cron("61 * * * *").is_err());
+    assert!(Trigger::cron("*/0 * * * *").is_err());
+  }
+
+  #[test]
+  fn test_due_jobs() {
+    let db = SqliteStorage::in_memory();
+    let scheduler = Scheduler::new(&db);
+    let now = Local::now();
+    let once = Trigger::Once(now + Duration::minutes(10));
+    let once = scheduler.schedule("reminder", once, &"tea").unwrap();
+    let cron = Trigger::cron("* * * * *").unwrap();
+    let first = scheduler
+      .schedule_unique("yeelight", "refresh", cron.clone(), &())
+      .unwrap();
+    let cron = scheduler
+      .schedule_unique("yeelight", "refresh", cron, &())
+      .unwrap();
+    assert_eq!(cron, first);
+    assert_eq!(scheduler.jobs("yeelight").unwrap().len(), 1);
+
+    let due = scheduler.due(now + Duration::minutes(2)).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].id, cron);
+
+    // both due, until they are done
+    let later = now + Duration::minutes(11);
+    let due = scheduler.due(later).unwrap();
+    assert_eq!(due.len(), 2);
+    for job in &due {
+      scheduler.done(job, later).unwrap();
+    }
+    let job = due.iter().find(|job| job.id == once).unwrap();
+    assert_eq!(job.payload::<String>(), Some("tea".into()));
+    assert!(scheduler.jobs("reminder").unwrap().is_empty());
+    assert_eq!(scheduler.jobs("yeelight").unwrap().len(), 1);
+    assert!(scheduler.due(later).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_changed_unique_job_is_replaced() {
+    let db = SqliteStorage::in_memory();
+    let scheduler = Scheduler::new(&db);
+    let hourly = Trigger::cron("0 * * * *").unwrap();
+    let daily = Trigger::cron("0 0 * * *").unwrap();
+    let first = scheduler
+      .schedule_unique("yeelight", "refresh", hourly, &())
+      .unwrap();
+    let second = scheduler
+      .schedule_unique("yeelight", "refresh", daily.clone(), &())
+      .unwrap();
+    assert_ne!(first, second);
+    let jobs = scheduler.jobs("yeelight").unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].trigger, daily);
+
+    let later = Local::now() + Duration::days(2);
+    scheduler.defer(&jobs[0], later).unwrap();
+    let jobs = scheduler.jobs("yeelight").unwrap();
+    assert_eq!(jobs[0].next_run.timestamp(), later.timestamp());
+  }
+}
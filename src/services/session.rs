@@ -0,0 +1,185 @@
+use crate::common::*;
+
+/// A conversation with one user in one chat
+pub type SessionKey = (tg::ChatId, tg::UserId);
+
+pub trait ToSessionKey {
+  fn session_key(&self) -> SessionKey;
+}
+
+impl ToSessionKey for SessionKey {
+  fn session_key(&self) -> SessionKey {
+    *self
+  }
+}
+
+impl ToSessionKey for tg::Message {
+  fn session_key(&self) -> SessionKey {
+    (self.chat.id(), self.from.id)
+  }
+}
+
+impl ToSessionKey for tg::CallbackQuery {
+  fn session_key(&self) -> SessionKey {
+    (self.message.chat.id(), self.from.id)
+  }
+}
+
+struct Session<T> {
+  value: T,
+  token: Token,
+  expires_at: DateTime<Local>,
+}
+
+/// Per-user conversation state which expires after a period of
+/// inactivity
+pub struct SessionStore<T> {
+  sessions: HashMap<SessionKey, Session<T>>,
+  ttl: Duration,
+}
+
+impl<T> SessionStore<T> {
+  pub fn new(ttl: Duration) -> Self {
+    SessionStore {
+      sessions: HashMap::new(),
+      ttl,
+    }
+  }
+
+  /// Start a new session, replacing the previous one of the same user
+  pub fn start<K: ToSessionKey>(&mut self, key: &K, value: T) -> &mut T {
+    self.start_with(key, |_| value)
+  }
+
+  /// Start a new session whose state needs the session token, e.g. to
+  /// render callback buttons. Panels of the replaced session expire.
+  pub fn start_with<K, F>(&mut self, key: &K, init: F) -> &mut T
+  where
+    K: ToSessionKey,
+    F: FnOnce(Token) -> T,
+  {
+    self.purge_expired();
+    let key = key.session_key();
+    let token = new_token();
+    let session = Session {
+      value: init(token),
+      token,
+      expires_at: Local::now() + self.ttl,
+    };
+    self.sessions.insert(key, session);
+    &mut self.sessions.get_mut(&key).unwrap().value
+  }
+
+  pub fn token<K: ToSessionKey>(&self, key: &K) -> Option<Token> {
+    let now = Local::now();
+    self
+      .sessions
+      .get(&key.session_key())
+      .filter(|session| session.expires_at > now)
+      .map(|session| session.token)
+  }
+
+  pub fn contains<K: ToSessionKey>(&self, key: &K) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn get<K: ToSessionKey>(&self, key: &K) -> Option<&T> {
+    let now = Local::now();
+    self
+      .sessions
+      .get(&key.session_key())
+      .filter(|session| session.expires_at > now)
+      .map(|session| &session.value)
+  }
+
+  /// Get the session for modification, which also extends its lifetime
+  pub fn get_mut<K: ToSessionKey>(&mut self, key: &K) -> Option<&mut T> {
+    let now = Local::now();
+    let ttl = self.ttl;
+    self
+      .sessions
+      .get_mut(&key.session_key())
+      .filter(|session| session.expires_at > now)
+      .map(|session| {
+        session.expires_at = now + ttl;
+        &mut session.value
+      })
+  }
+
+  /// The session a callback button belongs to, `None` if the button was
+  /// rendered for an earlier or expired session
+  pub fn get_by_callback(
+    &mut self,
+    query: &tg::CallbackQuery,
+  ) -> Option<&mut T> {
+    if query.token().is_none() || query.token() != self.token(query) {
+      return None;
+    }
+    self.get_mut(query)
+  }
+
+  /// End the session, returning its state if not yet expired
+  pub fn take<K: ToSessionKey>(&mut self, key: &K) -> Option<T> {
+    let now = Local::now();
+    self
+      .sessions
+      .remove(&key.session_key())
+      .filter(|session| session.expires_at > now)
+      .map(|session| session.value)
+  }
+
+  fn purge_expired(&mut self) {
+    let now = Local::now();
+    self.sessions.retain(|_, session| session.expires_at > now);
+  }
+}
+
+impl<B: InteractiveBuilder> SessionStore<B> {
+  /// Feed a message to the sender's builder, ending the session and
+  /// returning the result once the builder is ready
+  pub fn feed_message(
+    &mut self,
+    msg: &tg::Message,
+    ctx: &Context,
+  ) -> Option<B::Target> {
+    let ready = {
+      let builder = self.get_mut(msg)?;
+      builder.on_message(msg, ctx);
+      builder.ready()
+    };
+    self.finish(msg, ready)
+  }
+
+  /// Feed a callback query to the sender's builder, ending the session and
+  /// returning the result once the builder is ready. Clicks on panels of
+  /// other sessions are answered as expired.
+  pub fn feed_callback(
+    &mut self,
+    query: &tg::CallbackQuery,
+    ctx: &Context,
+  ) -> Option<B::Target> {
+    let ready = {
+      let builder = match self.get_by_callback(query) {
+        Some(builder) => builder,
+        None => {
+          ctx.bot.answer_expired(query);
+          return None;
+        }
+      };
+      builder.on_callback(query, ctx);
+      builder.ready()
+    };
+    self.finish(query, ready)
+  }
+
+  fn finish<K: ToSessionKey>(
+    &mut self,
+    key: &K,
+    ready: bool,
+  ) -> Option<B::Target> {
+    if !ready {
+      return None;
+    }
+    self.take(key).and_then(|builder| builder.build())
+  }
+}
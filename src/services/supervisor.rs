@@ -0,0 +1,131 @@
+use crate::common::*;
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// Suspension after the first panic, doubled on each one in a row
+const BASE_SUSPENSION_MINUTES: i64 = 1;
+
+/// Longest suspension, a panicking extension is retried at least daily.
+/// Panics further apart than this are not counted as in a row.
+const MAX_SUSPENSION_MINUTES: i64 = 24 * 60;
+
+#[derive(Debug, Clone, Default)]
+struct Health {
+  /// Panics in a row
+  panics: u32,
+  last_panic: Option<DateTime<Local>>,
+  suspended_until: Option<DateTime<Local>>,
+}
+
+/// Runs extension code so that a panic only takes the extension down.
+/// The panic is reported and the extension suspended for a while,
+/// longer each time it panics again.
+#[derive(Clone)]
+pub struct Supervisor {
+  errors: ErrorReporter,
+  health: Rc<RefCell<HashMap<String, Health>>>,
+}
+
+impl Supervisor {
+  pub fn new(errors: ErrorReporter) -> Self {
+    Supervisor {
+      errors,
+      health: Default::default(),
+    }
+  }
+
+  pub fn is_suspended(&self, ext: &str) -> bool {
+    self.suspended_until(ext).is_some()
+  }
+
+  /// When the suspension of `ext` ends, None if it is not suspended
+  pub fn suspended_until(&self, ext: &str) -> Option<DateTime<Local>> {
+    let health = self.health.borrow();
+    let until = health.get(ext).and_then(|h| h.suspended_until);
+    until.filter(|&until| Local::now() < until)
+  }
+
+  /// Run `f` on behalf of `ext`, None if it panicked
+  pub fn run<R, F: FnOnce() -> R>(&self, ext: &str, f: F) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+      Ok(result) => Some(result),
+      Err(payload) => {
+        self.panicked(ext, &*payload);
+        None
+      }
+    }
+  }
+
+  /// Wrap a future spawned on behalf of `ext` the same way
+  pub fn wrap<F>(
+    &self,
+    ext: &str,
+    future: F,
+  ) -> impl Future<Item = (), Error = ()>
+  where
+    F: Future<Item = (), Error = ()>,
+  {
+    let this = self.clone();
+    let ext = ext.to_string();
+    AssertUnwindSafe(future).catch_unwind().then(move |result| {
+      if let Err(payload) = result {
+        this.panicked(&ext, &*payload);
+      }
+      ok(())
+    })
+  }
+
+  /// Suspended extensions with when they are retried
+  pub fn suspended(&self) -> Vec<(String, DateTime<Local>)> {
+    let now = Local::now();
+    let health = self.health.borrow();
+    let mut suspended: Vec<_> = health
+      .iter()
+      .filter_map(|(ext, h)| Some((ext.clone(), h.suspended_until?)))
+      .filter(|&(_, until)| now < until)
+      .collect();
+    suspended.sort();
+    suspended
+  }
+
+  fn panicked(&self, ext: &str, payload: &(Any + Send)) {
+    let suspension = {
+      let mut health = self.health.borrow_mut();
+      let health = health.entry(ext.into()).or_insert_with(Health::default);
+      let now = Local::now();
+      let max = Duration::minutes(MAX_SUSPENSION_MINUTES);
+      if health.last_panic.map_or(false, |last| now - last > max) {
+        health.panics = 0;
+      }
+      health.panics += 1;
+      health.last_panic = Some(now);
+      let suspension = suspension(health.panics);
+      health.suspended_until = Some(now + suspension);
+      suspension
+    };
+
+    let error = format!(
+      "panicked: {}, suspended for {}",
+      panic_message(payload),
+      format_duration(suspension)
+    );
+    self.errors.report(ext, &error);
+  }
+}
+
+fn suspension(panics: u32) -> Duration {
+  let minutes = BASE_SUSPENSION_MINUTES << panics.saturating_sub(1).min(16);
+  Duration::minutes(minutes.min(MAX_SUSPENSION_MINUTES))
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    s.to_string()
+  } else if let Some(s) = payload.downcast_ref::<String>() {
+    s.clone()
+  } else {
+    "unknown panic".into()
+  }
+}
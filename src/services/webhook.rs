@@ -0,0 +1,137 @@
+use crate::common::*;
+
+use crate::services::http::{
+  self, respond, response, same_secret, ResponseFuture,
+};
+
+use futures::sync::mpsc;
+use hyper::{Body, Method, Request, StatusCode};
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+
+/// Telegram echoes the secret given to `setWebhook` in this header
+pub const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+pub const HEALTH_PATH: &str = "/healthz";
+
+/// Handles the requests arriving on the webhook listener
+#[derive(Clone)]
+struct Endpoint {
+  secret: String,
+  updates: mpsc::UnboundedSender<tg::Update>,
+  logger: Logger,
+}
+
+impl Endpoint {
+  fn handle(&self, req: Request<Body>) -> ResponseFuture {
+    if req.uri().path() == HEALTH_PATH {
+      return respond(StatusCode::OK, "ok");
+    }
+    if req.method() != Method::POST {
+      return respond(StatusCode::METHOD_NOT_ALLOWED, "");
+    }
+
+    let secret = req.headers().get(SECRET_HEADER).map(|v| v.as_bytes());
+    if !secret.map_or(false, |s| same_secret(s, self.secret.as_bytes())) {
+      warn!(self.logger, "Rejected webhook request without valid secret");
+      return respond(StatusCode::UNAUTHORIZED, "");
+    }
+
+    let this = self.clone();
+    Box::new(req.into_body().concat2().map(move |body| {
+      match serde_json::from_slice::<tg::Update>(&body) {
+        Ok(update) => {
+          this.updates.unbounded_send(update).ok();
+          response(StatusCode::OK, "")
+        }
+        Err(e) => {
+          warn!(this.logger, "Malformed webhook update: {}", e);
+          response(StatusCode::BAD_REQUEST, "")
+        }
+      }
+    }))
+  }
+}
+
+/// A secret made up for this run when none is configured, from the
+/// system's CSPRNG since it is all that keeps forged updates out
+pub fn new_secret() -> io::Result<String> {
+  let mut bytes = [0u8; 32];
+  File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+  Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Serve the webhook on `addr`. Updates carrying `secret` come out of
+/// the returned stream, `/healthz` is answered on the same listener.
+pub fn listen(
+  addr: &SocketAddr,
+  secret: String,
+  handle: &reactor::Handle,
+  logger: Logger,
+) -> io::Result<impl Stream<Item = tg::Update, Error = ()>> {
+  let (updates, received) = mpsc::unbounded();
+  let endpoint = Endpoint {
+    secret,
+    updates,
+    logger: logger.clone(),
+  };
+  http::serve(addr, handle, logger, move |req| endpoint.handle(req))?;
+  Ok(received)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::testing::*;
+
+  fn post(secret: Option<&str>, body: &str) -> Request<Body> {
+    let mut req = Request::post("/");
+    if let Some(secret) = secret {
+      req.header(SECRET_HEADER, secret);
+    }
+    req.body(Body::from(body.to_string())).unwrap()
+  }
+
+  #[test]
+  fn test_webhook_endpoint() {
+    let (updates, received) = mpsc::unbounded();
+    let endpoint = Endpoint {
+      secret: "s3cret".into(),
+      updates,
+      logger: Logger::root(slog::Discard, o!()),
+    };
+    let status =
+      |req: Request<Body>| endpoint.handle(req).wait().unwrap().status();
+    let update = json!({
+      "update_id": 7,
+      "message": fixtures::message(1, PRIVATE_CHAT, USER_ID, "/remind_me"),
+    })
+    .to_string();
+
+    let health = Request::get(HEALTH_PATH).body(Body::empty()).unwrap();
+    assert_eq!(status(health), StatusCode::OK);
+    assert_eq!(status(post(None, &update)), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+      status(post(Some("s3cre"), &update)),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(status(post(Some("s3cret"), "{")), StatusCode::BAD_REQUEST);
+    assert_eq!(status(post(Some("s3cret"), &update)), StatusCode::OK);
+
+    drop(endpoint);
+    let received: Vec<_> = received.collect().wait().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].id, 7);
+  }
+
+  #[test]
+  fn test_new_secret() {
+    let secret = new_secret().unwrap();
+    assert_eq!(secret.len(), 64);
+    assert!(secret.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(secret, new_secret().unwrap());
+  }
+}
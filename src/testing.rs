@@ -0,0 +1,328 @@
+//! Offline harness driving a `Context` with fixture updates.
+//!
+//! The bot API is built on a connector that records every outgoing
+//! request and answers it with a plausible fake response, so tests can
+//! feed `tg::Update`s through `Context::process_update` and assert on
+//! the exact requests the extensions sent.
+#![allow(dead_code)]
+
+use crate::common::*;
+
+use serde_json::Value as JsonValue;
+use std::rc::Rc;
+use std::time;
+
+use crate::services::outbox::RateLimits;
+use crate::tg::connector::Connector;
+
+pub const BOT_ID: i64 = 1000;
+pub const USER_ID: i64 = 100;
+pub const OTHER_USER_ID: i64 = 101;
+pub const PRIVATE_CHAT: i64 = USER_ID;
+pub const GROUP_CHAT: i64 = -200;
+/// Every request to it is refused, as if the bot was blocked
+pub const BLOCKED_CHAT: i64 = -403;
+
+/// An outgoing request captured by the harness
+#[derive(Debug, Clone)]
+pub struct Recorded {
+  pub method: String,
+  pub body: JsonValue,
+  pub response: JsonValue,
+}
+
+impl Recorded {
+  pub fn text(&self) -> Option<&str> {
+    self.body["text"].as_str()
+  }
+
+  pub fn chat_id(&self) -> Option<i64> {
+    self.body["chat_id"].as_i64()
+  }
+
+  /// Callback data of all inline buttons attached to the request
+  pub fn buttons(&self) -> Vec<String> {
+    let rows = self.body["reply_markup"]["inline_keyboard"].as_array();
+    rows
+      .into_iter()
+      .flat_map(|rows| rows.iter())
+      .filter_map(|row| row.as_array())
+      .flat_map(|row| row.iter())
+      .filter_map(|btn| btn["callback_data"].as_str())
+      .map(|x| x.into())
+      .collect()
+  }
+
+  /// Callback data of the inline button labelled `text`
+  pub fn button(&self, text: &str) -> Option<String> {
+    let rows = self.body["reply_markup"]["inline_keyboard"].as_array()?;
+    rows
+      .iter()
+      .filter_map(|row| row.as_array())
+      .flat_map(|row| row.iter())
+      .find(|btn| btn["text"] == text)
+      .and_then(|btn| btn["callback_data"].as_str())
+      .map(|x| x.into())
+  }
+}
+
+#[derive(Debug, Default)]
+struct RecordingConnector {
+  requests: Rc<RefCell<Vec<Recorded>>>,
+  next_message_id: Cell<i64>,
+}
+
+impl RecordingConnector {
+  fn fake_response(&self, method: &str, body: &JsonValue) -> JsonValue {
+    match method {
+      "sendMessage" | "sendAudio" => {
+        let id = self.next_message_id.get() + 1;
+        self.next_message_id.set(id);
+        let chat_id = body["chat_id"].as_i64().unwrap_or(PRIVATE_CHAT);
+        let text = body["text"].as_str().unwrap_or("");
+        fixtures::message(id, chat_id, BOT_ID, text)
+      }
+      "editMessageText" => {
+        let id = body["message_id"].as_i64().unwrap_or(0);
+        let chat_id = body["chat_id"].as_i64().unwrap_or(PRIVATE_CHAT);
+        let text = body["text"].as_str().unwrap_or("");
+        fixtures::message(id, chat_id, BOT_ID, text)
+      }
+      _ => json!(true),
+    }
+  }
+}
+
+impl Connector for RecordingConnector {
+  fn request(
+    &self,
+    _token: &str,
+    req: tg::HttpRequest,
+  ) -> tg::TelegramFuture<tg::HttpResponse> {
+    let url = req.url.url("");
+    let method = url.rsplit('/').next().unwrap_or("").to_string();
+    let body = match req.body {
+      tg::Body::Json(ref body) => {
+        serde_json::from_slice(body.as_ref()).unwrap_or(JsonValue::Null)
+      }
+      _ => JsonValue::Null,
+    };
+
+    let response = self.fake_response(&method, &body);
+    let raw = if body["chat_id"] == BLOCKED_CHAT {
+      json!({
+        "ok": false,
+        "error_code": 403,
+        "description": "Forbidden: bot was blocked by the user",
+      })
+    } else {
+      json!({"ok": true, "result": response.clone()})
+    };
+    self.requests.borrow_mut().push(Recorded {
+      method,
+      body,
+      response,
+    });
+
+    let resp = tg::HttpResponse {
+      body: Some(raw.to_string().into_bytes()),
+    };
+    tg::TelegramFuture::new(Box::new(ok(resp)))
+  }
+}
+
+pub struct Harness {
+  pub core: reactor::Core,
+  pub ctx: Context,
+  requests: Rc<RefCell<Vec<Recorded>>>,
+  next_update_id: i64,
+  next_message_id: i64,
+}
+
+impl Harness {
+  pub fn new() -> Self {
+    let core = reactor::Core::new().unwrap();
+    let connector = RecordingConnector::default();
+    let requests = connector.requests.clone();
+    let api = tg::Api::configure("test-token")
+      .connector(Box::new(connector))
+      .build(core.handle())
+      .unwrap();
+    let logger = Logger::root(slog::Discard, o!());
+    let bot = Bot::new(api, core.handle(), logger.clone());
+    bot.outbox().set_limits(RateLimits::unlimited());
+
+    let db = Box::new(MemoryStorage::default());
+    let config = Config::default();
+    let mut ctx = Context::new(bot, core.handle(), logger, db, config);
+    ctx.guard.add_safe_chat(tg::ChatId::from(PRIVATE_CHAT));
+    ctx.guard.add_safe_chat(tg::ChatId::from(GROUP_CHAT));
+
+    Harness {
+      core,
+      ctx,
+      requests,
+      next_update_id: 1,
+      next_message_id: 1,
+    }
+  }
+
+  /// Make a user admin in both test chats
+  pub fn add_admin(&mut self, user_id: i64) -> &mut Self {
+    let user = tg::UserId::from(user_id);
+    for &chat in &[PRIVATE_CHAT, GROUP_CHAT] {
+      self.ctx.guard.add_admin(tg::ChatId::from(chat), user);
+    }
+    self
+  }
+
+  pub fn plug<T: BotExtension + 'static>(&mut self) -> &mut Self {
+    self.ctx.plug_ext::<T>();
+    self.settle();
+    self.requests.borrow_mut().clear();
+    self
+  }
+
+  /// Feed an update and run the reactor until spawned requests are sent
+  pub fn feed(&mut self, update: tg::Update) {
+    self.ctx.process_update(update);
+    self.settle();
+  }
+
+  pub fn feed_json(&mut self, kind: &str, payload: JsonValue) {
+    let id = self.next_update_id;
+    self.next_update_id += 1;
+    let update = serde_json::from_value(json!({
+      "update_id": id,
+      kind: payload,
+    }))
+    .expect("invalid update fixture");
+    self.feed(update);
+  }
+
+  /// Send a text message from `user_id` into `chat_id`, returns the
+  /// message id
+  pub fn send_text(&mut self, chat_id: i64, user_id: i64, text: &str) -> i64 {
+    let id = self.next_message_id;
+    self.next_message_id += 1;
+    self.feed_json("message", fixtures::message(id, chat_id, user_id, text));
+    id
+  }
+
+  /// Edit a message previously sent with `send_text`
+  pub fn edit_text(&mut self, id: i64, chat_id: i64, user_id: i64, text: &str) {
+    let mut msg = fixtures::message(id, chat_id, user_id, text);
+    msg["edit_date"] = json!(Local::now().timestamp());
+    self.feed_json("edited_message", msg);
+  }
+
+  /// Reply to a message previously sent by the bot
+  pub fn reply_text(
+    &mut self,
+    to: &Recorded,
+    chat_id: i64,
+    user_id: i64,
+    text: &str,
+  ) {
+    let id = self.next_message_id;
+    self.next_message_id += 1;
+    let mut msg = fixtures::message(id, chat_id, user_id, text);
+    msg["reply_to_message"] = to.response.clone();
+    self.feed_json("message", msg);
+  }
+
+  /// Press an inline button on a message previously sent by the bot
+  pub fn click(&mut self, on: &Recorded, user_id: i64, data: &str) {
+    let query = fixtures::callback_query(&on.response, user_id, data);
+    self.feed_json("callback_query", query);
+  }
+
+  pub fn inline_query(&mut self, user_id: i64, query: &str) {
+    let query = json!({
+      "id": format!("iq-{}", self.next_update_id),
+      "from": fixtures::user(user_id),
+      "query": query,
+      "offset": "",
+    });
+    self.feed_json("inline_query", query);
+  }
+
+  /// Run the jobs which would be due at `now`
+  pub fn run_jobs_at(&mut self, now: DateTime<Local>) {
+    self.ctx.run_due_jobs(now);
+    self.settle();
+  }
+
+  pub fn requests(&self) -> Vec<Recorded> {
+    self.requests.borrow().clone()
+  }
+
+  pub fn take_requests(&self) -> Vec<Recorded> {
+    self.requests.borrow_mut().drain(..).collect()
+  }
+
+  /// Run the reactor until spawned requests are sent
+  pub fn settle(&mut self) {
+    for _ in 0..10 {
+      self.core.turn(Some(time::Duration::from_millis(1)));
+    }
+  }
+}
+
+pub mod fixtures {
+  use super::*;
+
+  pub fn user(user_id: i64) -> JsonValue {
+    if user_id == BOT_ID {
+      json!({
+        "id": BOT_ID,
+        "is_bot": true,
+        "first_name": "Fond",
+        "username": "fond_test_bot",
+      })
+    } else {
+      json!({
+        "id": user_id,
+        "is_bot": false,
+        "first_name": format!("User{}", user_id),
+        "username": format!("user{}", user_id),
+      })
+    }
+  }
+
+  pub fn chat(chat_id: i64) -> JsonValue {
+    if chat_id < 0 {
+      json!({"id": chat_id, "type": "group", "title": "Test group"})
+    } else {
+      json!({
+        "id": chat_id,
+        "type": "private",
+        "first_name": format!("User{}", chat_id),
+      })
+    }
+  }
+
+  pub fn message(id: i64, chat_id: i64, user_id: i64, text: &str) -> JsonValue {
+    json!({
+      "message_id": id,
+      "from": user(user_id),
+      "date": Local::now().timestamp(),
+      "chat": chat(chat_id),
+      "text": text,
+    })
+  }
+
+  pub fn callback_query(
+    message: &JsonValue,
+    user_id: i64,
+    data: &str,
+  ) -> JsonValue {
+    json!({
+      "id": format!("cb-{}", data),
+      "from": user(user_id),
+      "message": message,
+      "chat_instance": "test",
+      "data": data,
+    })
+  }
+}